@@ -0,0 +1,67 @@
+// A stand-in for an OTLP/HTTP collector: prints each exported batch of
+// spans and answers 200. Point `TraceConfig::new` at
+// `http://127.0.0.1:4318/v1/traces` (or the address given as argument).
+
+use std::env;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+fn find_head_end(buf: &[u8]) -> Option<usize> {
+  buf.windows(4).position(|w| w == b"\r\n\r\n").map(|pos| pos + 4)
+}
+
+fn content_length(head: &[u8]) -> usize {
+  let head = String::from_utf8_lossy(head);
+  for line in head.split("\r\n") {
+    let mut parts = line.splitn(2, ':');
+    let name = parts.next().unwrap_or("");
+    if name.eq_ignore_ascii_case("content-length") {
+      return parts.next().unwrap_or("").trim().parse().unwrap_or(0);
+    }
+  }
+  0
+}
+
+fn serve(mut stream: TcpStream) -> std::io::Result<()> {
+  let mut buf = Vec::new();
+  let mut rbuf = [0; 8192];
+  let head_end = loop {
+    if let Some(pos) = find_head_end(&buf) {
+      break pos;
+    }
+    match stream.read(&mut rbuf)? {
+      0 => return Ok(()),
+      n => buf.extend_from_slice(&rbuf[ .. n]),
+    }
+  };
+  let body_len = content_length(&buf[ .. head_end]);
+  while buf.len() < head_end + body_len {
+    match stream.read(&mut rbuf)? {
+      0 => break,
+      n => buf.extend_from_slice(&rbuf[ .. n]),
+    }
+  }
+  let first_line = buf.split(|&x| x == b'\r').next().unwrap_or(b"");
+  println!("{}", String::from_utf8_lossy(first_line));
+  println!("{}", String::from_utf8_lossy(&buf[head_end .. ]));
+  stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: 2\r\nConnection: close\r\n\r\n{}")?;
+  stream.flush()
+}
+
+fn main() {
+  let addr = env::args().nth(1).unwrap_or_else(|| "127.0.0.1:4318".into());
+  let listener = TcpListener::bind(&addr).unwrap();
+  println!("otlp_sink: listening on {}", addr);
+  for stream in listener.incoming() {
+    match stream {
+      Err(e) => {
+        println!("otlp_sink: accept: {:?}", e);
+      }
+      Ok(stream) => {
+        if let Err(e) = serve(stream) {
+          println!("otlp_sink: {:?}", e);
+        }
+      }
+    }
+  }
+}
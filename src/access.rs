@@ -0,0 +1,435 @@
+#![forbid(unsafe_code)]
+
+use crate::{BackendTiming, RequestHead};
+use crate::tls::{ClientHello};
+use crate::trace::{RequestTrace};
+use crate::log::{json_escape_into};
+
+use smol_str::{SmolStr};
+use time::{get_time_usec};
+
+use std::fmt::{Write as FmtWrite};
+use std::fs::{File, OpenOptions, rename};
+use std::io::{Error as IoError, Write};
+use std::net::{IpAddr};
+use std::path::{PathBuf};
+use std::sync::{Mutex};
+use std::time::{Duration as StdDuration, Instant, SystemTime, UNIX_EPOCH};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum AccessFormat {
+  // NCSA Combined Log Format, followed by the gateway-specific fields.
+  Combined,
+  // One JSON object per line.
+  Json,
+}
+
+#[derive(Clone, Debug)]
+pub struct AccessLogConfig {
+  pub path: PathBuf,
+  pub format: AccessFormat,
+  // Rotate once the file reaches this size.
+  pub max_bytes: Option<u64>,
+  // Rotate once the file has been open for this long.
+  pub max_age: Option<StdDuration>,
+  // Number of rotated files to keep, as `<path>.1` through `<path>.<keep>`.
+  pub keep: usize,
+}
+
+impl AccessLogConfig {
+  pub fn new<P: Into<PathBuf>>(path: P, format: AccessFormat) -> AccessLogConfig {
+    AccessLogConfig{
+      path: path.into(),
+      format,
+      max_bytes: Some(64 * 1024 * 1024),
+      max_age: Some(StdDuration::from_secs(24 * 3600)),
+      keep: 7,
+    }
+  }
+}
+
+#[derive(Clone, Debug)]
+pub struct AccessEntry {
+  pub client_ip: Option<IpAddr>,
+  // From the ClientHello, see `crate::tls`: the highest version the client
+  // offered, and the SNI name if it sent one.
+  pub tls_version: Option<SmolStr>,
+  pub sni: Option<SmolStr>,
+  pub host: Option<SmolStr>,
+  pub request_id: SmolStr,
+  pub method: SmolStr,
+  pub target: SmolStr,
+  pub version: SmolStr,
+  pub referer: Option<SmolStr>,
+  pub user_agent: Option<SmolStr>,
+  pub status: u16,
+  pub bytes_in: usize,
+  pub bytes_out: usize,
+  pub member: Option<SmolStr>,
+  pub upstream: Option<StdDuration>,
+  pub t0: Instant,
+  pub total: Option<StdDuration>,
+  pub trace: Option<RequestTrace>,
+}
+
+impl AccessEntry {
+  pub fn new(head: &RequestHead) -> AccessEntry {
+    AccessEntry{
+      client_ip: head.client_ip,
+      tls_version: None,
+      sni: None,
+      host: head.route_host.clone(),
+      request_id: head.request_id.clone(),
+      method: head.method.clone(),
+      target: head.target.clone(),
+      version: head.version.clone(),
+      referer: head.referer.clone(),
+      user_agent: head.user_agent.clone(),
+      status: 0,
+      bytes_in: head.total_len(),
+      bytes_out: 0,
+      member: None,
+      upstream: None,
+      t0: head.t0,
+      total: None,
+      trace: head.trace.clone(),
+    }
+  }
+
+  pub fn set_tls(&mut self, hello: &ClientHello) {
+    self.tls_version = hello.version.clone();
+    self.sni = hello.sni.clone();
+  }
+
+  pub fn set_backend(&mut self, member: Option<SmolStr>, timing: Option<BackendTiming>) {
+    self.member = member;
+    self.upstream = timing.map(|t| t.upstream());
+    if let (Some(trace), Some(timing)) = (self.trace.as_mut(), timing) {
+      trace.queued = Some(timing.queued);
+      trace.sent = Some(timing.sent);
+      trace.received = Some(timing.received);
+    }
+  }
+
+  // Marks the start of writing the response, for the trace.
+  pub fn begin_write(&mut self) {
+    if let Some(trace) = self.trace.as_mut() {
+      if trace.write.is_none() {
+        trace.write = Some(Instant::now());
+      }
+    }
+  }
+
+  // Fills in the status and sizes from the encoded response, of which
+  // `written` bytes reached the client.
+  pub fn finish(&mut self, out: &[u8], written: usize) {
+    self.status = response_status(out).unwrap_or(0);
+    self.bytes_out = written;
+    self.total = Some(self.t0.elapsed());
+  }
+
+  fn format_combined(&self, buf: &mut String, now: SystemTime) {
+    let dash = |s: &Option<SmolStr>| -> SmolStr {
+      s.clone().unwrap_or_else(|| "-".into())
+    };
+    // NB: quoted fields escape `"` and `\` as Apache does.
+    let _ = write!(buf, "{} - - [{}] \"{} {} {}\" {} {} \"{}\" \"{}\"",
+        self.client_ip.map_or_else(|| "-".to_string(), |ip| ip.to_string()),
+        clf_time(now),
+        clf_escape(&self.method), clf_escape(&self.target), clf_escape(&self.version),
+        self.status, self.bytes_out,
+        clf_escape(&dash(&self.referer)), clf_escape(&dash(&self.user_agent)));
+    let _ = write!(buf, " rid={} host={} tls={} sni={} in={} member={} upstream_ms={} total_ms={}",
+        self.request_id, dash(&self.host), dash(&self.tls_version), dash(&self.sni), self.bytes_in,
+        dash(&self.member), millis(self.upstream), millis(self.total));
+  }
+
+  fn format_json(&self, buf: &mut String) {
+    let _ = write!(buf, "{{\"ts\":\"{}\"", get_time_usec().utc().rfc3339_nsec());
+    push_json_field(buf, "client", self.client_ip.map(|ip| ip.to_string()).as_ref().map(|s| s.as_str()));
+    push_json_field(buf, "tls_version", self.tls_version.as_ref().map(|s| s.as_str()));
+    push_json_field(buf, "sni", self.sni.as_ref().map(|s| s.as_str()));
+    push_json_field(buf, "host", self.host.as_ref().map(|s| s.as_str()));
+    push_json_field(buf, "request_id", Some(self.request_id.as_str()));
+    push_json_field(buf, "method", Some(self.method.as_str()));
+    push_json_field(buf, "path", Some(self.target.as_str()));
+    let _ = write!(buf, ",\"status\":{},\"bytes_in\":{},\"bytes_out\":{}", self.status, self.bytes_in, self.bytes_out);
+    push_json_field(buf, "member", self.member.as_ref().map(|s| s.as_str()));
+    push_json_field(buf, "referer", self.referer.as_ref().map(|s| s.as_str()));
+    push_json_field(buf, "user_agent", self.user_agent.as_ref().map(|s| s.as_str()));
+    let _ = write!(buf, ",\"upstream_ms\":{},\"total_ms\":{}}}", millis(self.upstream), millis(self.total));
+  }
+}
+
+fn push_json_field(buf: &mut String, key: &str, value: Option<&str>) {
+  let _ = write!(buf, ",\"{}\":", key);
+  match value {
+    None => buf.push_str("null"),
+    Some(value) => {
+      buf.push('"');
+      json_escape_into(buf, value);
+      buf.push('"');
+    }
+  }
+}
+
+fn millis(d: Option<StdDuration>) -> SmolStr {
+  match d {
+    None => "-".into(),
+    Some(d) => format!("{:.3}", d.as_secs_f64() * 1000.0).into()
+  }
+}
+
+pub fn response_status(out: &[u8]) -> Option<u16> {
+  let mut parts = out.splitn(3, |&x| x == b' ');
+  parts.next()?;
+  let code = parts.next()?;
+  if code.len() != 3 || !code.iter().all(|x| x.is_ascii_digit()) {
+    return None;
+  }
+  Some(code.iter().fold(0, |acc, &x| acc * 10 + (x - b'0') as u16))
+}
+
+fn clf_escape(s: &str) -> String {
+  let mut out = String::with_capacity(s.len());
+  for c in s.chars() {
+    if c == '"' || c == '\\' {
+      out.push('\\');
+    }
+    out.push(c);
+  }
+  out
+}
+
+// Formats as `10/Oct/2000:13:55:36 +0000`.
+fn clf_time(t: SystemTime) -> String {
+  const MONTHS: [&'static str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+  let secs = t.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0) as i64;
+  let days = secs.div_euclid(86400);
+  let rem = secs.rem_euclid(86400);
+  // NB: civil-from-days, proleptic Gregorian calendar.
+  let z = days + 719468;
+  let era = z.div_euclid(146097);
+  let doe = z.rem_euclid(146097);
+  let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+  let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+  let mp = (5 * doy + 2) / 153;
+  let day = doy - (153 * mp + 2) / 5 + 1;
+  let month = if mp < 10 { mp + 3 } else { mp - 9 };
+  let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+  format!("{:02}/{}/{}:{:02}:{:02}:{:02} +0000",
+      day, MONTHS[(month - 1) as usize], year, rem / 3600, (rem / 60) % 60, rem % 60)
+}
+
+struct LogFile {
+  file: File,
+  size: u64,
+  opened: Instant,
+}
+
+pub struct AccessLog {
+  cfg: AccessLogConfig,
+  file: Mutex<Option<LogFile>>,
+}
+
+impl AccessLog {
+  pub fn open(cfg: AccessLogConfig) -> Result<AccessLog, IoError> {
+    let file = open_file(&cfg.path)?;
+    log_info!("access: logging to {:?} format = {:?}", cfg.path, cfg.format);
+    Ok(AccessLog{cfg, file: Mutex::new(Some(file))})
+  }
+
+  pub fn write(&self, entry: &AccessEntry) {
+    let mut line = String::new();
+    match self.cfg.format {
+      AccessFormat::Combined => entry.format_combined(&mut line, SystemTime::now()),
+      AccessFormat::Json => entry.format_json(&mut line),
+    }
+    line.push('\n');
+    let mut file = self.file.lock().unwrap();
+    let rotate = match file.as_ref() {
+      None => false,
+      Some(f) => {
+        self.cfg.max_bytes.map_or(false, |max| f.size >= max) ||
+        self.cfg.max_age.map_or(false, |max| f.opened.elapsed() >= max)
+      }
+    };
+    if rotate {
+      *file = None;
+      self.rotate();
+    }
+    if file.is_none() {
+      match open_file(&self.cfg.path) {
+        Err(e) => {
+          log_warn!("access: failed to open {:?}: {:?}", self.cfg.path, e);
+          return;
+        }
+        Ok(f) => *file = Some(f)
+      }
+    }
+    let f = file.as_mut().unwrap();
+    match f.file.write_all(line.as_bytes()) {
+      Err(e) => {
+        log_warn!("access: write failed: {:?}", e);
+        *file = None;
+      }
+      Ok(_) => {
+        f.size += line.len() as u64;
+      }
+    }
+  }
+
+  // Reopens the file at the configured path, e.g. after an external rotation.
+  pub fn reopen(&self) {
+    let mut file = self.file.lock().unwrap();
+    match open_file(&self.cfg.path) {
+      Err(e) => {
+        log_warn!("access: failed to reopen {:?}: {:?}", self.cfg.path, e);
+        *file = None;
+      }
+      Ok(f) => {
+        log_info!("access: reopened {:?}", self.cfg.path);
+        *file = Some(f);
+      }
+    }
+  }
+
+  fn rotate(&self) {
+    let path_n = |n: usize| -> PathBuf {
+      let mut p = self.cfg.path.clone().into_os_string();
+      p.push(format!(".{}", n));
+      p.into()
+    };
+    if self.cfg.keep == 0 {
+      let _ = rename(&self.cfg.path, path_n(1));
+      return;
+    }
+    for n in (1 .. self.cfg.keep).rev() {
+      let _ = rename(path_n(n), path_n(n + 1));
+    }
+    if let Err(e) = rename(&self.cfg.path, path_n(1)) {
+      log_warn!("access: failed to rotate {:?}: {:?}", self.cfg.path, e);
+      return;
+    }
+    log_info!("access: rotated {:?}", self.cfg.path);
+  }
+}
+
+fn open_file(path: &PathBuf) -> Result<LogFile, IoError> {
+  let file = OpenOptions::new().create(true).append(true).open(path)?;
+  let size = file.metadata()?.len();
+  Ok(LogFile{file, size, opened: Instant::now()})
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use std::fs::{read_to_string, remove_file, write as write_file};
+
+  fn entry() -> AccessEntry {
+    AccessEntry{
+      client_ip: Some("192.0.2.1".parse().unwrap()),
+      tls_version: Some("TLSv1.3".into()),
+      sni: Some("example.com".into()),
+      host: Some("example.com".into()),
+      request_id: "rid1".into(),
+      method: "GET".into(),
+      target: "/a?b=1".into(),
+      version: "HTTP/1.0".into(),
+      referer: None,
+      user_agent: Some("curl/8.0".into()),
+      status: 200,
+      bytes_in: 40,
+      bytes_out: 512,
+      member: Some("127.0.0.1:8080".into()),
+      upstream: Some(StdDuration::from_millis(3)),
+      t0: Instant::now(),
+      total: Some(StdDuration::from_millis(5)),
+      trace: None,
+    }
+  }
+
+  #[test]
+  fn test_format_combined() {
+    let now = UNIX_EPOCH + StdDuration::from_secs(1682858096);
+    let mut quoted = entry();
+    quoted.target = "/a\"b\\c".into();
+    quoted.referer = Some("https://x/\"".into());
+    quoted.user_agent = Some("ua \\\" ua".into());
+    let mut bare = entry();
+    bare.client_ip = None;
+    bare.tls_version = None;
+    bare.sni = None;
+    bare.user_agent = None;
+    bare.upstream = None;
+    let cases: Vec<(&str, AccessEntry, &str)> = vec![
+      ("plain", entry(),
+       "192.0.2.1 - - [30/Apr/2023:12:34:56 +0000] \"GET /a?b=1 HTTP/1.0\" 200 512 \"-\" \"curl/8.0\" \
+        rid=rid1 host=example.com tls=TLSv1.3 sni=example.com in=40 member=127.0.0.1:8080 upstream_ms=3.000 total_ms=5.000"),
+      ("quoted", quoted,
+       "192.0.2.1 - - [30/Apr/2023:12:34:56 +0000] \"GET /a\\\"b\\\\c HTTP/1.0\" 200 512 \"https://x/\\\"\" \"ua \\\\\\\" ua\" \
+        rid=rid1 host=example.com tls=TLSv1.3 sni=example.com in=40 member=127.0.0.1:8080 upstream_ms=3.000 total_ms=5.000"),
+      ("missing", bare,
+       "- - - [30/Apr/2023:12:34:56 +0000] \"GET /a?b=1 HTTP/1.0\" 200 512 \"-\" \"-\" \
+        rid=rid1 host=example.com tls=- sni=- in=40 member=127.0.0.1:8080 upstream_ms=- total_ms=5.000"),
+    ];
+    for (name, entry, expected) in cases {
+      let mut buf = String::new();
+      entry.format_combined(&mut buf, now);
+      assert_eq!(buf, expected, "case: {}", name);
+    }
+  }
+
+  #[test]
+  fn test_clf_time() {
+    let cases: Vec<(u64, &str)> = vec![
+      (0, "01/Jan/1970:00:00:00 +0000"),
+      (951782400, "29/Feb/2000:00:00:00 +0000"),
+      (951868799, "29/Feb/2000:23:59:59 +0000"),
+      (951868800, "01/Mar/2000:00:00:00 +0000"),
+      (4107542399, "28/Feb/2100:23:59:59 +0000"),
+      (4107542400, "01/Mar/2100:00:00:00 +0000"),
+      (1735689599, "31/Dec/2024:23:59:59 +0000"),
+      (1735689600, "01/Jan/2025:00:00:00 +0000"),
+      (1682858096, "30/Apr/2023:12:34:56 +0000"),
+    ];
+    for (secs, expected) in cases {
+      assert_eq!(clf_time(UNIX_EPOCH + StdDuration::from_secs(secs)), expected, "secs: {}", secs);
+    }
+  }
+
+  #[test]
+  fn test_rotate() {
+    let path = std::env::temp_dir().join(format!("access-rotate-{}.log", std::process::id()));
+    let path_n = |n: usize| -> PathBuf {
+      PathBuf::from(format!("{}.{}", path.display(), n))
+    };
+    let mut cfg = AccessLogConfig::new(&path, AccessFormat::Combined);
+    cfg.keep = 2;
+    let log = AccessLog{cfg, file: Mutex::new(None)};
+    for gen in ["a", "b", "c"].iter() {
+      write_file(&path, gen).unwrap();
+      log.rotate();
+      assert!(!path.exists());
+    }
+    assert_eq!(read_to_string(path_n(1)).unwrap(), "c");
+    assert_eq!(read_to_string(path_n(2)).unwrap(), "b");
+    assert!(!path_n(3).exists());
+    // Rotates on the size limit before writing.
+    let mut cfg = AccessLogConfig::new(&path, AccessFormat::Combined);
+    cfg.keep = 2;
+    cfg.max_bytes = Some(1);
+    let log = AccessLog::open(cfg).unwrap();
+    let mut first = entry();
+    first.request_id = "first".into();
+    log.write(&first);
+    log.write(&entry());
+    assert!(read_to_string(path_n(1)).unwrap().contains("rid=first"));
+    assert_eq!(read_to_string(path_n(2)).unwrap(), "c");
+    assert!(read_to_string(&path).unwrap().contains("rid=rid1"));
+    for p in [path.clone(), path_n(1), path_n(2)].iter() {
+      let _ = remove_file(p);
+    }
+  }
+}
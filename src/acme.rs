@@ -1,22 +1,39 @@
+#![forbid(unsafe_code)]
+
 //use crate::{GatewayBackendHandle, Worker};
 use crate::{Context};
+use crate::metrics::{metrics};
 
 use native_tls::{Error as TlsError, Identity};
 use service_base::prelude::*;
 use service_base::route::*;
 use uacme::{Error as UacmeError, Directory, DirectoryUrl, create_p384_key};
+use smol_str::{SmolStr};
 use uacme::persist::{FilePersist};
 
 use std::fs::{File};
-use std::io::{Read};
-use std::thread::{sleep, spawn};
+use std::io::{Error as IoError, Read};
+use std::path::{Path};
+use std::thread::{sleep};
 use std::time::{Duration};
 
+// NB: a renewal gives up after this many attempts, so that it cannot hold
+// the renewal worker indefinitely.
+pub const RENEW_ATTEMPTS: usize = 3;
+pub const RENEW_BACKOFF: Duration = Duration::from_secs(30);
+
 #[derive(Debug)]
 pub enum AcmeErr {
   _Top,
   Tls(TlsError),
   Uacme(UacmeError),
+  Io(IoError),
+}
+
+impl From<IoError> for AcmeErr {
+  fn from(e: IoError) -> AcmeErr {
+    AcmeErr::Io(e)
+  }
 }
 
 impl From<TlsError> for AcmeErr {
@@ -35,39 +52,47 @@ pub struct Acme {
 }
 
 impl Acme {
-  pub fn identity<S: AsRef<str>>(domain: S, ctx: Context) -> Result<Identity, AcmeErr> {
+  // Loads `<dir>/<domain>.crt` and `<dir>/<domain>.key`; may be called
+  // before chroot with the outside path of the chroot's `/var/tmp/acme`.
+  pub fn load_identity<P: AsRef<Path>, S: AsRef<str>>(dir: P, domain: S) -> Result<Identity, AcmeErr> {
+    let dir = dir.as_ref();
     let domain = domain.as_ref();
     let mut crt = Vec::new();
     let mut key = Vec::new();
-    // FIXME: determine whether staging or production env.
-    //let mut crt_f = File::open(&format!("/var/tmp/acme-staging/{}.crt", domain)).unwrap();
-    let mut crt_f = File::open(&format!("/var/tmp/acme/{}.crt", domain)).unwrap();
-    crt_f.read_to_end(&mut crt).unwrap();
-    drop(crt_f);
-    //let mut key_f = File::open(&format!("/var/tmp/acme-staging/{}.key", domain)).unwrap();
-    let mut key_f = File::open(&format!("/var/tmp/acme/{}.key", domain)).unwrap();
-    key_f.read_to_end(&mut key).unwrap();
-    drop(key_f);
+    File::open(dir.join(format!("{}.crt", domain)))?.read_to_end(&mut crt)?;
+    File::open(dir.join(format!("{}.key", domain)))?.read_to_end(&mut key)?;
     let id = Identity::from_pkcs8(&crt, &key)?;
     Ok(id)
   }
 
-  pub fn fresh_identity<S: AsRef<str>, S_: AsRef<str>>(domain: S, alt_domains: &[S_], ctx: Context) -> Result<(), AcmeErr> {
-    let domain = domain.as_ref().to_string();
-    let alt_domains: Vec<_> = alt_domains.iter().map(|s| s.as_ref().to_string()).collect();
-    spawn(move || {
-      let mut acme_nr = 0;
-      loop {
-        acme_nr += 1;
-        let res = AcmeWorker::fresh_identity(&domain, &alt_domains, ctx.clone());
-        println!("INFO:   acme attempt {}: result={:?}", acme_nr, res);
-        if res.is_ok() {
-          break;
-        }
-        sleep(Duration::from_secs(30));
+  // Returns the expiry of `<dir>/<domain>.crt` as an RFC 3339 UTC time.
+  pub fn cert_expiry<P: AsRef<Path>, S: AsRef<str>>(dir: P, domain: S) -> Result<Option<SmolStr>, AcmeErr> {
+    let mut crt = Vec::new();
+    File::open(dir.as_ref().join(format!("{}.crt", domain.as_ref())))?.read_to_end(&mut crt)?;
+    Ok(cert_not_after(&crt))
+  }
+
+  // NB: the identity as persisted by `AcmeWorker` in `/var/tmp/acme`.
+  pub fn identity<S: AsRef<str>>(domain: S, _ctx: Context) -> Result<Identity, AcmeErr> {
+    // FIXME: determine whether staging or production env.
+    Acme::load_identity("/var/tmp/acme", domain)
+  }
+
+  // Obtains a fresh certificate into `dir`, blocking until done; needs the
+  // HTTP-01 challenge routes of `ctx.router` to be served on port 80.
+  pub fn fresh_identity<P: AsRef<Path>, S: AsRef<str>, S_: AsRef<str>>(dir: P, domain: S, alt_domains: &[S_], ctx: Context) -> Result<(), AcmeErr> {
+    let mut acme_nr = 0;
+    loop {
+      acme_nr += 1;
+      let res = AcmeWorker::fresh_identity(dir.as_ref(), domain.as_ref(), alt_domains, ctx.clone());
+      log_info!("acme attempt {}: result={:?}", acme_nr, res);
+      metrics().acme_renewals.with(if res.is_ok() { "ok" } else { "error" }).inc();
+      match res {
+        Err(e) if acme_nr >= RENEW_ATTEMPTS => return Err(e),
+        Err(_) => sleep(RENEW_BACKOFF),
+        Ok(_) => return Ok(())
       }
-    });
-    Ok(())
+    }
   }
 }
 
@@ -76,21 +101,21 @@ pub struct AcmeWorker {
 }
 
 impl AcmeWorker {
-  pub fn fresh_identity<S: AsRef<str>, S_: AsRef<str>>(domain: S, alt_domains: &[S_], ctx: Context) -> Result<(), AcmeErr> {
+  pub fn fresh_identity<S: AsRef<str>, S_: AsRef<str>>(dir: &Path, domain: S, alt_domains: &[S_], ctx: Context) -> Result<(), AcmeErr> {
     let domain = domain.as_ref();
     let alt_domains: Vec<_> = alt_domains.iter().map(|s| s.as_ref()).collect();
     // FIXME: determine whether staging or production env.
     /*let url = DirectoryUrl::LetsEncryptStaging;
     let persist = FilePersist::new("/var/tmp/acme-staging");*/
     let url = DirectoryUrl::LetsEncrypt;
-    let persist = FilePersist::new("/var/tmp/acme");
-    println!("DEBUG:  acme: file persist... done");
+    let persist = FilePersist::new(dir);
+    log_debug!("acme: file persist... done");
     let dir = Directory::from_url(persist.clone(), url)?;
-    println!("DEBUG:  acme: directory from url... done");
+    log_debug!("acme: directory from url... done");
     let acct = dir.account(&format!("dns@{}", domain))?;
-    println!("DEBUG:  acme: account... done");
+    log_debug!("acme: account... done");
     let mut order = acct.new_order(domain, &alt_domains)?;
-    println!("DEBUG:  acme: new order... done");
+    log_debug!("acme: new order... done");
     let mut acme_token = None;
     let csr = loop {
       if let Some(token) = acme_token.take() {
@@ -100,42 +125,124 @@ impl AcmeWorker {
       if let Some(csr) = order.confirm_validations() {
         break csr;
       }
-      println!("DEBUG:  acme: confirm validations returned None... done");
+      log_debug!("acme: confirm validations returned None... done");
       let auths = order.authorizations()?;
-      println!("DEBUG:  acme: authorizations... done");
+      log_debug!("acme: authorizations... done");
       if auths.len() <= 0 {
         return Err(AcmeErr::_Top);
       }
       let challenge = auths[0].http_challenge();
-      println!("DEBUG:  acme: get challenge... done");
+      log_debug!("acme: get challenge... done");
       let token = challenge.http_token().to_string();
-      println!("DEBUG:  acme: get token... done");
+      log_debug!("acme: get token... done");
       let proof = challenge.http_proof().to_string();
-      println!("DEBUG:  acme: get proof... done");
+      log_debug!("acme: get proof... done");
       acme_token = token.clone().into();
       ctx.router.lock().unwrap()
         .insert(80, GET, (".well-known", "acme-challenge", token), Box::new(move |_, _, _| {
           ok().with_payload_str_mime(proof.clone(), Mime::TextPlain).into()
         }));
-      println!("DEBUG:  acme: challenge validation: waiting...");
+      log_debug!("acme: challenge validation: waiting...");
       challenge.validate(10_000)?;
-      println!("DEBUG:  acme: challenge validation: done");
+      log_debug!("acme: challenge validation: done");
       order.refresh()?;
-      println!("DEBUG:  acme: refresh... done");
+      log_debug!("acme: refresh... done");
     };
     if let Some(token) = acme_token.take() {
       ctx.router.lock().unwrap()
         .remove(80, GET, (".well-known", "acme-challenge", token));
     }
     let secret_key = create_p384_key();
-    println!("DEBUG:  acme: create key... done");
+    log_debug!("acme: create key... done");
     let cert_order = csr.finalize_pkey(secret_key, 10_000)?;
-    println!("DEBUG:  acme: finalize key... done");
+    log_debug!("acme: finalize key... done");
     let cert = cert_order.download_and_save_cert()?;
-    println!("DEBUG:  acme: download and save cert... done");
+    log_debug!("acme: download and save cert... done");
     persist.fresh_symlinks(domain)?;
-    println!("DEBUG:  acme: fresh symlinks... done");
-    println!("DEBUG:  acme: done");
+    log_debug!("acme: fresh symlinks... done");
+    log_debug!("acme: done");
     Ok(())
   }
 }
+
+// Returns the `notAfter` time of the first certificate in a PEM bundle.
+pub fn cert_not_after(pem: &[u8]) -> Option<SmolStr> {
+  const BEGIN: &'static [u8] = b"-----BEGIN CERTIFICATE-----";
+  const END: &'static [u8] = b"-----END CERTIFICATE-----";
+  let start = pem.windows(BEGIN.len()).position(|w| w == BEGIN)? + BEGIN.len();
+  let len = pem[start .. ].windows(END.len()).position(|w| w == END)?;
+  let der = base64_decode(&pem[start .. start + len])?;
+  // NB: Certificate ::= SEQUENCE { tbsCertificate SEQUENCE { [0] version
+  // OPTIONAL, serialNumber, signature, issuer, validity, .. }, .. }
+  let (_, cert, _) = der_next(&der).filter(|&(tag, _, _)| tag == 0x30)?;
+  let (_, tbs, _) = der_next(cert).filter(|&(tag, _, _)| tag == 0x30)?;
+  let mut rest = tbs;
+  if rest.first() == Some(&0xa0) {
+    rest = der_next(rest)?.2;
+  }
+  for _ in 0 .. 3 {
+    rest = der_next(rest)?.2;
+  }
+  let (_, validity, _) = der_next(rest).filter(|&(tag, _, _)| tag == 0x30)?;
+  let (_, _, validity) = der_next(validity)?;
+  let (tag, not_after, _) = der_next(validity)?;
+  let not_after = std::str::from_utf8(not_after).ok()?;
+  let (year, rest) = match (tag, not_after.len()) {
+    // UTCTime, YYMMDDHHMMSSZ.
+    (0x17, 13) => {
+      let yy: u32 = not_after[ .. 2].parse().ok()?;
+      (if yy >= 50 { 1900 + yy } else { 2000 + yy }, &not_after[2 .. ])
+    }
+    // GeneralizedTime, YYYYMMDDHHMMSSZ.
+    (0x18, 15) => (not_after[ .. 4].parse().ok()?, &not_after[4 .. ]),
+    _ => return None
+  };
+  if !rest.ends_with('Z') || !rest[ .. 10].bytes().all(|x| x.is_ascii_digit()) {
+    return None;
+  }
+  Some(format!("{:04}-{}-{}T{}:{}:{}Z", year, &rest[0 .. 2], &rest[2 .. 4], &rest[4 .. 6], &rest[6 .. 8], &rest[8 .. 10]).into())
+}
+
+// Returns the tag, the contents, and the remainder of a DER element.
+fn der_next(buf: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+  let tag = *buf.get(0)?;
+  let first = *buf.get(1)? as usize;
+  let (len, hdr) = if first < 0x80 {
+    (first, 2)
+  } else {
+    let n = first & 0x7f;
+    if n == 0 || n > 4 {
+      return None;
+    }
+    let len = buf.get(2 .. 2 + n)?.iter().fold(0, |acc, &x| (acc << 8) | x as usize);
+    (len, 2 + n)
+  };
+  let end = hdr.checked_add(len)?;
+  Some((tag, buf.get(hdr .. end)?, &buf[end .. ]))
+}
+
+fn base64_decode(s: &[u8]) -> Option<Vec<u8>> {
+  let mut out = Vec::with_capacity(s.len() * 3 / 4);
+  let mut acc: u32 = 0;
+  let mut bits = 0;
+  for &x in s.iter() {
+    let v = match x {
+      b'A' ..= b'Z' => x - b'A',
+      b'a' ..= b'z' => x - b'a' + 26,
+      b'0' ..= b'9' => x - b'0' + 52,
+      b'+' => 62,
+      b'/' => 63,
+      b'=' => break,
+      b' ' | b'\t' | b'\r' | b'\n' => continue,
+      _ => return None
+    };
+    acc = (acc << 6) | v as u32;
+    bits += 6;
+    if bits >= 8 {
+      bits -= 8;
+      out.push((acc >> bits) as u8);
+      acc &= (1 << bits) - 1;
+    }
+  }
+  Some(out)
+}
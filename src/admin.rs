@@ -0,0 +1,500 @@
+#![forbid(unsafe_code)]
+
+use crate::{Context, Gateway443};
+use crate::acme::{Acme};
+use crate::backend::{BackendPool, BreakerState};
+use crate::errpage::{reason_phrase};
+use crate::log::{json_escape_into};
+use crate::metrics::{Encoder, encode_registry};
+use crate::pool::{WorkerPool};
+use crate::routing::{PathMatch};
+
+use service_base::prelude::*;
+use service_base::route::*;
+use smol_str::{SmolStr};
+
+use std::collections::{BTreeMap};
+use std::fmt::{Write as FmtWrite};
+use std::fs::{Permissions, remove_file, set_permissions};
+use std::io::{Error as IoError, ErrorKind as IoErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpListener};
+use std::os::unix::fs::{PermissionsExt};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::{UnixListener};
+use std::path::{PathBuf};
+use std::sync::{Arc};
+use std::sync::atomic::{Ordering};
+use std::thread::{Builder as ThreadBuilder};
+use std::time::{Duration as StdDuration};
+
+#[derive(Clone, Debug)]
+pub enum AdminAddr {
+  // NB: always a loopback address, see
+  // `ProxyGatewayConfig::set_admin_listener`.
+  Tcp(SocketAddr),
+  Unix(PathBuf),
+}
+
+pub enum AdminListener {
+  Tcp(TcpListener),
+  Unix(UnixListener),
+}
+
+impl AsRawFd for AdminListener {
+  fn as_raw_fd(&self) -> RawFd {
+    match self {
+      &AdminListener::Tcp(ref bind) => bind.as_raw_fd(),
+      &AdminListener::Unix(ref bind) => bind.as_raw_fd(),
+    }
+  }
+}
+
+pub fn bind(addr: &AdminAddr) -> Result<AdminListener, IoError> {
+  match addr {
+    &AdminAddr::Tcp(ref addr) => {
+      let bind = TcpListener::bind(addr)?;
+      log_info!("admin: listening on {}", addr);
+      Ok(AdminListener::Tcp(bind))
+    }
+    &AdminAddr::Unix(ref path) => {
+      match remove_file(path) {
+        Err(ref e) if e.kind() == IoErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+        Ok(_) => {}
+      }
+      let bind = UnixListener::bind(path)?;
+      set_permissions(path, Permissions::from_mode(0o600))?;
+      log_info!("admin: listening on {:?}", path);
+      Ok(AdminListener::Unix(bind))
+    }
+  }
+}
+
+// Control endpoints, which only answer POST; the router only matches GET.
+#[derive(Clone)]
+enum Control {
+  Drain(Arc<BackendPool>),
+  Enable(Arc<BackendPool>),
+  Renew,
+  Reload,
+}
+
+pub fn start(gw: Arc<Gateway443>, ctx: Context, bind: AdminListener) {
+  ThreadBuilder::new()
+    .name("admin".into())
+    .spawn(move || serve(gw, ctx, bind))
+    .unwrap();
+}
+
+fn controls(gw: &Gateway443) -> BTreeMap<String, Control> {
+  let mut controls = BTreeMap::new();
+  for member in gw.groups.values().flat_map(|g| g.members().iter()) {
+    controls.insert(format!("/backends/{}/drain", member.addr()), Control::Drain(member.clone()));
+    controls.insert(format!("/backends/{}/enable", member.addr()), Control::Enable(member.clone()));
+  }
+  controls.insert("/certs/renew".into(), Control::Renew);
+  controls.insert("/reload".into(), Control::Reload);
+  controls
+}
+
+// Renews the certificate of the primary host and its other hosts, then
+// reloads; at most one renewal runs at a time.
+fn renewer(gw: &Arc<Gateway443>, ctx: &Context) -> WorkerPool<()> {
+  let gw = gw.clone();
+  let ctx = ctx.clone();
+  // NB: a zero-capacity queue only takes a job when the worker is idle.
+  WorkerPool::new("acme", 1, 0, move |()| {
+    let domain = match gw.config.primhost.as_ref() {
+      None => return,
+      Some(domain) => domain.clone()
+    };
+    let alt_domains: Vec<SmolStr> = gw.config.hostgroup.keys().filter(|&h| h != &domain).cloned().collect();
+    log_info!("admin: renewing certificate for {:?}", domain);
+    // NB: runs inside the sandbox, where `tls_dir` is at its own path.
+    match Acme::fresh_identity(&gw.config.sandbox.tls_dir, &domain, &alt_domains, ctx.clone()) {
+      Err(e) => log_warn!("admin: renew: failed: {:?}", e),
+      Ok(_) => {
+        log_info!("admin: renew: done");
+        gw.reload();
+      }
+    }
+  })
+}
+
+fn control(gw: &Gateway443, ctx: &Context, renewer: &WorkerPool<()>, action: &Control) -> (u16, String) {
+  match action {
+    &Control::Drain(ref member) => {
+      member.disable();
+      (200, render_member(member))
+    }
+    &Control::Enable(ref member) => {
+      member.enable();
+      (200, render_member(member))
+    }
+    &Control::Renew => {
+      if gw.config.primhost.is_none() {
+        return (503, "{\"renew\":\"error\",\"error\":\"no primary host\"}".into());
+      }
+      if !ctx.http01.load(Ordering::Acquire) {
+        return (503, "{\"renew\":\"error\",\"error\":\"no HTTP-01 responder on port 80\"}".into());
+      }
+      match renewer.try_submit(()) {
+        Err(_) => (409, "{\"renew\":\"running\"}".into()),
+        Ok(_) => (202, "{\"renew\":\"started\"}".into())
+      }
+    }
+    &Control::Reload => {
+      log_info!("admin: reload");
+      gw.reload();
+      (200, "{\"reload\":\"done\"}".into())
+    }
+  }
+}
+
+fn routes(gw: &Arc<Gateway443>, port: u16) -> Router {
+  let mut router = Router::new();
+  let json = |body: String| -> Option<HttpResponse> {
+    ok().with_payload_str_mime(body, http1::Mime::ApplicationJson).into()
+  };
+  {
+    let gw = gw.clone();
+    router.insert(port, GET, "metrics", Box::new(move |_, _, _| {
+      ok().with_payload_str_mime(render_metrics(&gw), http1::Mime::TextPlain).into()
+    }));
+  }
+  {
+    let gw = gw.clone();
+    router.insert(port, GET, "hosts", Box::new(move |_, _, _| json(render_hosts(&gw))));
+  }
+  {
+    let gw = gw.clone();
+    router.insert(port, GET, "backends", Box::new(move |_, _, _| json(render_backends(&gw))));
+  }
+  {
+    let gw = gw.clone();
+    router.insert(port, GET, "certs", Box::new(move |_, _, _| json(render_certs(&gw))));
+  }
+  {
+    let gw = gw.clone();
+    router.insert(port, GET, "connections", Box::new(move |_, _, _| json(render_conns(&gw))));
+  }
+  router
+}
+
+fn serve(gw: Arc<Gateway443>, ctx: Context, bind: AdminListener) {
+  let port = match &bind {
+    &AdminListener::Tcp(ref bind) => bind.local_addr().map(|addr| addr.port()).unwrap_or(0),
+    &AdminListener::Unix(_) => 0,
+  };
+  let router = routes(&gw, port);
+  let controls = controls(&gw);
+  let renewer = renewer(&gw, &ctx);
+  let handle = |stream: &mut dyn ReadWrite| {
+    serve_conn(stream, port, &router, &controls, |action| control(&gw, &ctx, &renewer, action))
+  };
+  let mut poll = crate::net::Poll::new().unwrap();
+  poll.register(&bind, bind.as_raw_fd() as crate::net::Token, crate::net::Interest::Read).unwrap();
+  while !gw.is_shutting_down() {
+    match crate::net::poll_read_fd_timeout(&mut poll, &bind, StdDuration::from_secs(1)) {
+      Err(_) |
+      Ok(None) => continue,
+      Ok(Some(_)) => {}
+    }
+    let timeout = Some(StdDuration::from_secs(5));
+    match &bind {
+      &AdminListener::Tcp(ref bind) => match bind.accept() {
+        Err(e) => log_debug!("admin: accept: {:?}", e),
+        Ok((mut stream, _)) => {
+          let _ = stream.set_read_timeout(timeout);
+          let _ = stream.set_write_timeout(timeout);
+          handle(&mut stream);
+        }
+      },
+      &AdminListener::Unix(ref bind) => match bind.accept() {
+        Err(e) => log_debug!("admin: accept: {:?}", e),
+        Ok((mut stream, _)) => {
+          let _ = stream.set_read_timeout(timeout);
+          let _ = stream.set_write_timeout(timeout);
+          handle(&mut stream);
+        }
+      },
+    }
+  }
+  log_info!("admin: stopped");
+}
+
+trait ReadWrite: Read + Write {}
+
+impl<S: Read + Write> ReadWrite for S {}
+
+fn serve_conn<F: Fn(&Control) -> (u16, String)>(stream: &mut dyn ReadWrite, port: u16, router: &Router, controls: &BTreeMap<String, Control>, control: F) {
+  let mut rbuf = vec![0; 8192];
+  let r_sz = match stream.read(&mut rbuf) {
+    Err(e) => {
+      log_debug!("admin: read error: {:?}", e);
+      return;
+    }
+    Ok(r_sz) => r_sz
+  };
+  let base_url = http1::Url::parse("http://127.0.0.1").unwrap();
+  let mut parser = http1::RequestParser::new(rbuf[ .. r_sz].iter().map(|&x| x));
+  let mut req = http1::Request::default();
+  if parser.parse_first_line(&base_url, &mut req).is_err() ||
+     parser.parse_headers(&mut req).is_err()
+  {
+    log_debug!("admin: invalid request");
+    return;
+  }
+  drop(parser);
+  let path = rbuf[ .. r_sz].split(|&x| x == b' ').nth(1).unwrap_or(b"");
+  let path = path.split(|&x| x == b'?').next().unwrap_or(b"");
+  let path = crate::safe_ascii(path);
+  log_info!("admin: {:?} {}", req.method, path);
+  let buf = match (req.method, controls.get(path.as_str())) {
+    (Some(http1::Method::Post), Some(action)) => {
+      let (status, body) = control(action);
+      encode_json(status, &body)
+    }
+    (Some(http1::Method::Get), None) => {
+      let rep = match HttpRequest::try_from_raw_strip_headers(req) {
+        Err(_) => HttpResponse::from_status(HttpStatus::BadRequest),
+        Ok((req, _)) => match router.match_(port, &req) {
+          Err(_) |
+          Ok(None) => HttpResponse::not_found(),
+          Ok(Some(rep)) => rep
+        }
+      };
+      let mut rep = rep.to_raw();
+      rep.push_header(http1::HeaderName::Connection, "close");
+      let mut buf = Vec::new();
+      rep.encode(&mut buf).unwrap();
+      buf
+    }
+    _ => encode_json(405, "{\"error\":\"method not allowed\"}")
+  };
+  if let Err(e) = stream.write_all(&buf).and_then(|_| stream.flush()) {
+    log_debug!("admin: write error: {:?}", e);
+  }
+}
+
+// NB: written directly, as the router's responses cannot carry every status
+// used here.
+fn encode_json(status: u16, body: &str) -> Vec<u8> {
+  let reason = match status {
+    200 => "OK",
+    202 => "Accepted",
+    _ => reason_phrase(status)
+  };
+  format!("HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+      status, reason, body.len(), body).into_bytes()
+}
+
+fn push_json_str(buf: &mut String, s: &str) {
+  buf.push('"');
+  json_escape_into(buf, s);
+  buf.push('"');
+}
+
+fn push_json_opt(buf: &mut String, s: Option<&str>) {
+  match s {
+    None => buf.push_str("null"),
+    Some(s) => push_json_str(buf, s)
+  }
+}
+
+fn render_hosts(gw: &Gateway443) -> String {
+  let mut buf = String::from("{\"default_group\":");
+  push_json_opt(&mut buf, gw.config.def_group.as_ref().map(|s| s.as_str()));
+  buf.push_str(",\"hosts\":[");
+  for (i, (host, group)) in gw.config.hostgroup.iter().enumerate() {
+    if i > 0 {
+      buf.push(',');
+    }
+    buf.push_str("{\"host\":");
+    push_json_str(&mut buf, host);
+    buf.push_str(",\"group\":");
+    push_json_str(&mut buf, group);
+    buf.push('}');
+  }
+  buf.push_str("],\"wildcards\":[");
+  for (i, &(ref suffix, ref group)) in gw.config.hostpatterns.wildcards().iter().enumerate() {
+    if i > 0 {
+      buf.push(',');
+    }
+    buf.push_str("{\"pattern\":");
+    push_json_str(&mut buf, &format!("*{}", suffix));
+    buf.push_str(",\"group\":");
+    push_json_str(&mut buf, group);
+    buf.push('}');
+  }
+  buf.push_str("],\"regexes\":[");
+  for (i, &(ref re, ref group)) in gw.config.hostpatterns.regexes().iter().enumerate() {
+    if i > 0 {
+      buf.push(',');
+    }
+    buf.push_str("{\"pattern\":");
+    push_json_str(&mut buf, re.as_str());
+    buf.push_str(",\"group\":");
+    push_json_str(&mut buf, group);
+    buf.push('}');
+  }
+  buf.push_str("],\"routes\":[");
+  for (i, rule) in gw.config.routes.rules().iter().enumerate() {
+    if i > 0 {
+      buf.push(',');
+    }
+    buf.push_str("{\"host\":");
+    push_json_opt(&mut buf, rule.host.as_ref().map(|s| s.as_str()));
+    let (kind, path) = match &rule.path {
+      &PathMatch::Any => ("any", None),
+      &PathMatch::Prefix(ref p) => ("prefix", Some(p.as_str())),
+      &PathMatch::Exact(ref p) => ("exact", Some(p.as_str())),
+      &PathMatch::Regex(ref re) => ("regex", Some(re.as_str())),
+    };
+    buf.push_str(",\"path_match\":");
+    push_json_str(&mut buf, kind);
+    buf.push_str(",\"path\":");
+    push_json_opt(&mut buf, path);
+    buf.push_str(",\"methods\":[");
+    for (j, method) in rule.methods.iter().enumerate() {
+      if j > 0 {
+        buf.push(',');
+      }
+      push_json_str(&mut buf, method);
+    }
+    buf.push_str("],\"headers\":[");
+    for (j, &(ref name, _)) in rule.headers.iter().enumerate() {
+      if j > 0 {
+        buf.push(',');
+      }
+      push_json_str(&mut buf, name);
+    }
+    let _ = write!(&mut buf, "],\"rewrites\":{},\"group\":", rule.rewrites.len());
+    push_json_str(&mut buf, &rule.group);
+    buf.push('}');
+  }
+  buf.push_str("]}");
+  buf
+}
+
+fn render_member(member: &BackendPool) -> String {
+  let mut buf = String::from("{\"addr\":");
+  push_json_str(&mut buf, member.addr());
+  let q = member.queue_stats();
+  let _ = write!(&mut buf, ",\"up\":{},\"draining\":{},\"disabled\":{},\"outstanding\":{},\"conns\":{}",
+      member.is_up(), member.is_draining(), member.is_disabled(), member.outstanding(), member.health().len());
+  let _ = write!(&mut buf, ",\"queue\":{{\"depth\":{},\"cap\":{},\"rejected\":{},\"shed\":{}}}",
+      q.depth, q.cap, q.rejected, q.shed);
+  buf.push_str(",\"breaker\":");
+  match member.breaker_stats() {
+    None => buf.push_str("null"),
+    Some(b) => {
+      let state = match b.state {
+        BreakerState::Closed => "closed",
+        BreakerState::Open => "open",
+        BreakerState::HalfOpen => "half-open",
+      };
+      let _ = write!(&mut buf, "{{\"state\":\"{}\",\"opens\":{},\"rejected\":{}}}", state, b.opens, b.rejected);
+    }
+  }
+  buf.push('}');
+  buf
+}
+
+fn render_backends(gw: &Gateway443) -> String {
+  let mut buf = String::from("{\"groups\":[");
+  for (i, group) in gw.groups.values().enumerate() {
+    if i > 0 {
+      buf.push(',');
+    }
+    buf.push_str("{\"name\":");
+    push_json_str(&mut buf, group.name());
+    buf.push_str(",\"members\":[");
+    for (j, member) in group.members().iter().enumerate() {
+      if j > 0 {
+        buf.push(',');
+      }
+      buf.push_str(&render_member(member));
+    }
+    buf.push_str("]}");
+  }
+  buf.push_str("]}");
+  buf
+}
+
+fn render_certs(gw: &Gateway443) -> String {
+  let mut domains: Vec<&SmolStr> = gw.config.primhost.iter().collect();
+  domains.extend(gw.config.hostgroup.keys().filter(|&h| Some(h) != gw.config.primhost.as_ref()));
+  let mut buf = String::from("{\"certs\":[");
+  let mut first = true;
+  for domain in domains {
+    // NB: runs inside the sandbox, where `tls_dir` is at its own path.
+    let not_after = match Acme::cert_expiry(&gw.config.sandbox.tls_dir, domain) {
+      Err(_) => continue,
+      Ok(not_after) => not_after
+    };
+    if !first {
+      buf.push(',');
+    }
+    first = false;
+    buf.push_str("{\"domain\":");
+    push_json_str(&mut buf, domain);
+    buf.push_str(",\"not_after\":");
+    push_json_opt(&mut buf, not_after.as_ref().map(|s| s.as_str()));
+    buf.push('}');
+  }
+  buf.push_str("]}");
+  buf
+}
+
+fn render_conns(gw: &Gateway443) -> String {
+  let conns: Vec<_> = gw.conns.lock().unwrap().iter().map(|(&seq_nr, info)| (seq_nr, info.clone())).collect();
+  let mut buf = String::from("{\"connections\":[");
+  for (i, (seq_nr, info)) in conns.iter().enumerate() {
+    if i > 0 {
+      buf.push(',');
+    }
+    let _ = write!(&mut buf, "{{\"conn\":{},\"client\":", seq_nr);
+    push_json_opt(&mut buf, info.client_ip.map(|ip| ip.to_string()).as_ref().map(|s| s.as_str()));
+    let _ = write!(&mut buf, ",\"age_ms\":{},\"requests\":{},\"host\":", info.t0.elapsed().as_millis(), info.nreqs);
+    push_json_opt(&mut buf, info.host.as_ref().map(|s| s.as_str()));
+    buf.push_str(",\"method\":");
+    push_json_opt(&mut buf, info.method.as_ref().map(|s| s.as_str()));
+    buf.push_str(",\"target\":");
+    push_json_opt(&mut buf, info.target.as_ref().map(|s| s.as_str()));
+    buf.push('}');
+  }
+  buf.push_str("]}");
+  buf
+}
+
+pub fn render_metrics(gw: &Gateway443) -> String {
+  let mut enc = Encoder::new();
+  encode_registry(&mut enc);
+  let members: Vec<_> = gw.groups.values().flat_map(|g| g.members().iter().cloned()).collect();
+  let stats: Vec<_> = members.iter().map(|m| (m.addr(), m.queue_stats())).collect();
+  enc.gauge_values("gateway_backend_up", "Whether a backend member is considered up.", "member",
+      members.iter().map(|m| (m.addr(), if m.is_up() { 1.0 } else { 0.0 })));
+  enc.gauge_values("gateway_backend_draining", "Whether a backend member is draining.", "member",
+      members.iter().map(|m| (m.addr(), if m.is_draining() { 1.0 } else { 0.0 })));
+  enc.gauge_values("gateway_backend_outstanding", "Requests submitted to a backend member and not yet answered.", "member",
+      members.iter().map(|m| (m.addr(), m.outstanding() as f64)));
+  enc.gauge_values("gateway_backend_queue_depth", "Requests waiting for a backend connection.", "member",
+      stats.iter().map(|&(addr, ref q)| (addr, q.depth as f64)));
+  enc.counter_values("gateway_backend_queue_rejected_total", "Requests rejected because the backend queue was full.", "member",
+      stats.iter().map(|&(addr, ref q)| (addr, q.rejected as f64)));
+  enc.counter_values("gateway_backend_queue_shed_total", "Requests shed after waiting too long in the backend queue.", "member",
+      stats.iter().map(|&(addr, ref q)| (addr, q.shed as f64)));
+  let breakers: Vec<_> = members.iter().filter_map(|m| m.breaker_stats().map(|b| (m.addr(), b))).collect();
+  enc.gauge_values("gateway_backend_breaker_state", "Circuit breaker state: 0 closed, 1 open, 2 half-open.", "member",
+      breakers.iter().map(|&(addr, ref b)| (addr, match b.state {
+        BreakerState::Closed => 0.0,
+        BreakerState::Open => 1.0,
+        BreakerState::HalfOpen => 2.0,
+      })));
+  enc.counter_values("gateway_backend_breaker_opens_total", "Times the circuit breaker opened.", "member",
+      breakers.iter().map(|&(addr, ref b)| (addr, b.opens as f64)));
+  enc.counter_values("gateway_backend_breaker_rejected_total", "Requests rejected by an open circuit breaker.", "member",
+      breakers.iter().map(|&(addr, ref b)| (addr, b.rejected as f64)));
+  enc.finish()
+}
@@ -0,0 +1,1428 @@
+#![forbid(unsafe_code)]
+
+use crate::{BackendJob, BackendTiming};
+use crate::access::{response_status};
+use crate::metrics::{metrics};
+
+use service_base::prelude::*;
+use service_base::chan::*;
+use smol_str::{SmolStr};
+use time::{Timespec, get_time_coarse};
+
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::net::{IpAddr, ToSocketAddrs, TcpStream};
+use std::sync::{Arc, Condvar, Mutex, Weak};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::thread::{Builder as ThreadBuilder, sleep};
+use std::time::{Duration as StdDuration, Instant};
+
+#[derive(Clone, Debug)]
+pub struct BackendConfig {
+  pub min_conns: usize,
+  pub max_conns: usize,
+  pub conn_idle_timeout: StdDuration,
+  pub connect_timeout: StdDuration,
+  pub reconnect_delay: StdDuration,
+  pub queue_cap: usize,
+  pub queue_max_age: StdDuration,
+  pub retry: RetryPolicy,
+}
+
+impl Default for BackendConfig {
+  fn default() -> BackendConfig {
+    BackendConfig{
+      min_conns: 1,
+      max_conns: 8,
+      conn_idle_timeout: StdDuration::from_secs(60),
+      connect_timeout: StdDuration::from_secs(2),
+      reconnect_delay: StdDuration::from_secs(2),
+      queue_cap: 256,
+      queue_max_age: StdDuration::from_secs(2),
+      retry: RetryPolicy::default(),
+    }
+  }
+}
+
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+  // Total attempts per request, including the first one.
+  pub max_attempts: u32,
+  // Also retry POST and PATCH requests that carry an `Idempotency-Key`.
+  pub idempotency_key: bool,
+  // Prefer a different group member for each retry.
+  pub other_member: bool,
+  // Retry tokens earned per request sent to a backend.
+  pub budget_ratio: f64,
+  // Maximum (and initial) retry tokens held by a backend.
+  pub budget_cap: u32,
+}
+
+impl Default for RetryPolicy {
+  fn default() -> RetryPolicy {
+    RetryPolicy{
+      max_attempts: 2,
+      idempotency_key: false,
+      other_member: true,
+      budget_ratio: 0.2,
+      budget_cap: 10,
+    }
+  }
+}
+
+impl RetryPolicy {
+  pub fn allows(&self, method: Option<&http1::Method>, has_idempotency_key: bool) -> bool {
+    if self.max_attempts <= 1 {
+      return false;
+    }
+    match method {
+      Some(&http1::Method::Get) |
+      Some(&http1::Method::Head) |
+      Some(&http1::Method::Options) |
+      Some(&http1::Method::Trace) |
+      Some(&http1::Method::Put) |
+      Some(&http1::Method::Delete) => true,
+      Some(&http1::Method::Post) |
+      Some(&http1::Method::Patch) => self.idempotency_key && has_idempotency_key,
+      _ => false
+    }
+  }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Probe {
+  // Chan `OKQ` ping, expecting `OKR`.
+  Ping,
+  // HTTP GET of the path, expecting a 2xx or 3xx response.
+  HttpGet(SmolStr),
+}
+
+#[derive(Clone, Debug)]
+pub struct HealthCheckConfig {
+  pub probe: Probe,
+  pub host: SmolStr,
+  pub interval: StdDuration,
+  pub timeout: StdDuration,
+  pub rise: u32,
+  pub fall: u32,
+}
+
+impl Default for HealthCheckConfig {
+  fn default() -> HealthCheckConfig {
+    HealthCheckConfig{
+      probe: Probe::Ping,
+      host: "localhost".into(),
+      interval: StdDuration::from_secs(5),
+      timeout: StdDuration::from_secs(2),
+      rise: 2,
+      fall: 3,
+    }
+  }
+}
+
+#[derive(Clone, Copy, Default, Debug)]
+pub struct CheckState {
+  pub up: bool,
+  pub consecutive_ok: u32,
+  pub consecutive_fail: u32,
+  pub last_check: Option<Timespec>,
+}
+
+#[derive(Clone, Debug)]
+pub struct BreakerConfig {
+  pub consecutive_failures: u32,
+  pub error_rate: f64,
+  pub window: usize,
+  pub min_samples: usize,
+  pub open_for: StdDuration,
+}
+
+impl Default for BreakerConfig {
+  fn default() -> BreakerConfig {
+    BreakerConfig{
+      consecutive_failures: 5,
+      error_rate: 0.5,
+      window: 100,
+      min_samples: 20,
+      open_for: StdDuration::from_secs(10),
+    }
+  }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum BreakerState {
+  Closed,
+  Open,
+  HalfOpen,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct BreakerStats {
+  pub state: BreakerState,
+  pub opens: u64,
+  pub rejected: u64,
+}
+
+struct BreakerInner {
+  state: BreakerState,
+  consecutive_failures: u32,
+  window: VecDeque<bool>,
+  window_failures: usize,
+  opened_at: Option<Instant>,
+  trial_in_flight: bool,
+  opens: u64,
+  rejected: u64,
+}
+
+pub struct Breaker {
+  name: SmolStr,
+  cfg: BreakerConfig,
+  inner: Mutex<BreakerInner>,
+}
+
+impl Breaker {
+  pub fn new(name: &str, cfg: BreakerConfig) -> Breaker {
+    Breaker{
+      name: name.into(),
+      inner: Mutex::new(BreakerInner{
+        state: BreakerState::Closed,
+        consecutive_failures: 0,
+        window: VecDeque::with_capacity(cfg.window),
+        window_failures: 0,
+        opened_at: None,
+        trial_in_flight: false,
+        opens: 0,
+        rejected: 0,
+      }),
+      cfg,
+    }
+  }
+
+  pub fn stats(&self) -> BreakerStats {
+    let inner = self.inner.lock().unwrap();
+    BreakerStats{
+      state: inner.state,
+      opens: inner.opens,
+      rejected: inner.rejected,
+    }
+  }
+
+  // NB: does not change state; `claim` takes the half-open trial once a
+  // request is actually sent.
+  pub fn admits(&self) -> bool {
+    let inner = self.inner.lock().unwrap();
+    self.admits_inner(&inner)
+  }
+
+  fn admits_inner(&self, inner: &BreakerInner) -> bool {
+    match inner.state {
+      BreakerState::Closed => true,
+      BreakerState::Open => {
+        inner.opened_at.map_or(true, |t| t.elapsed() >= self.cfg.open_for)
+      }
+      BreakerState::HalfOpen => !inner.trial_in_flight,
+    }
+  }
+
+  pub fn reject(&self) {
+    self.inner.lock().unwrap().rejected += 1;
+  }
+
+  // Called right before a request is sent. Returns false if the breaker no
+  // longer admits it; otherwise, unless closed, the request becomes the
+  // half-open trial, which `record` or `release_trial` ends.
+  pub fn claim(&self) -> bool {
+    let mut inner = self.inner.lock().unwrap();
+    if !self.admits_inner(&inner) {
+      inner.rejected += 1;
+      return false;
+    }
+    match inner.state {
+      BreakerState::Closed => {}
+      BreakerState::Open |
+      BreakerState::HalfOpen => {
+        if inner.state == BreakerState::Open {
+          log_info!("breaker: {}: open -> half-open", self.name);
+        }
+        inner.state = BreakerState::HalfOpen;
+        inner.trial_in_flight = true;
+      }
+    }
+    true
+  }
+
+  // Ends a half-open trial whose request the backend declined without
+  // handling it, so that the next request can be the trial.
+  pub fn release_trial(&self) {
+    let mut inner = self.inner.lock().unwrap();
+    if inner.state == BreakerState::HalfOpen {
+      inner.trial_in_flight = false;
+    }
+  }
+
+  pub fn record(&self, ok: bool) {
+    let mut inner = self.inner.lock().unwrap();
+    if inner.window.len() >= self.cfg.window.max(1) {
+      if let Some(false) = inner.window.pop_front() {
+        inner.window_failures -= 1;
+      }
+    }
+    inner.window.push_back(ok);
+    if ok {
+      inner.consecutive_failures = 0;
+    } else {
+      inner.window_failures += 1;
+      inner.consecutive_failures += 1;
+    }
+    match inner.state {
+      BreakerState::HalfOpen => {
+        inner.trial_in_flight = false;
+        if ok {
+          log_info!("breaker: {}: half-open -> closed", self.name);
+          inner.state = BreakerState::Closed;
+          inner.window.clear();
+          inner.window_failures = 0;
+        } else {
+          log_info!("breaker: {}: half-open -> open", self.name);
+          inner.state = BreakerState::Open;
+          inner.opened_at = Some(Instant::now());
+          inner.opens += 1;
+        }
+      }
+      BreakerState::Closed => {
+        let samples = inner.window.len();
+        let tripped = inner.consecutive_failures >= self.cfg.consecutive_failures ||
+            (samples >= self.cfg.min_samples && samples > 0 &&
+             inner.window_failures as f64 >= self.cfg.error_rate * samples as f64);
+        if tripped {
+          log_info!("breaker: {}: closed -> open: consecutive failures = {} window failures = {}/{}",
+              self.name, inner.consecutive_failures, inner.window_failures, samples);
+          inner.state = BreakerState::Open;
+          inner.opened_at = Some(Instant::now());
+          inner.opens += 1;
+        }
+      }
+      BreakerState::Open => {}
+    }
+  }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct QueueStats {
+  pub depth: usize,
+  pub cap: usize,
+  pub oldest_age: Option<StdDuration>,
+  pub rejected: u64,
+  pub shed: u64,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ConnStatus {
+  Connecting,
+  Idle,
+  Busy,
+  Draining,
+  Down,
+}
+
+#[derive(Clone, Debug)]
+pub struct ConnHealth {
+  pub status: ConnStatus,
+  pub served: u64,
+  pub failures: u64,
+  pub consecutive_failures: u32,
+  pub last_ok: Option<Timespec>,
+}
+
+impl ConnHealth {
+  fn new() -> ConnHealth {
+    ConnHealth{
+      status: ConnStatus::Connecting,
+      served: 0,
+      failures: 0,
+      consecutive_failures: 0,
+      last_ok: None,
+    }
+  }
+}
+
+struct PoolState {
+  jobs: VecDeque<(Instant, BackendJob)>,
+  conns: usize,
+  idle: usize,
+  next_id: usize,
+  health: BTreeMap<usize, ConnHealth>,
+  // Connections reconnecting because of a drain.
+  draining_conns: BTreeSet<usize>,
+}
+
+pub struct BackendPool {
+  addr: SmolStr,
+  cfg: BackendConfig,
+  state: Mutex<PoolState>,
+  cond: Condvar,
+  outstanding: AtomicUsize,
+  check: Mutex<CheckState>,
+  breaker: Option<Breaker>,
+  draining: AtomicBool,
+  // NB: set by an operator; unlike `draining`, not cleared on reconnect.
+  disabled: AtomicBool,
+  siblings: Mutex<Vec<Weak<BackendPool>>>,
+  retry_tokens: Mutex<f64>,
+  rejected: AtomicU64,
+  shed: AtomicU64,
+}
+
+impl BackendPool {
+  pub fn start(addr: &str, cfg: BackendConfig, breaker: Option<BreakerConfig>) -> Arc<BackendPool> {
+    assert!(cfg.max_conns > 0);
+    assert!(cfg.min_conns <= cfg.max_conns);
+    let pool = Arc::new(BackendPool{
+      addr: addr.into(),
+      state: Mutex::new(PoolState{
+        jobs: VecDeque::new(),
+        conns: 0,
+        idle: 0,
+        next_id: 0,
+        health: BTreeMap::new(),
+        draining_conns: BTreeSet::new(),
+      }),
+      cond: Condvar::new(),
+      outstanding: AtomicUsize::new(0),
+      check: Mutex::new(CheckState{up: true, .. CheckState::default()}),
+      breaker: breaker.map(|b| Breaker::new(addr, b)),
+      draining: AtomicBool::new(false),
+      disabled: AtomicBool::new(false),
+      siblings: Mutex::new(Vec::new()),
+      retry_tokens: Mutex::new(cfg.retry.budget_cap as f64),
+      rejected: AtomicU64::new(0),
+      shed: AtomicU64::new(0),
+      cfg,
+    });
+    log_info!("backend: {}: start: conns = {}..{} queue cap = {}",
+        pool.addr, pool.cfg.min_conns, pool.cfg.max_conns, pool.cfg.queue_cap);
+    {
+      let mut st = pool.state.lock().unwrap();
+      for _ in 0 .. pool.cfg.min_conns {
+        pool.spawn_conn(&mut st);
+      }
+    }
+    let shed_pool = pool.clone();
+    ThreadBuilder::new()
+      .name(format!("shed-{}", pool.addr))
+      .spawn(move || shed_loop(shed_pool))
+      .unwrap();
+    pool
+  }
+
+  pub fn addr(&self) -> &str {
+    &self.addr
+  }
+
+  pub fn submit(self: &Arc<Self>, job: BackendJob) -> Result<(), BackendJob> {
+    let mut st = self.state.lock().unwrap();
+    if st.jobs.len() >= self.cfg.queue_cap {
+      drop(st);
+      self.rejected.fetch_add(1, Ordering::Relaxed);
+      return Err(job);
+    }
+    self.outstanding.fetch_add(1, Ordering::AcqRel);
+    self.earn_retry_token();
+    st.jobs.push_back((Instant::now(), job));
+    if st.jobs.len() > st.idle && st.conns < self.cfg.max_conns {
+      self.spawn_conn(&mut st);
+    }
+    drop(st);
+    self.cond.notify_one();
+    Ok(())
+  }
+
+  pub fn queue_len(&self) -> usize {
+    self.state.lock().unwrap().jobs.len()
+  }
+
+  pub fn queue_stats(&self) -> QueueStats {
+    let st = self.state.lock().unwrap();
+    QueueStats{
+      depth: st.jobs.len(),
+      cap: self.cfg.queue_cap,
+      oldest_age: st.jobs.front().map(|&(t, _)| t.elapsed()),
+      rejected: self.rejected.load(Ordering::Relaxed),
+      shed: self.shed.load(Ordering::Relaxed),
+    }
+  }
+
+  // NB: fails queued jobs that have waited longer than `queue_max_age`,
+  // so that their clients get a 503 right away.
+  fn shed_stale(&self) {
+    let mut stale = Vec::new();
+    {
+      let mut st = self.state.lock().unwrap();
+      while let Some(&(t, _)) = st.jobs.front() {
+        if t.elapsed() < self.cfg.queue_max_age {
+          break;
+        }
+        let (_, job) = st.jobs.pop_front().unwrap();
+        stale.push(job);
+      }
+    }
+    if stale.is_empty() {
+      return;
+    }
+    log_info!("backend: {}: shed {} stale requests", self.addr, stale.len());
+    self.shed.fetch_add(stale.len() as u64, Ordering::Relaxed);
+    for job in stale {
+      self.finish();
+      job.reply.fail(503);
+    }
+  }
+
+  pub fn outstanding(&self) -> usize {
+    self.outstanding.load(Ordering::Acquire)
+  }
+
+  // NB: a member with no connections yet (e.g. all retired) is considered up
+  // until a connection attempt or a health check says otherwise.
+  pub fn is_up(&self) -> bool {
+    if !self.check.lock().unwrap().up {
+      return false;
+    }
+    let st = self.state.lock().unwrap();
+    st.health.is_empty() || st.health.values().any(|h| h.status != ConnStatus::Down)
+  }
+
+  pub fn breaker_stats(&self) -> Option<BreakerStats> {
+    self.breaker.as_ref().map(|b| b.stats())
+  }
+
+  pub fn is_draining(&self) -> bool {
+    self.draining.load(Ordering::Acquire) || self.is_disabled()
+  }
+
+  pub fn is_disabled(&self) -> bool {
+    self.disabled.load(Ordering::Acquire)
+  }
+
+  // Takes the member out of rotation until `enable` is called.
+  pub fn disable(&self) {
+    if !self.disabled.swap(true, Ordering::AcqRel) {
+      log_info!("backend: {}: disabled, shifting new requests to siblings", self.addr);
+    }
+    self.handoff_queued();
+  }
+
+  pub fn enable(&self) {
+    if self.disabled.swap(false, Ordering::AcqRel) {
+      log_info!("backend: {}: enabled, back in rotation", self.addr);
+    }
+  }
+
+  pub fn admits(&self) -> bool {
+    !self.is_draining() && self.is_up() && self.breaker.as_ref().map_or(true, |b| b.admits())
+  }
+
+  // Like `admits`, but counts a refusal by the breaker as a rejected
+  // request; called at most once per request and member.
+  fn admits_request(&self) -> bool {
+    if self.is_draining() || !self.is_up() {
+      return false;
+    }
+    match self.breaker.as_ref() {
+      None => true,
+      Some(b) => {
+        if !b.admits() {
+          b.reject();
+          return false;
+        }
+        true
+      }
+    }
+  }
+
+  fn claim(&self) -> bool {
+    self.breaker.as_ref().map_or(true, |b| b.claim())
+  }
+
+  fn release_trial(&self) {
+    if let Some(b) = self.breaker.as_ref() {
+      b.release_trial();
+    }
+  }
+
+  fn record(&self, ok: bool) {
+    if let Some(b) = self.breaker.as_ref() {
+      b.record(ok);
+    }
+  }
+
+  pub fn check_state(&self) -> CheckState {
+    *self.check.lock().unwrap()
+  }
+
+  pub fn start_health_check(self: &Arc<Self>, cfg: HealthCheckConfig) {
+    let pool = self.clone();
+    ThreadBuilder::new()
+      .name(format!("health-{}", self.addr))
+      .spawn(move || check_loop(pool, cfg))
+      .unwrap();
+  }
+
+  fn record_check(&self, ok: bool, cfg: &HealthCheckConfig) {
+    let mut check = self.check.lock().unwrap();
+    check.last_check = Some(get_time_coarse());
+    if ok {
+      check.consecutive_ok += 1;
+      check.consecutive_fail = 0;
+      if !check.up && check.consecutive_ok >= cfg.rise {
+        check.up = true;
+        log_info!("health: {}: down -> up", self.addr);
+      }
+    } else {
+      check.consecutive_fail += 1;
+      check.consecutive_ok = 0;
+      if check.up && check.consecutive_fail >= cfg.fall {
+        check.up = false;
+        log_info!("health: {}: up -> down", self.addr);
+      }
+    }
+  }
+
+  pub fn health(&self) -> Vec<(usize, ConnHealth)> {
+    let st = self.state.lock().unwrap();
+    st.health.iter().map(|(&id, h)| (id, h.clone())).collect()
+  }
+
+  fn spawn_conn(self: &Arc<Self>, st: &mut PoolState) {
+    let id = st.next_id;
+    st.next_id += 1;
+    st.conns += 1;
+    st.health.insert(id, ConnHealth::new());
+    let pool = self.clone();
+    ThreadBuilder::new()
+      .name(format!("backend-{}", id))
+      .spawn(move || conn_loop(pool, id))
+      .unwrap();
+  }
+
+  fn update<F: FnOnce(&mut ConnHealth)>(&self, id: usize, f: F) {
+    let mut st = self.state.lock().unwrap();
+    if let Some(h) = st.health.get_mut(&id) {
+      f(h);
+    }
+  }
+
+  // NB: returns `None` when an idle connection in excess of `min_conns`
+  // should retire.
+  fn take_job(&self, id: usize) -> Option<BackendJob> {
+    let mut st = self.state.lock().unwrap();
+    if let Some(h) = st.health.get_mut(&id) {
+      h.status = ConnStatus::Idle;
+    }
+    st.idle += 1;
+    loop {
+      if let Some((_, job)) = st.jobs.pop_front() {
+        st.idle -= 1;
+        if let Some(h) = st.health.get_mut(&id) {
+          h.status = ConnStatus::Busy;
+        }
+        return Some(job);
+      }
+      let (guard, res) = self.cond.wait_timeout(st, self.cfg.conn_idle_timeout).unwrap();
+      st = guard;
+      if res.timed_out() && st.jobs.is_empty() && st.conns > self.cfg.min_conns {
+        st.idle -= 1;
+        st.conns -= 1;
+        st.health.remove(&id);
+        return None;
+      }
+    }
+  }
+
+  fn finish(&self) {
+    let prev = self.outstanding.fetch_sub(1, Ordering::AcqRel);
+    if prev == 1 && self.is_draining() {
+      log_info!("backend: {}: drain: no requests in flight", self.addr);
+    }
+  }
+
+  fn set_siblings(&self, siblings: Vec<Weak<BackendPool>>) {
+    *self.siblings.lock().unwrap() = siblings;
+  }
+
+  // NB: queued jobs are moved to siblings right away; jobs already sent to
+  // the backend are left to finish on their own connections.
+  fn begin_drain(&self) {
+    if !self.draining.swap(true, Ordering::AcqRel) {
+      log_info!("backend: {}: drain: start, shifting new requests to siblings", self.addr);
+    }
+    self.handoff_queued();
+  }
+
+  fn handoff_queued(&self) {
+    let jobs: Vec<_> = self.state.lock().unwrap().jobs.drain(..).map(|(_, job)| job).collect();
+    if !jobs.is_empty() {
+      log_info!("backend: {}: drain: handing off {} queued requests", self.addr, jobs.len());
+    }
+    for job in jobs {
+      self.handoff(job);
+    }
+  }
+
+  fn mark_draining(&self, id: usize) {
+    let mut st = self.state.lock().unwrap();
+    if let Some(h) = st.health.get_mut(&id) {
+      h.status = ConnStatus::Draining;
+    }
+    st.draining_conns.insert(id);
+  }
+
+  // NB: the drain is tracked per pool: a connection that is back ends it
+  // only if no other connection is still waiting to reconnect.
+  fn end_drain(&self, id: usize) {
+    {
+      let mut st = self.state.lock().unwrap();
+      st.draining_conns.remove(&id);
+      if !st.draining_conns.is_empty() {
+        return;
+      }
+    }
+    if self.draining.swap(false, Ordering::AcqRel) {
+      log_info!("backend: {}: drain: end, back in rotation", self.addr);
+    }
+  }
+
+  fn pick_sibling(&self) -> Option<Arc<BackendPool>> {
+    let siblings = self.siblings.lock().unwrap();
+    siblings.iter()
+      .filter_map(|s| s.upgrade())
+      .filter(|s| s.admits_request())
+      .min_by_key(|s| s.outstanding())
+  }
+
+  fn handoff(&self, job: BackendJob) {
+    self.finish();
+    match self.pick_sibling() {
+      None => {
+        log_warn!("backend: {}: drain: no sibling available, dropping request", self.addr);
+        job.reply.fail(503);
+      }
+      Some(sibling) => {
+        log_debug!("backend: {}: drain: switch over to {}", self.addr, sibling.addr);
+        match sibling.submit(job) {
+          Ok(_) => {}
+          Err(job) => {
+            log_warn!("backend: {}: drain: {} queue full, dropping request", self.addr, sibling.addr);
+            job.reply.fail(503);
+          }
+        }
+      }
+    }
+  }
+
+  fn earn_retry_token(&self) {
+    let mut tokens = self.retry_tokens.lock().unwrap();
+    *tokens = (*tokens + self.cfg.retry.budget_ratio).min(self.cfg.retry.budget_cap as f64);
+  }
+
+  fn withdraw_retry_token(&self) -> bool {
+    let mut tokens = self.retry_tokens.lock().unwrap();
+    if *tokens < 1.0 {
+      return false;
+    }
+    *tokens -= 1.0;
+    true
+  }
+
+  // NB: called after a failed query, when the request may or may not have
+  // reached the backend.
+  fn retry(self: &Arc<Self>, job: BackendJob) {
+    let policy = &self.cfg.retry;
+    if !job.retryable {
+      log_info!("backend: {}: retry: request not retryable", self.addr);
+      self.finish();
+      job.reply.fail(503);
+      return;
+    }
+    if job.attempts >= policy.max_attempts {
+      log_info!("backend: {}: retry: attempts exhausted ({})", self.addr, job.attempts);
+      self.finish();
+      job.reply.fail(503);
+      return;
+    }
+    let sibling = if policy.other_member { self.pick_sibling() } else { None };
+    // NB: a retry on this member waits for a reconnect; if the client would
+    // give up first, it gets the 503 now rather than a 504 while the retry
+    // still runs.
+    let needed = if sibling.is_some() { StdDuration::from_secs(0) } else { self.cfg.reconnect_delay };
+    if Instant::now() + needed >= job.deadline {
+      log_info!("backend: {}: retry: no time left before the client times out", self.addr);
+      self.finish();
+      job.reply.fail(503);
+      return;
+    }
+    if !self.withdraw_retry_token() {
+      log_warn!("backend: {}: retry: budget exhausted", self.addr);
+      self.finish();
+      job.reply.fail(503);
+      return;
+    }
+    metrics().backend_retries.with(&self.addr).inc();
+    match sibling {
+      None => {
+        log_info!("backend: {}: retry: attempt {} on same member", self.addr, job.attempts + 1);
+        self.requeue(job);
+      }
+      Some(sibling) => {
+        log_info!("backend: {}: retry: attempt {} on {}", self.addr, job.attempts + 1, sibling.addr);
+        self.finish();
+        match sibling.submit(job) {
+          Ok(_) => {}
+          Err(job) => {
+            log_warn!("backend: {}: retry: {} queue full, dropping request", self.addr, sibling.addr);
+            job.reply.fail(503);
+          }
+        }
+      }
+    }
+  }
+
+  fn requeue(&self, job: BackendJob) {
+    self.state.lock().unwrap().jobs.push_front((Instant::now(), job));
+    self.cond.notify_one();
+  }
+}
+
+fn conn_loop(pool: Arc<BackendPool>, id: usize) {
+  log_info!("backend: {}: conn {}: start", pool.addr, id);
+  let mut first = Some(());
+  'outer: loop {
+    if first.take().is_none() {
+      sleep(pool.cfg.reconnect_delay);
+    }
+    let addr = match pool.addr.to_socket_addrs().ok().and_then(|mut addrs| addrs.next()) {
+      None => {
+        log_info!("backend: {}: conn {}: failed to resolve", pool.addr, id);
+        pool.update(id, |h| h.status = ConnStatus::Down);
+        continue 'outer;
+      }
+      Some(addr) => addr
+    };
+    let stream = match TcpStream::connect_timeout(&addr, pool.cfg.connect_timeout) {
+      Ok(stream) => stream,
+      Err(_) => {
+        //log_debug!("backend:   connect: failed: addr={}", pool.addr);
+        metrics().backend_connect_failures.with(&pool.addr).inc();
+        pool.update(id, |h| h.status = ConnStatus::Down);
+        pool.record(false);
+        continue 'outer;
+      }
+    };
+    let mut chan: Chan = Chan::new(stream);
+    match chan.query(&Msg::OKQ) {
+      Ok(Msg::OKR) => {}
+      Ok(Msg::HUP) => {
+        pool.mark_draining(id);
+        pool.begin_drain();
+        continue 'outer;
+      }
+      _ => {
+        //log_debug!("backend:   setup: failed: addr={}", pool.addr);
+        metrics().backend_connect_failures.with(&pool.addr).inc();
+        pool.update(id, |h| h.status = ConnStatus::Down);
+        pool.record(false);
+        continue 'outer;
+      }
+    }
+    pool.update(id, |h| {
+      h.consecutive_failures = 0;
+      h.last_ok = Some(get_time_coarse());
+    });
+    pool.end_drain(id);
+    log_info!("backend: {}: conn {}: connected", pool.addr, id);
+    metrics().backend_connects.with(&pool.addr).inc();
+    loop {
+      let mut job = match pool.take_job(id) {
+        None => {
+          log_info!("backend: {}: conn {}: idle, retiring", pool.addr, id);
+          return;
+        }
+        Some(job) => job
+      };
+      let _log = crate::log::scope(job.log_ctx.clone());
+      if pool.is_draining() {
+        pool.handoff(job);
+        pool.mark_draining(id);
+        continue 'outer;
+      }
+      // NB: a retry has already waited out the reconnect delay; only a
+      // first attempt is shed for its age.
+      if job.attempts == 0 && job.queued.elapsed() >= pool.cfg.queue_max_age {
+        pool.shed.fetch_add(1, Ordering::Relaxed);
+        pool.finish();
+        job.reply.fail(503);
+        continue;
+      }
+      if !pool.claim() {
+        log_info!("backend: {}: conn {}: breaker open, handing off", pool.addr, id);
+        pool.handoff(job);
+        continue;
+      }
+      job.attempts += 1;
+      let req = Msg::H1Q(job.req);
+      let t_query = Instant::now();
+      let maybe_rep = match chan.query(&req) {
+        Ok(Msg::Top) => None,
+        Ok(Msg::H1P(rep)) => Some(rep),
+        Ok(Msg::HUP) => {
+          // NB: the backend declined this request without handling it.
+          job.req = match req {
+            Msg::H1Q(req) => req,
+            _ => unreachable!()
+          };
+          job.attempts -= 1;
+          pool.release_trial();
+          pool.mark_draining(id);
+          pool.begin_drain();
+          pool.handoff(job);
+          continue 'outer;
+        }
+        _ => {
+          log_debug!("backend:   query: failed");
+          job.req = match req {
+            Msg::H1Q(req) => req,
+            _ => unreachable!()
+          };
+          pool.record(false);
+          pool.retry(job);
+          pool.update(id, |h| {
+            h.status = ConnStatus::Down;
+            h.failures += 1;
+            h.consecutive_failures += 1;
+          });
+          log_info!("backend: {}: conn {}: disconnected", pool.addr, id);
+          metrics().backend_disconnects.with(&pool.addr).inc();
+          continue 'outer;
+        }
+      };
+      let timing = BackendTiming{queued: job.queued, sent: t_query, received: Instant::now()};
+      pool.record(true);
+      pool.update(id, |h| {
+        h.served += 1;
+        h.consecutive_failures = 0;
+        h.last_ok = Some(get_time_coarse());
+      });
+      pool.finish();
+      match job.reply.send(maybe_rep, &pool.addr, timing) {
+        Ok(_) => {}
+        _ => {}
+      }
+    }
+  }
+}
+
+fn shed_loop(pool: Arc<BackendPool>) {
+  let interval = (pool.cfg.queue_max_age / 4).max(StdDuration::from_millis(100));
+  loop {
+    sleep(interval);
+    pool.shed_stale();
+  }
+}
+
+fn check_loop(pool: Arc<BackendPool>, cfg: HealthCheckConfig) {
+  log_info!("health: {}: start: probe = {:?} interval = {:?}", pool.addr, cfg.probe, cfg.interval);
+  loop {
+    let ok = probe(&pool.addr, &cfg);
+    pool.record_check(ok, &cfg);
+    sleep(cfg.interval);
+  }
+}
+
+fn probe(addr: &str, cfg: &HealthCheckConfig) -> bool {
+  let addr = match addr.to_socket_addrs().ok().and_then(|mut addrs| addrs.next()) {
+    None => return false,
+    Some(addr) => addr
+  };
+  let stream = match TcpStream::connect_timeout(&addr, cfg.timeout) {
+    Err(_) => return false,
+    Ok(stream) => stream
+  };
+  if stream.set_read_timeout(Some(cfg.timeout)).is_err() ||
+     stream.set_write_timeout(Some(cfg.timeout)).is_err()
+  {
+    return false;
+  }
+  let mut chan: Chan = Chan::new(stream);
+  match chan.query(&Msg::OKQ) {
+    Ok(Msg::OKR) => {}
+    _ => return false
+  }
+  match &cfg.probe {
+    &Probe::Ping => true,
+    &Probe::HttpGet(ref path) => {
+      let req = match probe_request(path, &cfg.host) {
+        None => return false,
+        Some(req) => req
+      };
+      match chan.query(&Msg::H1Q(req)) {
+        Ok(Msg::H1P(rep)) => {
+          let mut buf = Vec::new();
+          if rep.to_raw().encode(&mut buf).is_err() {
+            return false;
+          }
+          match response_status(&buf) {
+            Some(status) => status >= 200 && status < 400,
+            None => false
+          }
+        }
+        _ => false
+      }
+    }
+  }
+}
+
+fn probe_request(path: &str, host: &str) -> Option<HttpRequest> {
+  let raw = format!("GET {} HTTP/1.1\r\nHost: {}\r\n\r\n", path, host);
+  let base_url = http1::Url::parse("http://127.0.0.1").ok()?;
+  let mut parser = http1::RequestParser::new(raw.bytes());
+  let mut req = http1::Request::default();
+  parser.parse_first_line(&base_url, &mut req).ok()?;
+  parser.parse_headers(&mut req).ok()?;
+  drop(parser);
+  HttpRequest::try_from_raw_strip_headers(req).ok().map(|(req, _)| req)
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Strategy {
+  RoundRobin,
+  LeastOutstanding,
+  HashClientIp,
+  HashCookie(SmolStr),
+}
+
+impl Default for Strategy {
+  fn default() -> Strategy {
+    Strategy::RoundRobin
+  }
+}
+
+#[derive(Clone, Debug)]
+pub struct GroupConfig {
+  pub members: Vec<SmolStr>,
+  pub strategy: Strategy,
+  pub health: Option<HealthCheckConfig>,
+  pub breaker: Option<BreakerConfig>,
+}
+
+pub struct PickKey<'a> {
+  pub client_ip: Option<IpAddr>,
+  pub cookie: Option<&'a [u8]>,
+}
+
+const RING_VNODES: usize = 64;
+
+pub struct BackendGroup {
+  name: SmolStr,
+  members: Vec<Arc<BackendPool>>,
+  strategy: Strategy,
+  next: AtomicUsize,
+  ring: Vec<(u64, usize)>,
+}
+
+impl BackendGroup {
+  pub fn start(name: &str, cfg: &GroupConfig, backend_cfg: &BackendConfig) -> BackendGroup {
+    let members: Vec<_> = cfg.members.iter().map(|addr| BackendPool::start(addr, backend_cfg.clone(), cfg.breaker.clone())).collect();
+    if let Some(health) = cfg.health.as_ref() {
+      for member in members.iter() {
+        member.start_health_check(health.clone());
+      }
+    }
+    let mut ring = Vec::with_capacity(members.len() * RING_VNODES);
+    for (idx, member) in members.iter().enumerate() {
+      for vnode in 0 .. RING_VNODES {
+        ring.push((ring_hash(format!("{}#{}", member.addr(), vnode).as_bytes()), idx));
+      }
+    }
+    ring.sort();
+    for member in members.iter() {
+      let siblings = members.iter()
+        .filter(|m| !Arc::ptr_eq(m, member))
+        .map(|m| Arc::downgrade(m))
+        .collect();
+      member.set_siblings(siblings);
+    }
+    log_info!("backend: group {:?}: members = {} strategy = {:?}", name, members.len(), cfg.strategy);
+    BackendGroup{
+      name: name.into(),
+      members,
+      strategy: cfg.strategy.clone(),
+      next: AtomicUsize::new(0),
+      ring,
+    }
+  }
+
+  pub fn name(&self) -> &str {
+    &self.name
+  }
+
+  pub fn members(&self) -> &[Arc<BackendPool>] {
+    &self.members
+  }
+
+  pub fn pick(&self, key: &PickKey) -> Option<&Arc<BackendPool>> {
+    let n = self.members.len();
+    if n == 0 {
+      return None;
+    }
+    match &self.strategy {
+      &Strategy::RoundRobin => {
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        (0 .. n).map(|k| &self.members[(start + k) % n]).find(|m| m.admits_request())
+      }
+      &Strategy::LeastOutstanding => {
+        self.members.iter().filter(|m| m.admits_request()).min_by_key(|m| m.outstanding())
+      }
+      &Strategy::HashClientIp => {
+        match key.client_ip {
+          None => self.select_ring(0),
+          Some(IpAddr::V4(ip)) => self.select_ring(ring_hash(&ip.octets())),
+          Some(IpAddr::V6(ip)) => self.select_ring(ring_hash(&ip.octets())),
+        }
+      }
+      &Strategy::HashCookie(ref name) => {
+        match key.cookie.and_then(|c| cookie_value(c, name)) {
+          None => self.select_ring(key.client_ip.map_or(0, |ip| ring_hash(ip.to_string().as_bytes()))),
+          Some(value) => self.select_ring(ring_hash(value)),
+        }
+      }
+    }
+  }
+
+  fn select_ring(&self, hash: u64) -> Option<&Arc<BackendPool>> {
+    let start = match self.ring.binary_search_by(|&(h, _)| h.cmp(&hash)) {
+      Ok(pos) | Err(pos) => pos
+    };
+    let len = self.ring.len();
+    // NB: each member has many points on the ring; ask it only once.
+    let mut tried = vec![false; self.members.len()];
+    for k in 0 .. len {
+      let (_, idx) = self.ring[(start + k) % len];
+      if tried[idx] {
+        continue;
+      }
+      tried[idx] = true;
+      let member = &self.members[idx];
+      if member.admits_request() {
+        return Some(member);
+      }
+    }
+    None
+  }
+}
+
+pub fn cookie_value<'a>(header: &'a [u8], name: &str) -> Option<&'a [u8]> {
+  for pair in header.split(|&x| x == b';') {
+    let pair = match pair.iter().position(|&x| x != b' ') {
+      None => continue,
+      Some(start) => &pair[start .. ]
+    };
+    let eq = match pair.iter().position(|&x| x == b'=') {
+      None => continue,
+      Some(eq) => eq
+    };
+    if &pair[ .. eq] == name.as_bytes() {
+      return Some(&pair[eq + 1 .. ]);
+    }
+  }
+  None
+}
+
+// NB: FNV-1a alone barely mixes short, similar keys like `addr#vnode` or
+// an IPv4 address into the high bits, which order the ring; the MurmurHash3
+// finalizer spreads them.
+fn ring_hash(buf: &[u8]) -> u64 {
+  let mut h = fnv1a64(buf);
+  h ^= h >> 33;
+  h = h.wrapping_mul(0xff51_afd7_ed55_8ccd);
+  h ^= h >> 33;
+  h = h.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+  h ^= h >> 33;
+  h
+}
+
+fn fnv1a64(buf: &[u8]) -> u64 {
+  let mut h: u64 = 0xcbf2_9ce4_8422_2325;
+  for &x in buf.iter() {
+    h ^= x as u64;
+    h = h.wrapping_mul(0x0000_0100_0000_01b3);
+  }
+  h
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // NB: no connections are opened, so the members stay up.
+  fn group(strategy: Strategy) -> BackendGroup {
+    let cfg = GroupConfig{
+      members: vec!["127.0.0.1:1".into(), "127.0.0.1:2".into(), "127.0.0.1:3".into()],
+      strategy,
+      health: None,
+      breaker: None,
+    };
+    let backend_cfg = BackendConfig{min_conns: 0, .. BackendConfig::default()};
+    BackendGroup::start("test", &cfg, &backend_cfg)
+  }
+
+  fn key(client_ip: &str, cookie: Option<&'static [u8]>) -> PickKey<'static> {
+    PickKey{client_ip: Some(client_ip.parse().unwrap()), cookie}
+  }
+
+  fn pick_addr(g: &BackendGroup, key: &PickKey) -> Option<String> {
+    g.pick(key).map(|m| m.addr().to_string())
+  }
+
+  #[test]
+  fn test_pick_round_robin() {
+    let g = group(Strategy::RoundRobin);
+    let k = key("192.0.2.1", None);
+    let picks: Vec<_> = (0 .. 6).map(|_| pick_addr(&g, &k).unwrap()).collect();
+    assert_eq!(picks, ["127.0.0.1:1", "127.0.0.1:2", "127.0.0.1:3", "127.0.0.1:1", "127.0.0.1:2", "127.0.0.1:3"]);
+    g.members()[1].disable();
+    let picks: Vec<_> = (0 .. 4).map(|_| pick_addr(&g, &k).unwrap()).collect();
+    assert!(picks.iter().all(|addr| addr != "127.0.0.1:2"), "picks: {:?}", picks);
+    for m in g.members().iter() {
+      m.disable();
+    }
+    assert_eq!(pick_addr(&g, &k), None);
+  }
+
+  #[test]
+  fn test_pick_least_outstanding() {
+    let g = group(Strategy::LeastOutstanding);
+    let k = key("192.0.2.1", None);
+    for (m, n) in g.members().iter().zip([2, 0, 1].iter()) {
+      m.outstanding.store(*n, Ordering::Release);
+    }
+    assert_eq!(pick_addr(&g, &k).unwrap(), "127.0.0.1:2");
+    g.members()[1].disable();
+    assert_eq!(pick_addr(&g, &k).unwrap(), "127.0.0.1:3");
+    g.members()[1].enable();
+    g.members()[0].outstanding.store(0, Ordering::Release);
+    // NB: ties go to the first member.
+    assert_eq!(pick_addr(&g, &k).unwrap(), "127.0.0.1:1");
+  }
+
+  #[test]
+  fn test_ring() {
+    let g = group(Strategy::HashClientIp);
+    assert_eq!(g.ring.len(), 3 * RING_VNODES);
+    assert!(g.ring.windows(2).all(|w| w[0] <= w[1]));
+    for idx in 0 .. 3 {
+      assert_eq!(g.ring.iter().filter(|&&(_, i)| i == idx).count(), RING_VNODES);
+    }
+    let ips: Vec<String> = (0 .. 300).map(|i| format!("10.0.{}.{}", i / 256, i % 256)).collect();
+    let before: Vec<_> = ips.iter().map(|ip| pick_addr(&g, &key(ip, None)).unwrap()).collect();
+    // Sticky, and spread over all members.
+    for (ip, addr) in ips.iter().zip(before.iter()) {
+      assert_eq!(&pick_addr(&g, &key(ip, None)).unwrap(), addr);
+    }
+    for m in g.members().iter() {
+      let n = before.iter().filter(|&addr| addr == m.addr()).count();
+      assert!(n >= ips.len() / 6, "member: {} clients: {}", m.addr(), n);
+    }
+    // Taking out a member only moves the clients that it had.
+    g.members()[0].disable();
+    for (ip, addr) in ips.iter().zip(before.iter()) {
+      let after = pick_addr(&g, &key(ip, None)).unwrap();
+      if addr == "127.0.0.1:1" {
+        assert_ne!(after, "127.0.0.1:1");
+      } else {
+        assert_eq!(&after, addr, "ip: {}", ip);
+      }
+    }
+  }
+
+  #[test]
+  fn test_pick_hash_cookie() {
+    let g = group(Strategy::HashCookie("sid".into()));
+    let a = pick_addr(&g, &key("192.0.2.1", Some(b"theme=dark; sid=abc123"))).unwrap();
+    assert_eq!(pick_addr(&g, &key("192.0.2.2", Some(b"sid=abc123"))).unwrap(), a);
+    assert_eq!(g.pick(&key("192.0.2.3", Some(b"sid=abc123"))).unwrap().addr(),
+               g.select_ring(ring_hash(b"abc123")).unwrap().addr());
+    // Without the cookie, by client address.
+    let b = pick_addr(&g, &key("192.0.2.9", None)).unwrap();
+    assert_eq!(pick_addr(&g, &key("192.0.2.9", Some(b"theme=dark"))).unwrap(), b);
+  }
+
+  fn breaker(open_for: StdDuration) -> Breaker {
+    Breaker::new("test", BreakerConfig{
+      consecutive_failures: 3,
+      error_rate: 0.5,
+      window: 10,
+      min_samples: 6,
+      open_for,
+    })
+  }
+
+  fn state(b: &Breaker) -> BreakerState {
+    b.stats().state
+  }
+
+  #[test]
+  fn test_breaker_cycle() {
+    let b = breaker(StdDuration::from_secs(0));
+    assert_eq!(state(&b), BreakerState::Closed);
+    assert!(b.claim());
+    b.record(false);
+    b.record(false);
+    assert_eq!(state(&b), BreakerState::Closed);
+    b.record(false);
+    assert_eq!(state(&b), BreakerState::Open);
+    assert_eq!(b.stats().opens, 1);
+    // Once `open_for` is over, one request is let through as the trial.
+    assert!(b.admits());
+    assert_eq!(state(&b), BreakerState::Open);
+    assert!(b.claim());
+    assert_eq!(state(&b), BreakerState::HalfOpen);
+    assert!(!b.admits());
+    assert!(!b.claim());
+    // A failed trial opens it again.
+    b.record(false);
+    assert_eq!(state(&b), BreakerState::Open);
+    assert_eq!(b.stats().opens, 2);
+    // A declined trial frees the slot for the next request.
+    assert!(b.claim());
+    b.release_trial();
+    assert_eq!(state(&b), BreakerState::HalfOpen);
+    assert!(b.admits());
+    assert!(b.claim());
+    // A successful trial closes it, with a fresh window.
+    b.record(true);
+    assert_eq!(state(&b), BreakerState::Closed);
+    b.record(false);
+    b.record(false);
+    assert_eq!(state(&b), BreakerState::Closed);
+    assert_eq!(b.stats().rejected, 1);
+  }
+
+  #[test]
+  fn test_breaker_open() {
+    let b = breaker(StdDuration::from_secs(3600));
+    for _ in 0 .. 3 {
+      b.record(false);
+    }
+    assert_eq!(state(&b), BreakerState::Open);
+    assert!(!b.admits());
+    assert!(!b.claim());
+    b.reject();
+    assert_eq!(b.stats().rejected, 2);
+    // Not a trial, so the breaker stays open.
+    b.release_trial();
+    assert_eq!(state(&b), BreakerState::Open);
+  }
+
+  #[test]
+  fn test_breaker_error_rate() {
+    let b = breaker(StdDuration::from_secs(3600));
+    // Alternating results never reach 3 consecutive failures.
+    for i in 0 .. 5 {
+      b.record(i % 2 == 1);
+    }
+    assert_eq!(state(&b), BreakerState::Closed);
+    // 3 failures in 6 samples reach the 50% error rate.
+    b.record(false);
+    assert_eq!(state(&b), BreakerState::Open);
+  }
+
+  #[test]
+  fn test_breaker_concurrent_trial() {
+    use std::sync::{Barrier};
+    for _ in 0 .. 20 {
+      let b = Arc::new(breaker(StdDuration::from_secs(0)));
+      for _ in 0 .. 3 {
+        b.record(false);
+      }
+      let barrier = Arc::new(Barrier::new(2));
+      let threads: Vec<_> = (0 .. 2).map(|_| {
+        let b = b.clone();
+        let barrier = barrier.clone();
+        std::thread::spawn(move || {
+          barrier.wait();
+          b.claim()
+        })
+      }).collect();
+      let claimed: Vec<bool> = threads.into_iter().map(|t| t.join().unwrap()).collect();
+      assert_eq!(claimed.iter().filter(|&&c| c).count(), 1, "claimed: {:?}", claimed);
+      assert_eq!(state(&b), BreakerState::HalfOpen);
+      assert_eq!(b.stats().rejected, 1);
+    }
+  }
+
+  #[test]
+  fn test_retry_allows() {
+    use http1::Method::*;
+    let default = RetryPolicy::default();
+    let keyed = RetryPolicy{idempotency_key: true, .. RetryPolicy::default()};
+    let once = RetryPolicy{max_attempts: 1, .. RetryPolicy::default()};
+    let cases: Vec<(&str, &RetryPolicy, Option<http1::Method>, bool, bool)> = vec![
+      ("get", &default, Some(Get), false, true),
+      ("head", &default, Some(Head), false, true),
+      ("options", &default, Some(Options), false, true),
+      ("trace", &default, Some(Trace), false, true),
+      ("put", &default, Some(Put), false, true),
+      ("delete", &default, Some(Delete), false, true),
+      ("post", &default, Some(Post), false, false),
+      ("post with key, not enabled", &default, Some(Post), true, false),
+      ("post with key", &keyed, Some(Post), true, true),
+      ("patch with key", &keyed, Some(Patch), true, true),
+      ("post without key", &keyed, Some(Post), false, false),
+      ("connect", &keyed, Some(Connect), true, false),
+      ("no method", &default, None, false, false),
+      ("one attempt", &once, Some(Get), false, false),
+    ];
+    for (name, policy, method, has_key, expected) in cases {
+      assert_eq!(policy.allows(method.as_ref(), has_key), expected, "case: {}", name);
+    }
+  }
+
+  #[test]
+  fn test_retry_budget() {
+    let retry = RetryPolicy{budget_ratio: 0.5, budget_cap: 2, .. RetryPolicy::default()};
+    let cfg = BackendConfig{min_conns: 0, retry, .. BackendConfig::default()};
+    let pool = BackendPool::start("127.0.0.1:1", cfg, None);
+    // Starts full.
+    assert!(pool.withdraw_retry_token());
+    assert!(pool.withdraw_retry_token());
+    assert!(!pool.withdraw_retry_token());
+    // Two requests earn one token.
+    pool.earn_retry_token();
+    assert!(!pool.withdraw_retry_token());
+    pool.earn_retry_token();
+    assert!(pool.withdraw_retry_token());
+    assert!(!pool.withdraw_retry_token());
+    // Never more than the cap.
+    for _ in 0 .. 10 {
+      pool.earn_retry_token();
+    }
+    assert_eq!(*pool.retry_tokens.lock().unwrap(), 2.0);
+  }
+
+  #[test]
+  fn test_cookie_value() {
+    let cases: Vec<(&[u8], &str, Option<&[u8]>)> = vec![
+      (b"sid=abc", "sid", Some(b"abc")),
+      (b"a=1; sid=abc; b=2", "sid", Some(b"abc")),
+      (b"a=1;sid=abc", "sid", Some(b"abc")),
+      (b"  sid=", "sid", Some(b"")),
+      (b"xsid=abc; sid=def", "sid", Some(b"def")),
+      (b"sid=abc; sid=def", "sid", Some(b"abc")),
+      (b"SID=abc", "sid", None),
+      (b"sid", "sid", None),
+      (b"a=1; b=2", "sid", None),
+      (b"", "sid", None),
+    ];
+    for (header, name, expected) in cases {
+      assert_eq!(cookie_value(header, name), expected, "header: {:?}", String::from_utf8_lossy(header));
+    }
+  }
+}
@@ -1,29 +1,41 @@
+#![forbid(unsafe_code)]
+
 pub use service_base::daemon::{protect};
 
+use smol_str::{SmolStr};
+
 use std::env::{set_current_dir};
-use std::fs::{create_dir_all};
+use std::fs::{OpenOptions, create_dir_all, metadata};
 use std::io::{Error as IoError};
-use std::os::unix::fs::{chroot};
-use std::path::{Path};
+use std::os::unix::fs::{MetadataExt, chroot};
+use std::path::{Path, PathBuf};
 use std::process::{Command};
 
-pub fn mount<P: AsRef<Path>>(chroot_dir: P) -> Result<(), IoError> {
-  let chroot_dir = chroot_dir.as_ref().to_owned();
-  let mount = |suffix| -> Result<(), IoError> {
-    let res = Command::new("mount")
-      //.arg("--bind")
-      .arg("-o").arg("bind,ro")
-      //.arg("-o").arg("remount,bind,ro")
-      .arg(suffix)
-      .arg(chroot_dir.join(suffix))
-      .status().ok().unwrap();
-    assert!(res.success());
-    Ok(())
-  };
-  // TODO: any other paths to bind mount?
-  mount("/dev/random")?;
-  mount("/dev/urandom")?;
-  mount("/etc/ssl")?;
+pub fn mount<P: AsRef<Path>>(chroot_dir: P, paths: &[PathBuf]) -> Result<(), SandboxErr> {
+  let chroot_dir = chroot_dir.as_ref();
+  for path in paths.iter() {
+    let target = chroot_dir.join(path.strip_prefix("/").unwrap_or(path));
+    let src_meta = metadata(path).map_err(|e| SandboxErr::Io(path.clone(), e))?;
+    if src_meta.is_dir() {
+      create_dir_all(&target).map_err(|e| SandboxErr::Io(target.clone(), e))?;
+    } else if metadata(&target).is_err() {
+      if let Some(parent) = target.parent() {
+        create_dir_all(parent).map_err(|e| SandboxErr::Io(parent.to_owned(), e))?;
+      }
+      OpenOptions::new().create(true).write(true).open(&target)
+        .map_err(|e| SandboxErr::Io(target.clone(), e))?;
+    }
+    // NB: a remount is needed for the read-only flag to take effect on
+    // older kernels.
+    let ok = Command::new("mount").arg("--bind").arg(path).arg(&target).status()
+      .map(|st| st.success()).unwrap_or(false) &&
+      Command::new("mount").arg("-o").arg("remount,bind,ro").arg(&target).status()
+      .map(|st| st.success()).unwrap_or(false);
+    if !ok {
+      return Err(SandboxErr::Mount(path.clone()));
+    }
+    log_info!("sandbox: bind mount {:?} -> {:?} (ro)", path, target);
+  }
   Ok(())
 }
 
@@ -34,9 +46,257 @@ pub fn mount<P: AsRef<Path>>(chroot_dir: P) -> Result<(), IoError> {
   Ok(())
 }*/
 
-pub fn mkdir() -> Result<(), IoError> {
-  // TODO: do not hide following errors.
-  create_dir_all("/var/tmp/acme-staging").ok();
-  create_dir_all("/var/tmp/acme").ok();
+pub fn mkdir() -> Result<(), SandboxErr> {
+  for dir in ["/var/tmp/acme-staging", "/var/tmp/acme"].iter() {
+    create_dir_all(dir).map_err(|e| SandboxErr::Io(PathBuf::from(dir), e))?;
+  }
   Ok(())
 }
+
+#[derive(Clone, Debug)]
+pub struct SandboxConfig {
+  // User name or numeric uid.
+  pub user: SmolStr,
+  // Group name or numeric gid; defaults to the user's primary group.
+  pub group: Option<SmolStr>,
+  pub chroot_dir: PathBuf,
+  // Host paths bind mounted read-only at the same path inside the chroot,
+  // e.g. `/dev/urandom` or `/etc/ssl`.
+  pub bind_mounts: Vec<PathBuf>,
+  // Directory inside the chroot holding `<domain>.crt` and `<domain>.key`.
+  pub tls_dir: PathBuf,
+}
+
+impl Default for SandboxConfig {
+  fn default() -> SandboxConfig {
+    SandboxConfig{
+      user: "297".into(),
+      group: Some("297".into()),
+      chroot_dir: PathBuf::from("/var/lib/proxy_gateway/new_root"),
+      bind_mounts: Vec::new(),
+      tls_dir: PathBuf::from("/var/tmp/acme"),
+    }
+  }
+}
+
+#[derive(Debug)]
+pub enum SandboxErr {
+  UnknownUser(SmolStr),
+  UnknownGroup(SmolStr),
+  Io(PathBuf, IoError),
+  Mount(PathBuf),
+  Verify(String),
+}
+
+pub struct Sandbox {
+  pub uid: u32,
+  pub gid: u32,
+  pub chroot_dir: PathBuf,
+  pub bind_mounts: Vec<PathBuf>,
+  root_id: (u64, u64),
+  // `(dev, ino)` of each bind mount source, in the order of `bind_mounts`.
+  mount_ids: Vec<(u64, u64)>,
+}
+
+impl Sandbox {
+  // Resolves ids and sets up bind mounts; the TLS identity is loaded by the
+  // caller before `enter`, see `outside_path`.
+  pub fn prepare(cfg: &SandboxConfig) -> Result<Sandbox, SandboxErr> {
+    let (uid, user_gid) = match cfg.user.parse::<u32>() {
+      Ok(uid) => (uid, None),
+      Err(_) => match crate::sys::lookup_user(&cfg.user) {
+        Err(e) => return Err(SandboxErr::Io(PathBuf::from("/etc/passwd"), e)),
+        Ok(None) => return Err(SandboxErr::UnknownUser(cfg.user.clone())),
+        Ok(Some((uid, gid))) => (uid, Some(gid))
+      }
+    };
+    let gid = match cfg.group.as_ref() {
+      None => match user_gid {
+        None => return Err(SandboxErr::UnknownGroup(cfg.user.clone())),
+        Some(gid) => gid
+      },
+      Some(group) => match group.parse::<u32>() {
+        Ok(gid) => gid,
+        Err(_) => match crate::sys::lookup_group(group) {
+          Err(e) => return Err(SandboxErr::Io(PathBuf::from("/etc/group"), e)),
+          Ok(None) => return Err(SandboxErr::UnknownGroup(group.clone())),
+          Ok(Some(gid)) => gid
+        }
+      }
+    };
+    if uid == 0 || gid == 0 {
+      return Err(SandboxErr::Verify(format!("refusing to run as uid = {} gid = {}", uid, gid)));
+    }
+    let root = metadata(&cfg.chroot_dir).map_err(|e| SandboxErr::Io(cfg.chroot_dir.clone(), e))?;
+    if !root.is_dir() {
+      return Err(SandboxErr::Verify(format!("chroot dir {:?} is not a directory", cfg.chroot_dir)));
+    }
+    mount(&cfg.chroot_dir, &cfg.bind_mounts)?;
+    let mut mount_ids = Vec::with_capacity(cfg.bind_mounts.len());
+    for path in cfg.bind_mounts.iter() {
+      let src = metadata(path).map_err(|e| SandboxErr::Io(path.clone(), e))?;
+      mount_ids.push((src.dev(), src.ino()));
+    }
+    log_info!("sandbox: prepared: uid = {} gid = {} chroot = {:?} mounts = {}",
+        uid, gid, cfg.chroot_dir, cfg.bind_mounts.len());
+    Ok(Sandbox{
+      uid,
+      gid,
+      chroot_dir: cfg.chroot_dir.clone(),
+      bind_mounts: cfg.bind_mounts.clone(),
+      root_id: (root.dev(), root.ino()),
+      mount_ids,
+    })
+  }
+
+  // Path of a file inside the chroot, as seen before entering it.
+  pub fn outside_path<P: AsRef<Path>>(&self, path: P) -> PathBuf {
+    let path = path.as_ref();
+    self.chroot_dir.join(path.strip_prefix("/").unwrap_or(path))
+  }
+
+  pub fn enter(&self) -> Result<(), SandboxErr> {
+    protect(&self.chroot_dir, self.uid, self.gid)
+      .map_err(|e| SandboxErr::Io(self.chroot_dir.clone(), e))?;
+    mkdir()?;
+    self.verify()?;
+    log_info!("sandbox: entered: verified");
+    Ok(())
+  }
+
+  fn verify(&self) -> Result<(), SandboxErr> {
+    let (uid, euid, gid, egid) = crate::sys::current_ids();
+    if uid != self.uid || euid != self.uid || gid != self.gid || egid != self.gid {
+      return Err(SandboxErr::Verify(format!(
+          "ids: uid = {} euid = {} gid = {} egid = {} (expected uid = {} gid = {})",
+          uid, euid, gid, egid, self.uid, self.gid)));
+    }
+    let root = metadata("/").map_err(|e| SandboxErr::Io(PathBuf::from("/"), e))?;
+    if (root.dev(), root.ino()) != self.root_id {
+      return Err(SandboxErr::Verify(format!("root is not the chroot dir {:?}", self.chroot_dir)));
+    }
+    // NB: a bind mount shares the source's device and inode, whereas a
+    // leftover file or directory at the mount point does not.
+    for (path, &id) in self.bind_mounts.iter().zip(self.mount_ids.iter()) {
+      let target = match metadata(path) {
+        Err(_) => return Err(SandboxErr::Verify(format!("bind mount {:?} missing inside chroot", path))),
+        Ok(target) => target
+      };
+      if (target.dev(), target.ino()) != id {
+        return Err(SandboxErr::Verify(format!("bind mount {:?} inside chroot is not its source", path)));
+      }
+    }
+    Ok(())
+  }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SeccompMode {
+  Off,
+  // Log syscalls outside of the allowlist to the audit log, but allow them.
+  Log,
+  // Kill the process on syscalls outside of the allowlist.
+  Enforce,
+}
+
+#[derive(Clone, Debug)]
+pub struct HardeningConfig {
+  // Keep CAP_NET_BIND_SERVICE after dropping root, e.g. to rebind a port.
+  pub keep_net_bind_service: bool,
+  pub seccomp: SeccompMode,
+}
+
+impl Default for HardeningConfig {
+  fn default() -> HardeningConfig {
+    HardeningConfig{
+      keep_net_bind_service: false,
+      seccomp: SeccompMode::Log,
+    }
+  }
+}
+
+impl HardeningConfig {
+  fn kept_caps(&self) -> Vec<u32> {
+    if self.keep_net_bind_service {
+      vec![crate::sys::CAP_NET_BIND_SERVICE]
+    } else {
+      Vec::new()
+    }
+  }
+}
+
+// Runs as root, before `Sandbox::enter` drops privileges.
+pub fn harden_pre(cfg: &HardeningConfig) -> Result<(), IoError> {
+  let keep = cfg.kept_caps();
+  crate::sys::capbset_drop_except(&keep)?;
+  crate::sys::set_keepcaps(!keep.is_empty())?;
+  Ok(())
+}
+
+// Runs after `Sandbox::enter`; all threads spawned afterwards inherit the
+// seccomp filter.
+pub fn harden(cfg: &HardeningConfig) -> Result<(), IoError> {
+  let keep = cfg.kept_caps();
+  crate::sys::capset(&keep)?;
+  crate::sys::set_keepcaps(false)?;
+  crate::sys::clear_ambient_caps()?;
+  crate::sys::set_no_new_privs()?;
+  let (effective, permitted) = crate::sys::capget()?;
+  let expected = keep.iter().fold(0_u64, |mask, &cap| mask | (1 << cap));
+  if effective != expected || permitted != expected {
+    log_error!("hardening: caps: effective = {:#x} permitted = {:#x} (expected {:#x})", effective, permitted, expected);
+    return Err(IoError::from_raw_os_error(libc::EPERM));
+  }
+  log_info!("hardening: caps: effective = {:#x} no_new_privs = 1", effective);
+  let action = match cfg.seccomp {
+    SeccompMode::Off => {
+      log_info!("hardening: seccomp: off");
+      return Ok(());
+    }
+    SeccompMode::Log => crate::sys::SECCOMP_RET_LOG,
+    SeccompMode::Enforce => crate::sys::SECCOMP_RET_KILL_PROCESS,
+  };
+  let allow = seccomp_allowlist();
+  crate::sys::seccomp_allowlist(&allow, action)?;
+  log_info!("hardening: seccomp: {:?}: allowed syscalls = {}", cfg.seccomp, allow.len());
+  Ok(())
+}
+
+// NB: covers the accept loop, TLS, backend I/O, thread spawning for the
+// backend pools, and reloading of error pages and ACME files.
+fn seccomp_allowlist() -> Vec<libc::c_long> {
+  let mut allow = vec![
+    libc::SYS_read, libc::SYS_write, libc::SYS_readv, libc::SYS_writev,
+    libc::SYS_pread64, libc::SYS_pwrite64, libc::SYS_lseek, libc::SYS_close,
+    libc::SYS_openat, libc::SYS_fstat, libc::SYS_newfstatat, libc::SYS_statx,
+    libc::SYS_getdents64, libc::SYS_mkdirat, libc::SYS_renameat, libc::SYS_unlinkat,
+    libc::SYS_fsync, libc::SYS_fdatasync, libc::SYS_ftruncate,
+    libc::SYS_fcntl, libc::SYS_ioctl, libc::SYS_dup, libc::SYS_dup3, libc::SYS_pipe2,
+    libc::SYS_mmap, libc::SYS_munmap, libc::SYS_mprotect, libc::SYS_mremap,
+    libc::SYS_madvise, libc::SYS_brk,
+    libc::SYS_rt_sigaction, libc::SYS_rt_sigprocmask, libc::SYS_rt_sigreturn,
+    libc::SYS_sigaltstack, libc::SYS_tgkill, libc::SYS_restart_syscall,
+    libc::SYS_futex, libc::SYS_sched_yield, libc::SYS_sched_getaffinity,
+    libc::SYS_nanosleep, libc::SYS_clock_nanosleep, libc::SYS_clock_gettime,
+    libc::SYS_gettimeofday, libc::SYS_getrandom,
+    libc::SYS_clone, libc::SYS_clone3, libc::SYS_set_robust_list, libc::SYS_rseq,
+    libc::SYS_prctl, libc::SYS_exit, libc::SYS_exit_group,
+    libc::SYS_getpid, libc::SYS_gettid, libc::SYS_getuid, libc::SYS_geteuid,
+    libc::SYS_getgid, libc::SYS_getegid, libc::SYS_uname,
+    libc::SYS_socket, libc::SYS_socketpair, libc::SYS_connect, libc::SYS_accept4,
+    libc::SYS_sendto, libc::SYS_recvfrom, libc::SYS_sendmsg, libc::SYS_recvmsg,
+    libc::SYS_shutdown, libc::SYS_getsockname, libc::SYS_getpeername,
+    libc::SYS_setsockopt, libc::SYS_getsockopt,
+    libc::SYS_epoll_create1, libc::SYS_epoll_ctl, libc::SYS_epoll_pwait,
+    libc::SYS_ppoll, libc::SYS_pselect6,
+  ];
+  #[cfg(target_arch = "x86_64")]
+  allow.extend_from_slice(&[
+    libc::SYS_open, libc::SYS_stat, libc::SYS_lstat, libc::SYS_mkdir,
+    libc::SYS_rename, libc::SYS_unlink, libc::SYS_pipe, libc::SYS_dup2,
+    libc::SYS_accept, libc::SYS_poll, libc::SYS_select,
+    libc::SYS_epoll_create, libc::SYS_epoll_wait, libc::SYS_arch_prctl,
+    libc::SYS_time,
+  ]);
+  allow
+}
@@ -0,0 +1,567 @@
+#![forbid(unsafe_code)]
+
+use crate::{BackendOutcome, BackendReply, ErrorReply, Gateway443, Routed, find_head_end, serve443};
+use crate::access::{AccessEntry};
+use crate::log::{LogCtx};
+use crate::metrics::{metrics};
+use crate::net::{Event, Interest, Notify, Poll, Token};
+use crate::pool::{WorkerPool};
+use crate::tls::{ClientHello, HelloStream};
+
+use native_tls::{HandshakeError, MidHandshakeTlsStream, TlsStream};
+use smol_str::{SmolStr};
+
+use std::io::{ErrorKind as IoErrorKind, Read, Write};
+use std::mem::{replace};
+use std::net::{IpAddr, Shutdown, TcpStream};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::{Arc};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{Receiver, Sender, TryRecvError, channel};
+use std::thread::{Builder as ThreadBuilder};
+use std::time::{Duration as StdDuration, Instant};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum EngineKind {
+  // Blocking I/O on a bounded pool of worker threads.
+  Pool,
+  // Nonblocking I/O driven by a small number of epoll reactor threads.
+  Epoll,
+}
+
+impl Default for EngineKind {
+  fn default() -> EngineKind {
+    EngineKind::Pool
+  }
+}
+
+#[derive(Clone, Debug)]
+pub struct ReactorConfig {
+  pub threads: usize,
+  pub max_conns: usize,
+  pub header_timeout: StdDuration,
+  pub idle_timeout: StdDuration,
+  pub backend_timeout: StdDuration,
+}
+
+impl Default for ReactorConfig {
+  fn default() -> ReactorConfig {
+    ReactorConfig{
+      threads: 2,
+      max_conns: 50_000,
+      header_timeout: StdDuration::from_secs(5),
+      idle_timeout: StdDuration::from_secs(60),
+      backend_timeout: StdDuration::from_secs(2),
+    }
+  }
+}
+
+pub type ConnJob = (TcpStream, u64);
+
+pub enum Engine {
+  Pool(WorkerPool<ConnJob>),
+  Epoll(Reactors),
+}
+
+impl Engine {
+  pub fn start(gw: Arc<Gateway443>) -> Engine {
+    let config = gw.config.clone();
+    match config.engine {
+      EngineKind::Pool => {
+        Engine::Pool(WorkerPool::new("worker", config.pool.workers, config.pool.queue_cap, move |(stream, seq_nr): ConnJob| {
+          let client_ip = stream.peer_addr().ok().map(|addr| addr.ip());
+          gw.conn_opened(seq_nr, client_ip);
+          let _closed = ConnClosed(&gw, seq_nr);
+          serve443(&gw, stream, seq_nr);
+        }))
+      }
+      EngineKind::Epoll => {
+        Engine::Epoll(Reactors::spawn(gw))
+      }
+    }
+  }
+
+  pub fn num_active(&self) -> usize {
+    match self {
+      &Engine::Pool(ref pool) => pool.num_pending(),
+      &Engine::Epoll(ref reactors) => reactors.num_live(),
+    }
+  }
+
+  pub fn try_submit(&mut self, job: ConnJob) -> Result<(), ConnJob> {
+    match self {
+      &mut Engine::Pool(ref pool) => pool.try_submit(job),
+      &mut Engine::Epoll(ref mut reactors) => reactors.try_submit(job),
+    }
+  }
+}
+
+// Calls `conn_closed` when dropped, also when `serve443` panics.
+struct ConnClosed<'a>(&'a Gateway443, u64);
+
+impl<'a> Drop for ConnClosed<'a> {
+  fn drop(&mut self) {
+    self.0.conn_closed(self.1);
+  }
+}
+
+struct ReactorHandle {
+  tx: Sender<ConnJob>,
+  notify: Arc<Notify>,
+}
+
+pub struct Reactors {
+  handles: Vec<ReactorHandle>,
+  next: usize,
+  live: Arc<AtomicUsize>,
+  max_conns: usize,
+}
+
+impl Reactors {
+  pub fn spawn(gw: Arc<Gateway443>) -> Reactors {
+    let cfg = gw.config.reactor.clone();
+    assert!(cfg.threads > 0);
+    let live = Arc::new(AtomicUsize::new(0));
+    let mut handles = Vec::with_capacity(cfg.threads);
+    for rank in 0 .. cfg.threads {
+      let (tx, rx) = channel();
+      let notify = Arc::new(Notify::new().unwrap());
+      let mut reactor = Reactor{
+        gw: gw.clone(),
+        cfg: cfg.clone(),
+        poll: Poll::new().unwrap(),
+        notify: notify.clone(),
+        rx,
+        conns: Vec::new(),
+        free: Vec::new(),
+        live: live.clone(),
+      };
+      reactor.poll.register(&*notify, WAKE_TOKEN, Interest::Read).unwrap();
+      ThreadBuilder::new()
+        .name(format!("reactor-{}", rank))
+        .spawn(move || reactor.run())
+        .unwrap();
+      handles.push(ReactorHandle{tx, notify});
+    }
+    log_info!("reactor: threads = {} max conns = {}", cfg.threads, cfg.max_conns);
+    Reactors{handles, next: 0, live, max_conns: cfg.max_conns}
+  }
+
+  pub fn num_live(&self) -> usize {
+    self.live.load(Ordering::Relaxed)
+  }
+
+  pub fn try_submit(&mut self, job: ConnJob) -> Result<(), ConnJob> {
+    if self.live.fetch_add(1, Ordering::AcqRel) >= self.max_conns {
+      self.live.fetch_sub(1, Ordering::AcqRel);
+      return Err(job);
+    }
+    let handle = &self.handles[self.next];
+    self.next = (self.next + 1) % self.handles.len();
+    match handle.tx.send(job) {
+      Err(e) => {
+        self.live.fetch_sub(1, Ordering::AcqRel);
+        Err(e.0)
+      }
+      Ok(_) => {
+        let _ = handle.notify.wake();
+        Ok(())
+      }
+    }
+  }
+}
+
+const WAKE_TOKEN: Token = Token::max_value();
+const MAX_HEAD: usize = 8192;
+
+enum ConnState {
+  Hello(HelloStream),
+  Handshake(MidHandshakeTlsStream<HelloStream>),
+  Read(TlsStream<HelloStream>, Vec<u8>),
+  Backend(TlsStream<HelloStream>, Receiver<BackendOutcome>, ErrorReply, bool, Vec<u8>, AccessEntry),
+  Write(TlsStream<HelloStream>, Vec<u8>, usize, bool, Vec<u8>, AccessEntry),
+  Closed,
+}
+
+enum Step {
+  Continue,
+  Wait(Interest),
+  Close,
+}
+
+struct Conn {
+  state: ConnState,
+  fd: RawFd,
+  client_ip: Option<IpAddr>,
+  seq_nr: u64,
+  nreqs: u64,
+  request_id: Option<SmolStr>,
+  // When the handshake started and, once done, ended; taken by the trace
+  // of the first request.
+  accepted: Instant,
+  tls: Option<(Instant, Instant)>,
+  hello: ClientHello,
+  deadline: Instant,
+}
+
+struct Reactor {
+  gw: Arc<Gateway443>,
+  cfg: ReactorConfig,
+  poll: Poll,
+  notify: Arc<Notify>,
+  rx: Receiver<ConnJob>,
+  conns: Vec<Option<Conn>>,
+  free: Vec<usize>,
+  live: Arc<AtomicUsize>,
+}
+
+impl Reactor {
+  fn run(&mut self) {
+    let mut events = Vec::new();
+    let mut last_sweep = Instant::now();
+    loop {
+      if let Err(e) = self.poll.wait(&mut events, Some(StdDuration::from_millis(250))) {
+        log_error!("reactor: wait: {:?}", e);
+        return;
+      }
+      for &ev in events.iter() {
+        if ev.token == WAKE_TOKEN {
+          self.notify.drain();
+          self.accept_new();
+          for token in self.notify.take().into_iter() {
+            self.drive(token as usize, None);
+          }
+        } else {
+          self.drive(ev.token as usize, Some(ev));
+        }
+      }
+      if self.gw.is_shutting_down() {
+        self.close_idle();
+      }
+      let now = Instant::now();
+      if now.duration_since(last_sweep) >= StdDuration::from_secs(1) {
+        last_sweep = now;
+        self.sweep(now);
+      }
+    }
+  }
+
+  fn accept_new(&mut self) {
+    loop {
+      let (stream, seq_nr) = match self.rx.try_recv() {
+        Err(_) => break,
+        Ok(job) => job
+      };
+      let fd = stream.as_raw_fd();
+      let client_ip = stream.peer_addr().ok().map(|addr| addr.ip());
+      if let Err(e) = stream.set_nonblocking(true) {
+        log_info!("reactor: set nonblocking: {:?}", e);
+        self.live.fetch_sub(1, Ordering::AcqRel);
+        continue;
+      }
+      let slot = match self.free.pop() {
+        None => {
+          self.conns.push(None);
+          self.conns.len() - 1
+        }
+        Some(slot) => slot
+      };
+      let accepted = Instant::now();
+      let state = ConnState::Hello(HelloStream::new(stream));
+      let deadline = Instant::now() + self.cfg.header_timeout;
+      self.conns[slot] = Some(Conn{
+        state, fd, client_ip, seq_nr,
+        nreqs: 0,
+        request_id: None,
+        accepted,
+        tls: None,
+        hello: ClientHello::default(),
+        deadline,
+      });
+      self.gw.conn_opened(seq_nr, client_ip);
+      if let Err(e) = self.poll.register(&fd, slot as Token, Interest::Read) {
+        log_info!("reactor: register: {:?}", e);
+        self.close(slot);
+        continue;
+      }
+      self.drive(slot, None);
+    }
+  }
+
+  // NB: during shutdown, keep-alive connections waiting for their next
+  // request are closed; connections mid-request are left to finish.
+  fn close_idle(&mut self) {
+    for slot in 0 .. self.conns.len() {
+      let idle = match self.conns[slot].as_ref() {
+        Some(&Conn{state: ConnState::Read(_, ref buf), nreqs, ..}) => nreqs > 0 && buf.is_empty(),
+        _ => false
+      };
+      if idle {
+        self.close(slot);
+      }
+    }
+  }
+
+  fn close(&mut self, slot: usize) {
+    if let Some(conn) = self.conns[slot].take() {
+      self.gw.conn_closed(conn.seq_nr);
+      let _ = self.poll.deregister(&conn.fd);
+      match conn.state {
+        ConnState::Read(stream, _) |
+        ConnState::Backend(stream, ..) |
+        ConnState::Write(stream, ..) => {
+          let _ = stream.get_ref().get_ref().shutdown(Shutdown::Both);
+        }
+        _ => {}
+      }
+      self.free.push(slot);
+      self.live.fetch_sub(1, Ordering::AcqRel);
+    }
+  }
+
+  fn sweep(&mut self, now: Instant) {
+    for slot in 0 .. self.conns.len() {
+      let expired = match self.conns[slot].as_ref() {
+        Some(conn) => conn.deadline <= now,
+        None => false
+      };
+      if !expired {
+        continue;
+      }
+      let conn = self.conns[slot].as_mut().unwrap();
+      match replace(&mut conn.state, ConnState::Closed) {
+        ConnState::Backend(stream, _, err_reply, _, _, mut entry) => {
+          log_info!("backend: timed out");
+          let buf = self.gw.complete(&err_reply, BackendOutcome::failed(504), false, &mut entry);
+          conn.state = ConnState::Write(stream, buf, 0, false, Vec::new(), entry);
+          conn.deadline = now + self.cfg.header_timeout;
+          self.drive(slot, None);
+        }
+        _ => {
+          log_info!("reactor: {}: timed out", conn.seq_nr);
+          self.close(slot);
+        }
+      }
+    }
+  }
+
+  fn drive(&mut self, slot: usize, ev: Option<Event>) {
+    let hangup = ev.map_or(false, |ev| ev.hangup && !ev.readable);
+    loop {
+      let conn = match self.conns.get_mut(slot).and_then(|c| c.as_mut()) {
+        None => return,
+        Some(conn) => conn
+      };
+      let _log = crate::log::scope(LogCtx{
+        conn: Some(conn.seq_nr),
+        req: if conn.nreqs > 0 { Some(conn.nreqs) } else { None },
+        request_id: conn.request_id.clone(),
+      });
+      if hangup {
+        if let ConnState::Backend(..) = conn.state {
+          self.close(slot);
+          return;
+        }
+      }
+      let state = replace(&mut conn.state, ConnState::Closed);
+      let (state, step) = step(&self.gw, &self.cfg, &self.notify, slot as Token, conn, state);
+      conn.state = state;
+      match step {
+        Step::Continue => {}
+        Step::Wait(interest) => {
+          let fd = conn.fd;
+          if let Err(e) = self.poll.reregister(&fd, slot as Token, interest) {
+            log_info!("reactor: reregister: {:?}", e);
+            self.close(slot);
+          }
+          return;
+        }
+        Step::Close => {
+          self.close(slot);
+          return;
+        }
+      }
+    }
+  }
+}
+
+fn step(gw: &Gateway443, cfg: &ReactorConfig, notify: &Arc<Notify>, token: Token, conn: &mut Conn, state: ConnState) -> (ConnState, Step) {
+  match state {
+    ConnState::Hello(mut stream) => {
+      match stream.read_hello() {
+        Ok(None) => (ConnState::Hello(stream), Step::Wait(Interest::Read)),
+        Ok(Some(hello)) => {
+          conn.hello = hello;
+          match gw.tls_acceptor().accept(stream) {
+            Ok(stream) => {
+              log_info!("tls: accepted");
+              conn.tls = Some((conn.accepted, Instant::now()));
+              (ConnState::Read(stream, Vec::new()), Step::Continue)
+            }
+            Err(HandshakeError::WouldBlock(mid)) => (ConnState::Handshake(mid), Step::Continue),
+            Err(HandshakeError::Failure(e)) => {
+              log_info!("tls: failed to accept: {:?}", e);
+              metrics().tls_handshake_failures.inc();
+              (ConnState::Closed, Step::Close)
+            }
+          }
+        }
+        Err(e) => {
+          log_info!("tls: failed to read ClientHello: {:?}", e);
+          (ConnState::Closed, Step::Close)
+        }
+      }
+    }
+    ConnState::Handshake(mid) => {
+      match mid.handshake() {
+        Ok(stream) => {
+          log_info!("tls: accepted");
+          conn.tls = Some((conn.accepted, Instant::now()));
+          (ConnState::Read(stream, Vec::new()), Step::Continue)
+        }
+        Err(HandshakeError::WouldBlock(mut mid)) => {
+          // NB: native_tls does not say which way the handshake is blocked;
+          // a blocked write means it also waits for the socket to drain.
+          let interest = if mid.get_mut().take_write_blocked() { Interest::ReadWrite } else { Interest::Read };
+          (ConnState::Handshake(mid), Step::Wait(interest))
+        }
+        Err(HandshakeError::Failure(e)) => {
+          log_info!("tls: failed to accept: {:?}", e);
+          metrics().tls_handshake_failures.inc();
+          (ConnState::Closed, Step::Close)
+        }
+      }
+    }
+    ConnState::Read(mut stream, mut buf) => {
+      if let Some(_) = find_head_end(&buf) {
+        let mut head = match gw.parse_head(&buf, conn.client_ip) {
+          Err(_) => return (ConnState::Closed, Step::Close),
+          Ok(head) => head
+        };
+        let total_len = head.total_len();
+        if buf.len() >= total_len || head.payload_len > crate::MAX_PAYLOAD {
+          conn.nreqs += 1;
+          conn.request_id = Some(head.request_id.clone());
+          if let Some(trace) = head.trace.as_mut() {
+            trace.tls = conn.tls.take();
+          }
+          crate::log::set_context(LogCtx{conn: Some(conn.seq_nr), req: Some(conn.nreqs), request_id: conn.request_id.clone()});
+          log_info!("request: {}", gw.config.redact.request(&buf[ .. total_len.min(buf.len())], head.route_host.as_ref().map(|s| s.as_str())));
+          gw.conn_request(conn.seq_nr, &head);
+          conn.deadline = Instant::now() + cfg.header_timeout;
+          let err_reply = head.err_reply();
+          let mut entry = AccessEntry::new(&head);
+          entry.set_tls(&conn.hello);
+          let group = match gw.route(&mut head, &buf) {
+            None => {
+              let out = err_reply.encode(&gw.errpages, 404);
+              return (ConnState::Write(stream, out, 0, false, Vec::new(), entry), Step::Continue);
+            }
+            Some(Routed::Redirect(status, location)) => {
+              let out = err_reply.encode_redirect(status, &location);
+              return (ConnState::Write(stream, out, 0, false, Vec::new(), entry), Step::Continue);
+            }
+            Some(Routed::Backend(group)) => group
+          };
+          if let Err(status) = gw.check_payload(&head) {
+            let out = err_reply.encode(&gw.errpages, status);
+            return (ConnState::Write(stream, out, 0, false, Vec::new(), entry), Step::Continue);
+          }
+          let keep_alive = head.keep_alive;
+          let rest = buf[total_len .. ].to_vec();
+          let (reply, front_rx) = BackendReply::new(Some((notify.clone(), token)));
+          let deadline = Instant::now() + cfg.backend_timeout;
+          if let Err(status) = gw.dispatch(head, &buf, &group, reply, deadline) {
+            let out = err_reply.encode(&gw.errpages, status);
+            return (ConnState::Write(stream, out, 0, false, Vec::new(), entry), Step::Continue);
+          }
+          conn.deadline = deadline;
+          return (ConnState::Backend(stream, front_rx, err_reply, keep_alive, rest, entry), Step::Continue);
+        }
+      } else if buf.len() >= MAX_HEAD {
+        log_info!("request header too large");
+        return (ConnState::Closed, Step::Close);
+      }
+      let mut rbuf = [0; 8192];
+      match stream.read(&mut rbuf) {
+        Ok(0) => (ConnState::Closed, Step::Close),
+        Ok(r_sz) => {
+          log_info!("read {} bytes", r_sz);
+          if buf.is_empty() {
+            conn.deadline = Instant::now() + cfg.header_timeout;
+          }
+          buf.extend_from_slice(&rbuf[ .. r_sz]);
+          (ConnState::Read(stream, buf), Step::Continue)
+        }
+        Err(ref e) if e.kind() == IoErrorKind::WouldBlock => {
+          (ConnState::Read(stream, buf), Step::Wait(Interest::Read))
+        }
+        Err(e) => {
+          log_info!("read error: {:?}", e);
+          (ConnState::Closed, Step::Close)
+        }
+      }
+    }
+    ConnState::Backend(stream, front_rx, err_reply, keep_alive, rest, mut entry) => {
+      let outcome = match front_rx.try_recv() {
+        Err(TryRecvError::Empty) => {
+          return (ConnState::Backend(stream, front_rx, err_reply, keep_alive, rest, entry), Step::Wait(Interest::None));
+        }
+        Err(TryRecvError::Disconnected) => {
+          log_info!("backend: recv error");
+          BackendOutcome::failed(502)
+        }
+        Ok(outcome) => outcome
+      };
+      let keep_alive = keep_alive && outcome.res.is_ok();
+      let out = gw.complete(&err_reply, outcome, keep_alive, &mut entry);
+      conn.deadline = Instant::now() + cfg.header_timeout;
+      (ConnState::Write(stream, out, 0, keep_alive, rest, entry), Step::Continue)
+    }
+    ConnState::Write(mut stream, out, mut pos, keep_alive, rest, mut entry) => {
+      entry.begin_write();
+      while pos < out.len() {
+        match stream.write(&out[pos .. ]) {
+          Ok(0) => {
+            gw.finish_request(entry, &out, pos);
+            return (ConnState::Closed, Step::Close);
+          }
+          Ok(w_sz) => {
+            pos += w_sz;
+          }
+          Err(ref e) if e.kind() == IoErrorKind::WouldBlock => {
+            return (ConnState::Write(stream, out, pos, keep_alive, rest, entry), Step::Wait(Interest::Write));
+          }
+          Err(e) => {
+            log_info!("write error: {:?}", e);
+            gw.finish_request(entry, &out, pos);
+            return (ConnState::Closed, Step::Close);
+          }
+        }
+      }
+      match stream.flush() {
+        Ok(_) => {}
+        Err(ref e) if e.kind() == IoErrorKind::WouldBlock => {
+          return (ConnState::Write(stream, out, pos, keep_alive, rest, entry), Step::Wait(Interest::Write));
+        }
+        Err(e) => {
+          log_info!("write error: {:?}", e);
+          gw.finish_request(entry, &out, pos);
+          return (ConnState::Closed, Step::Close);
+        }
+      }
+      log_info!("write done");
+      gw.finish_request(entry, &out, pos);
+      if !keep_alive {
+        return (ConnState::Closed, Step::Close);
+      }
+      conn.deadline = if rest.is_empty() {
+        Instant::now() + cfg.idle_timeout
+      } else {
+        Instant::now() + cfg.header_timeout
+      };
+      (ConnState::Read(stream, rest), Step::Continue)
+    }
+    ConnState::Closed => (ConnState::Closed, Step::Close)
+  }
+}
@@ -0,0 +1,363 @@
+#![forbid(unsafe_code)]
+
+use service_base::prelude::*;
+use smol_str::{SmolStr};
+
+use std::collections::{HashMap};
+use std::fs::{File, read_dir};
+use std::io::{Error as IoError, Read};
+use std::path::{Path, PathBuf};
+
+pub const DEFAULT_HOST: &'static str = "_default";
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum ErrorPageKind {
+  Html,
+  Json,
+}
+
+impl ErrorPageKind {
+  pub fn from_ext(ext: &str) -> Option<ErrorPageKind> {
+    match ext {
+      "html" | "htm" => Some(ErrorPageKind::Html),
+      "json" => Some(ErrorPageKind::Json),
+      _ => None
+    }
+  }
+
+  pub fn mime(self) -> http1::Mime {
+    match self {
+      ErrorPageKind::Html => http1::Mime::TextHtml,
+      ErrorPageKind::Json => http1::Mime::ApplicationJson,
+    }
+  }
+
+  pub fn escape(self, s: &str) -> String {
+    let mut buf = String::with_capacity(s.len());
+    match self {
+      ErrorPageKind::Html => {
+        for c in s.chars() {
+          match c {
+            '&' => buf.push_str("&amp;"),
+            '<' => buf.push_str("&lt;"),
+            '>' => buf.push_str("&gt;"),
+            '"' => buf.push_str("&quot;"),
+            '\'' => buf.push_str("&#39;"),
+            _ => buf.push(c)
+          }
+        }
+      }
+      ErrorPageKind::Json => {
+        for c in s.chars() {
+          match c {
+            '"' => buf.push_str("\\\""),
+            '\\' => buf.push_str("\\\\"),
+            '\n' => buf.push_str("\\n"),
+            '\r' => buf.push_str("\\r"),
+            '\t' => buf.push_str("\\t"),
+            c if (c as u32) < 0x20 => buf.push_str(&format!("\\u{:04x}", c as u32)),
+            _ => buf.push(c)
+          }
+        }
+      }
+    }
+    buf
+  }
+}
+
+// NB: `status` of `None` is the per-host catch-all template (`default.html`,
+// `default.json`).
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+struct ErrorPageKey {
+  host: SmolStr,
+  status: Option<u16>,
+  kind: ErrorPageKind,
+}
+
+pub struct ErrorPageVars<'a> {
+  pub status: u16,
+  pub request_id: &'a str,
+  pub host: Option<&'a str>,
+}
+
+#[derive(Clone, Default)]
+pub struct ErrorPages {
+  dir: Option<PathBuf>,
+  templates: HashMap<ErrorPageKey, String>,
+}
+
+impl ErrorPages {
+  pub fn empty() -> ErrorPages {
+    ErrorPages::default()
+  }
+
+  // The template directory is laid out as `<dir>/<host>/<status>.<ext>` and
+  // `<dir>/<host>/default.<ext>`, where `<ext>` is one of `html` or `json`,
+  // and `<host>` may be `_default` for templates shared by all hosts.
+  pub fn load<P: AsRef<Path>>(dir: P) -> Result<ErrorPages, IoError> {
+    let mut pages = ErrorPages{
+      dir: Some(dir.as_ref().to_owned()),
+      templates: HashMap::new(),
+    };
+    pages.reload()?;
+    Ok(pages)
+  }
+
+  pub fn reload(&mut self) -> Result<(), IoError> {
+    let dir = match self.dir.as_ref() {
+      None => return Ok(()),
+      Some(dir) => dir.clone()
+    };
+    let mut templates = HashMap::new();
+    for host_ent in read_dir(&dir)? {
+      let host_ent = host_ent?;
+      if !host_ent.file_type()?.is_dir() {
+        continue;
+      }
+      let host: SmolStr = match host_ent.file_name().to_str() {
+        None => continue,
+        Some(s) => s.to_ascii_lowercase().into()
+      };
+      for ent in read_dir(host_ent.path())? {
+        let ent = ent?;
+        if !ent.file_type()?.is_file() {
+          continue;
+        }
+        let path = ent.path();
+        let (stem, ext) = match (
+            path.file_stem().and_then(|s| s.to_str()),
+            path.extension().and_then(|s| s.to_str()))
+        {
+          (Some(stem), Some(ext)) => (stem, ext),
+          _ => continue
+        };
+        let kind = match ErrorPageKind::from_ext(ext) {
+          None => continue,
+          Some(kind) => kind
+        };
+        let status = if stem == "default" {
+          None
+        } else {
+          match stem.parse::<u16>() {
+            Ok(status) if status >= 400 && status < 600 => Some(status),
+            _ => {
+              log_warn!("ErrorPages::reload: skipping {:?}", path);
+              continue;
+            }
+          }
+        };
+        let mut text = String::new();
+        File::open(&path)?.read_to_string(&mut text)?;
+        templates.insert(ErrorPageKey{host: host.clone(), status, kind}, text);
+      }
+    }
+    log_info!("ErrorPages::reload: loaded {} templates from {:?}", templates.len(), dir);
+    self.templates = templates;
+    Ok(())
+  }
+
+  fn lookup(&self, host: Option<&str>, status: u16, kind: ErrorPageKind) -> Option<&str> {
+    let mut hosts = Vec::with_capacity(2);
+    if let Some(host) = host {
+      hosts.push(SmolStr::from(host.to_ascii_lowercase()));
+    }
+    hosts.push(DEFAULT_HOST.into());
+    for host in hosts.into_iter() {
+      for &status in [Some(status), None].iter() {
+        let key = ErrorPageKey{host: host.clone(), status, kind};
+        if let Some(text) = self.templates.get(&key) {
+          return Some(text);
+        }
+      }
+    }
+    None
+  }
+
+  pub fn render(&self, accept: Option<&[u8]>, vars: &ErrorPageVars) -> Option<(String, http1::Mime)> {
+    if self.templates.is_empty() {
+      return None;
+    }
+    for &kind in preferred_kinds(accept).iter() {
+      if let Some(text) = self.lookup(vars.host, vars.status, kind) {
+        return Some((substitute(text, kind, vars), kind.mime()));
+      }
+    }
+    None
+  }
+}
+
+fn substitute(text: &str, kind: ErrorPageKind, vars: &ErrorPageVars) -> String {
+  let mut buf = String::with_capacity(text.len());
+  let mut rest = text;
+  while let Some(start) = rest.find("{{") {
+    buf.push_str(&rest[ .. start]);
+    let tail = &rest[start + 2 .. ];
+    let end = match tail.find("}}") {
+      None => {
+        rest = &rest[start .. ];
+        break;
+      }
+      Some(end) => end
+    };
+    match tail[ .. end].trim() {
+      "status" => buf.push_str(&format!("{}", vars.status)),
+      "reason" => buf.push_str(&kind.escape(reason_phrase(vars.status))),
+      "request_id" => buf.push_str(&kind.escape(vars.request_id)),
+      "host" => buf.push_str(&kind.escape(vars.host.unwrap_or(""))),
+      _ => buf.push_str(&rest[start .. start + 2 + end + 2])
+    }
+    rest = &tail[end + 2 .. ];
+  }
+  buf.push_str(rest);
+  buf
+}
+
+// NB: a missing or unparseable `Accept` header prefers HTML. Otherwise each
+// kind takes the q-value of the most specific media range that matches it
+// (RFC 9110, section 12.5.1), and q=0 means "not acceptable": acceptable
+// kinds come first by q-value, with ties going to the one that appears
+// first, then kinds the header does not mention, then refused kinds.
+pub fn preferred_kinds(accept: Option<&[u8]>) -> [ErrorPageKind; 2] {
+  let accept = match accept.and_then(|s| std::str::from_utf8(s).ok()) {
+    None => return [ErrorPageKind::Html, ErrorPageKind::Json],
+    Some(s) => s
+  };
+  // Per kind: (specificity, q, position) of the best matching range.
+  let mut html: Option<(u8, f32, usize)> = None;
+  let mut json: Option<(u8, f32, usize)> = None;
+  for (pos, range) in accept.split(',').enumerate() {
+    let mut parts = range.split(';');
+    let media = parts.next().unwrap_or("").trim().to_ascii_lowercase();
+    let mut q = 1.0;
+    for param in parts {
+      let param = param.trim();
+      if param.starts_with("q=") {
+        q = param[2 .. ].trim().parse::<f32>().unwrap_or(0.0);
+      }
+    }
+    let (html_spec, json_spec) = match media.as_str() {
+      "text/html" | "application/xhtml+xml" => (3, 0),
+      "application/json" | "application/problem+json" => (0, 3),
+      "text/*" => (2, 0),
+      "application/*" => (0, 2),
+      "*/*" => (1, 1),
+      _ => (0, 0)
+    };
+    update_best(&mut html, html_spec, q, pos);
+    update_best(&mut json, json_spec, q, pos);
+  }
+  // NB: larger is preferred; positions are negated so that earlier wins.
+  let rank = |best: Option<(u8, f32, usize)>| -> (u8, f32, isize) {
+    match best {
+      None => (1, 0.0, 0),
+      Some((_, q, _)) if q <= 0.0 => (0, 0.0, 0),
+      Some((_, q, pos)) => (2, q, -(pos as isize))
+    }
+  };
+  let (hr, jr) = (rank(html), rank(json));
+  let json_first = jr.0 > hr.0 || (jr.0 == hr.0 && (jr.1 > hr.1 || (jr.1 == hr.1 && jr.2 > hr.2)));
+  if json_first {
+    [ErrorPageKind::Json, ErrorPageKind::Html]
+  } else {
+    [ErrorPageKind::Html, ErrorPageKind::Json]
+  }
+}
+
+fn update_best(best: &mut Option<(u8, f32, usize)>, spec: u8, q: f32, pos: usize) {
+  if spec == 0 {
+    return;
+  }
+  let better = match *best {
+    None => true,
+    Some((spec0, q0, _)) => spec > spec0 || (spec == spec0 && q > q0)
+  };
+  if better {
+    *best = Some((spec, q, pos));
+  }
+}
+
+pub fn http_status(code: u16) -> HttpStatus {
+  match code {
+    301 => HttpStatus::MovedPermanently,
+    302 => HttpStatus::Found,
+    307 => HttpStatus::TemporaryRedirect,
+    308 => HttpStatus::PermanentRedirect,
+    400 => HttpStatus::BadRequest,
+    404 => HttpStatus::NotFound,
+    502 => HttpStatus::BadGateway,
+    503 => HttpStatus::ServiceUnavailable,
+    504 => HttpStatus::GatewayTimeout,
+    _ => HttpStatus::InternalServerError
+  }
+}
+
+pub fn reason_phrase(status: u16) -> &'static str {
+  match status {
+    400 => "Bad Request",
+    401 => "Unauthorized",
+    403 => "Forbidden",
+    404 => "Not Found",
+    405 => "Method Not Allowed",
+    408 => "Request Timeout",
+    409 => "Conflict",
+    413 => "Payload Too Large",
+    429 => "Too Many Requests",
+    500 => "Internal Server Error",
+    502 => "Bad Gateway",
+    503 => "Service Unavailable",
+    504 => "Gateway Timeout",
+    _ => "Error"
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_preferred_kinds() {
+    use super::ErrorPageKind::{Html, Json};
+    let cases: &[(Option<&str>, [ErrorPageKind; 2])] = &[
+      (None, [Html, Json]),
+      (Some(""), [Html, Json]),
+      (Some("text/html"), [Html, Json]),
+      (Some("application/json"), [Json, Html]),
+      (Some("*/*"), [Html, Json]),
+      (Some("application/json, text/html"), [Json, Html]),
+      (Some("text/html, application/json"), [Html, Json]),
+      (Some("text/html;q=0.5, application/json"), [Json, Html]),
+      (Some("application/problem+json;q=0.9, */*;q=0.1"), [Json, Html]),
+      (Some("application/*"), [Json, Html]),
+      // The most specific range sets q, so `*/*` does not override it.
+      (Some("application/json;q=0, */*"), [Html, Json]),
+      (Some("*/*, application/json;q=0"), [Html, Json]),
+      (Some("text/html;q=0, */*"), [Json, Html]),
+      // An explicitly refused kind comes after one not mentioned at all.
+      (Some("text/html;q=0"), [Json, Html]),
+      (Some("application/json;q=0"), [Html, Json]),
+      (Some("image/png"), [Html, Json]),
+    ];
+    for &(accept, expected) in cases.iter() {
+      assert_eq!(preferred_kinds(accept.map(|s| s.as_bytes())), expected, "accept: {:?}", accept);
+    }
+  }
+
+  #[test]
+  fn test_substitute() {
+    let vars = ErrorPageVars{status: 503, request_id: "<id>", host: Some("a\"b")};
+    let cases: &[(&str, ErrorPageKind, &str)] = &[
+      ("plain", ErrorPageKind::Html, "plain"),
+      ("{{status}} {{ reason }}", ErrorPageKind::Html, "503 Service Unavailable"),
+      ("{{request_id}}", ErrorPageKind::Html, "&lt;id&gt;"),
+      ("{{request_id}}", ErrorPageKind::Json, "<id>"),
+      ("{{host}}", ErrorPageKind::Html, "a&quot;b"),
+      ("{{host}}", ErrorPageKind::Json, "a\\\"b"),
+      ("{{unknown}} x", ErrorPageKind::Html, "{{unknown}} x"),
+      ("a {{status", ErrorPageKind::Html, "a {{status"),
+      ("{{status}}{{status}}", ErrorPageKind::Json, "503503"),
+    ];
+    for &(text, kind, expected) in cases.iter() {
+      assert_eq!(substitute(text, kind, &vars), expected, "text: {:?}", text);
+    }
+  }
+}
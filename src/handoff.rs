@@ -0,0 +1,136 @@
+#![forbid(unsafe_code)]
+
+use std::fs::{Permissions, remove_file, set_permissions};
+use std::io::{Error as IoError, ErrorKind as IoErrorKind, Read, Write};
+use std::net::{TcpListener};
+use std::os::unix::fs::{PermissionsExt};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{Builder as ThreadBuilder, sleep};
+use std::time::{Duration as StdDuration};
+
+const REQUEST: &'static [u8] = b"HANDOFF\n";
+const REPLY: &'static [u8] = b"OK\n";
+
+static HANDED_OFF: AtomicBool = AtomicBool::new(false);
+static STOPPED: AtomicBool = AtomicBool::new(false);
+
+// NB: true once the listening sockets were passed to a new process; the
+// current process should stop accepting and drain.
+pub fn handed_off() -> bool {
+  HANDED_OFF.load(Ordering::Acquire)
+}
+
+// NB: stops offering the listening sockets, e.g. once the current process
+// has stopped serving on its own.
+pub fn stop() {
+  STOPPED.store(true, Ordering::Release);
+}
+
+// Asks a running process for its listening sockets. Returns `Ok(None)` when
+// nothing is listening on `path`.
+pub fn take_listeners<P: AsRef<Path>>(path: P) -> Result<Option<Vec<TcpListener>>, IoError> {
+  let path = path.as_ref();
+  let mut stream = match UnixStream::connect(path) {
+    Err(ref e) if e.kind() == IoErrorKind::NotFound ||
+                  e.kind() == IoErrorKind::ConnectionRefused => {
+      return Ok(None);
+    }
+    Err(e) => return Err(e),
+    Ok(stream) => stream
+  };
+  stream.set_read_timeout(Some(StdDuration::from_secs(5)))?;
+  stream.write_all(REQUEST)?;
+  let mut buf = [0; 16];
+  let (n, listeners) = crate::sys::recv_listeners(stream.as_raw_fd(), &mut buf)?;
+  if &buf[ .. n] != REPLY || listeners.is_empty() {
+    return Err(IoError::new(IoErrorKind::InvalidData, "handoff: unexpected reply"));
+  }
+  log_info!("handoff: took {} listening sockets from {:?}", listeners.len(), path);
+  Ok(Some(listeners))
+}
+
+// Offers the listening sockets to the next process on `path`. The socket
+// must be bound before chroot; a stale path left by a previous process is
+// replaced. Only the owner may connect, and only a peer running as the
+// same user or as root is handed the sockets.
+pub fn serve<P: AsRef<Path>>(path: P, listeners: Vec<TcpListener>) -> Result<(), IoError> {
+  let path: PathBuf = path.as_ref().to_owned();
+  match remove_file(&path) {
+    Err(ref e) if e.kind() == IoErrorKind::NotFound => {}
+    Err(e) => return Err(e),
+    Ok(_) => {}
+  }
+  let server = UnixListener::bind(&path)?;
+  set_permissions(&path, Permissions::from_mode(0o600))?;
+  server.set_nonblocking(true)?;
+  // NB: the owner of the socket; the next process may also still be root
+  // when it asks, before it drops privileges.
+  let (_, owner, _, _) = crate::sys::current_ids();
+  log_info!("handoff: listening on {:?}", path);
+  ThreadBuilder::new()
+    .name("handoff".to_owned())
+    .spawn(move || serve_loop(server, owner, listeners))
+    .unwrap();
+  Ok(())
+}
+
+fn serve_loop(server: UnixListener, owner: u32, listeners: Vec<TcpListener>) {
+  let fds: Vec<RawFd> = listeners.iter().map(|l| l.as_raw_fd()).collect();
+  loop {
+    if STOPPED.load(Ordering::Acquire) {
+      return;
+    }
+    let mut stream = match server.accept() {
+      Err(ref e) if e.kind() == IoErrorKind::WouldBlock => {
+        sleep(StdDuration::from_millis(100));
+        continue;
+      }
+      Err(e) => {
+        log_warn!("handoff: accept: {:?}", e);
+        sleep(StdDuration::from_millis(100));
+        continue;
+      }
+      Ok((stream, _)) => stream
+    };
+    match crate::sys::peer_uid(stream.as_raw_fd()) {
+      Ok(uid) if uid == owner || uid == 0 => {}
+      Ok(uid) => {
+        log_warn!("handoff: rejected peer with uid = {}", uid);
+        continue;
+      }
+      Err(e) => {
+        log_warn!("handoff: failed to get peer credentials: {:?}", e);
+        continue;
+      }
+    }
+    if stream.set_nonblocking(false).is_err() ||
+       stream.set_read_timeout(Some(StdDuration::from_secs(5))).is_err()
+    {
+      continue;
+    }
+    let mut buf = [0; 8];
+    match stream.read_exact(&mut buf) {
+      Ok(_) if &buf[ .. ] == REQUEST => {}
+      _ => {
+        log_warn!("handoff: invalid request");
+        continue;
+      }
+    }
+    if STOPPED.load(Ordering::Acquire) {
+      return;
+    }
+    match crate::sys::send_fds(stream.as_raw_fd(), &fds, REPLY) {
+      Err(e) => {
+        log_warn!("handoff: failed to send sockets: {:?}", e);
+        continue;
+      }
+      Ok(_) => {}
+    }
+    log_info!("handoff: passed {} listening sockets to new process", fds.len());
+    HANDED_OFF.store(true, Ordering::Release);
+    return;
+  }
+}
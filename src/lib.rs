@@ -1,6 +1,9 @@
-#![forbid(unsafe_code)]
+// NB: `sys` is the only module allowed unsafe code; every other module
+// forbids it.
+#![deny(unsafe_code)]
 
 extern crate http1;
+extern crate libc;
 extern crate native_tls;
 extern crate service_base;
 extern crate signal_hook;
@@ -9,12 +12,28 @@ extern crate time;
 extern crate uacme;
 extern crate unix2;
 
+use crate::access::{AccessEntry, AccessLog, AccessLogConfig};
+use crate::admin::{AdminAddr, AdminListener};
+use crate::backend::{BackendConfig, BackendGroup, BreakerConfig, GroupConfig, HealthCheckConfig, PickKey, RetryPolicy, Strategy};
+use crate::daemon::{HardeningConfig, Sandbox, SandboxConfig};
+use crate::engine::{Engine, EngineKind, ReactorConfig};
+use crate::errpage::{ErrorPages, ErrorPageVars, http_status};
+use crate::log::{Level, LogConfig, LogCtx, LogFormat};
+use crate::metrics::{metrics};
+use crate::net::{Interest, Notify, Poll, Token};
+use crate::pool::{Overload, PoolConfig, WorkerPool};
+use crate::redact::{RedactConfig};
+use crate::regex::{Regex};
+use crate::rewrite::{REDIRECT_STATUSES, Rewrite, RewriteOutcome};
+use crate::routing::{HostTable, PathMatch, RouteRule, RouteTable, has_dot_segment, normalize_host};
+use crate::tls::{HelloStream};
+use crate::trace::{RequestTrace, TraceConfig, TraceCtx, TRACEPARENT, TRACESTATE};
+
 use native_tls::{TlsAcceptor, TlsStream, MidHandshakeTlsStream};
 use service_base::prelude::*;
-use service_base::chan::*;
 use service_base::route::*;
 use smol_str::{SmolStr};
-use time::{Duration, Timespec, get_time_coarse, get_time_usec};
+use time::{Timespec, get_time_coarse, get_time_usec};
 
 use std::cmp::{max};
 use std::collections::{BTreeMap, BTreeSet, HashMap};
@@ -23,69 +42,530 @@ use std::env;
 use std::fs::*;
 use std::io::{Error as IoError, Cursor, BufWriter, Read, Write};
 use std::mem::{replace};
-use std::net::{ToSocketAddrs, TcpListener, TcpStream};
+use std::net::{IpAddr, SocketAddr, TcpListener, TcpStream};
 use std::os::unix::io::{AsRawFd, RawFd};
-use std::path::{PathBuf};
-use std::sync::{Arc, Mutex};
-use std::sync::mpsc::{Sender, SyncSender, Receiver, channel, sync_channel};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{SyncSender, Receiver, RecvTimeoutError, sync_channel};
 use std::thread::{sleep, spawn};
-use std::time::{Duration as StdDuration};
+use std::time::{Duration as StdDuration, Instant};
+
+#[macro_use] pub mod log;
 
+pub mod access;
 pub mod acme;
+pub mod admin;
+pub mod backend;
 pub mod build;
 pub mod daemon;
+pub mod engine;
+pub mod errpage;
+pub mod handoff;
+pub mod metrics;
 pub mod net;
+pub mod pool;
+pub mod redact;
+pub mod regex;
+pub mod request_id;
+pub mod rewrite;
+pub mod routing;
 pub mod signal;
+pub mod sys;
+pub mod systemd;
+pub mod tls;
+pub mod trace;
 
 pub type Config = ProxyGatewayConfig;
 
 #[derive(Clone, Default)]
 pub struct ProxyGatewayConfig {
-  allports: BTreeSet<u16>,
-  invhosts: BTreeMap<u16, BTreeSet<SmolStr>>,
-  hostport: BTreeMap<SmolStr, u16>,
+  groups: BTreeMap<SmolStr, GroupConfig>,
+  invhosts: BTreeMap<SmolStr, BTreeSet<SmolStr>>,
+  hostgroup: BTreeMap<SmolStr, SmolStr>,
+  hostpatterns: HostTable,
   primhost: Option<SmolStr>,
-  def_port: Option<u16>,
+  def_group: Option<SmolStr>,
+  errpage_dir: Option<PathBuf>,
+  pool: PoolConfig,
+  engine: EngineKind,
+  reactor: ReactorConfig,
+  backend: BackendConfig,
+  shutdown_grace: Option<StdDuration>,
+  handoff_path: Option<PathBuf>,
+  sandbox: SandboxConfig,
+  hardening: Option<HardeningConfig>,
+  log: LogConfig,
+  access_log: Option<AccessLogConfig>,
+  redact: RedactConfig,
+  admin: Option<AdminAddr>,
+  trust_request_id: bool,
+  trace: Option<TraceConfig>,
+  routes: RouteTable,
 }
 
 impl ProxyGatewayConfig {
-  pub fn map_host_to_port<S: AsRef<str>>(&mut self, host: S, port: u16) {
-    let host = host.as_ref();
-    if port & 1 != 0 {
-      println!("ERROR:  ProxyGatewayConfig::map_host_to_port: port = {} must be even (host = {:?})", port, host);
+  // The group for a normalized host without a matching route rule: an exact
+  // mapping, then the longest wildcard, then the first matching regex, then
+  // the default group.
+  pub fn host_group(&self, host: Option<&str>) -> Option<&SmolStr> {
+    host.and_then(|host_s| self.hostgroup.get(host_s))
+      .or_else(|| host.and_then(|host_s| self.hostpatterns.lookup(host_s)))
+      .or_else(|| self.def_group.as_ref())
+  }
+
+  pub fn add_backend_group<S: AsRef<str>, A: AsRef<str>>(&mut self, group: S, members: &[A], strategy: Strategy) {
+    let group = group.as_ref();
+    let members: Vec<SmolStr> = members.iter().map(|a| a.as_ref().into()).collect();
+    for addr in members.iter() {
+      if addr.rsplitn(2, ':').next().and_then(|port| port.parse::<u16>().ok()).is_none() {
+        log_error!("ProxyGatewayConfig::add_backend_group: member = {:?} must be host:port (group = {:?})", addr, group);
+        panic!();
+      }
+    }
+    log_info!("ProxyGatewayConfig::add_backend_group: group = {:?} members = {:?} strategy = {:?}", group, members, strategy);
+    self.groups.insert(group.into(), GroupConfig{members, strategy, health: None, breaker: None});
+  }
+
+  pub fn set_group_circuit_breaker<G: AsRef<str>>(&mut self, group: G, breaker: BreakerConfig) {
+    let group = group.as_ref();
+    match self.groups.get_mut(group) {
+      None => {
+        log_error!("ProxyGatewayConfig::set_group_circuit_breaker: unknown group = {:?}", group);
+        panic!();
+      }
+      Some(group_cfg) => {
+        if breaker.consecutive_failures == 0 || breaker.window == 0 {
+          log_error!("ProxyGatewayConfig::set_group_circuit_breaker: thresholds must be nonzero (group = {:?})", group);
+          panic!();
+        }
+        group_cfg.breaker = Some(breaker);
+      }
+    }
+  }
+
+  pub fn set_group_health_check<G: AsRef<str>>(&mut self, group: G, health: HealthCheckConfig) {
+    let group = group.as_ref();
+    match self.groups.get_mut(group) {
+      None => {
+        log_error!("ProxyGatewayConfig::set_group_health_check: unknown group = {:?}", group);
+        panic!();
+      }
+      Some(group_cfg) => {
+        if health.rise == 0 || health.fall == 0 {
+          log_error!("ProxyGatewayConfig::set_group_health_check: rise and fall must be nonzero (group = {:?})", group);
+          panic!();
+        }
+        group_cfg.health = Some(health);
+      }
+    }
+  }
+
+  pub fn map_host_to_group<S: AsRef<str>, G: AsRef<str>>(&mut self, host: S, group: G) {
+    let host = normalize_host(host.as_ref());
+    let host = host.as_str();
+    let group = group.as_ref();
+    if !self.groups.contains_key(group) {
+      log_error!("ProxyGatewayConfig::map_host_to_group: unknown group = {:?} (host = {:?})", group, host);
       panic!();
     }
-    self.allports.insert(port);
-    match self.invhosts.get_mut(&port) {
+    match self.invhosts.get_mut(group) {
       None => {
         let mut hs = BTreeSet::new();
         hs.insert(host.into());
-        self.invhosts.insert(port, hs);
+        self.invhosts.insert(group.into(), hs);
       }
       Some(hs) => {
         hs.insert(host.into());
       }
     }
     if self.primhost.is_none() {
-      println!("INFO:   ProxyGatewayConfig::map_host_to_port: new primary host = {:?}", host);
+      log_info!("ProxyGatewayConfig::map_host_to_group: new primary host = {:?}", host);
       self.primhost = Some(host.into());
     }
-    self.hostport.insert(host.into(), port);
+    self.hostgroup.insert(host.into(), group.into());
+  }
+
+  // The single-member group for a backend on `port`; see `add_backend_group`
+  // for more members.
+  fn port_group(&mut self, port: u16) -> SmolStr {
+    let group: SmolStr = format!("127.0.0.1:{}", port).into();
+    if !self.groups.contains_key(&group) {
+      self.add_backend_group(&group, &[&group], Strategy::RoundRobin);
+    }
+    group
+  }
+
+  pub fn map_host_to_port<S: AsRef<str>>(&mut self, host: S, port: u16) {
+    let group = self.port_group(port);
+    self.map_host_to_group(host, group);
+  }
+
+  // Maps every subdomain of `*.example.com` that has no exact mapping; the
+  // longest matching wildcard wins.
+  // NB: wildcard hosts are not added to the ACME certificate.
+  pub fn map_wildcard_host_to_group<S: AsRef<str>, G: AsRef<str>>(&mut self, pattern: S, group: G) {
+    let pattern = pattern.as_ref();
+    let group = group.as_ref();
+    if !self.groups.contains_key(group) {
+      log_error!("ProxyGatewayConfig::map_wildcard_host_to_group: unknown group = {:?} (pattern = {:?})", group, pattern);
+      panic!();
+    }
+    let suffix = match pattern.strip_prefix("*.") {
+      Some(rest) if !rest.is_empty() && !rest.contains('*') => normalize_host(&pattern[1 .. ]),
+      _ => {
+        log_error!("ProxyGatewayConfig::map_wildcard_host_to_group: pattern = {:?} must look like *.example.com", pattern);
+        panic!();
+      }
+    };
+    log_info!("ProxyGatewayConfig::map_wildcard_host_to_group: pattern = {:?} group = {:?}", pattern, group);
+    self.hostpatterns.insert_wildcard(suffix, group.into());
+  }
+
+  pub fn map_wildcard_host_to_port<S: AsRef<str>>(&mut self, pattern: S, port: u16) {
+    let group = self.port_group(port);
+    self.map_wildcard_host_to_group(pattern, group);
+  }
+
+  // Maps hosts matching `regex` that have no exact or wildcard mapping;
+  // regexes are tried in the order added. Matching is case-insensitive,
+  // against the whole host without port or trailing dot: `api\.example\.com`
+  // does not match `api.example.com.evil.net`.
+  pub fn map_host_regex_to_group<S: AsRef<str>, G: AsRef<str>>(&mut self, regex: S, group: G) {
+    let regex = regex.as_ref();
+    let group = group.as_ref();
+    if !self.groups.contains_key(group) {
+      log_error!("ProxyGatewayConfig::map_host_regex_to_group: unknown group = {:?} (regex = {:?})", group, regex);
+      panic!();
+    }
+    let re = match Regex::new(format!("(?i)^(?:{})$", regex.strip_prefix("(?i)").unwrap_or(regex))) {
+      Err(e) => {
+        log_error!("ProxyGatewayConfig::map_host_regex_to_group: invalid regex = {:?}: {}", regex, e);
+        panic!();
+      }
+      Ok(re) => re
+    };
+    log_info!("ProxyGatewayConfig::map_host_regex_to_group: regex = {:?} group = {:?}", regex, group);
+    self.hostpatterns.insert_regex(re, group.into());
+  }
+
+  pub fn map_host_regex_to_port<S: AsRef<str>>(&mut self, regex: S, port: u16) {
+    let group = self.port_group(port);
+    self.map_host_regex_to_group(regex, group);
   }
 
   pub fn set_primary_host<S: AsRef<str>>(&mut self, host: S) {
     let host = host.as_ref();
-    println!("INFO:   ProxyGatewayConfig::set_primary_host: host = {:?}", host);
+    log_info!("ProxyGatewayConfig::set_primary_host: host = {:?}", host);
     self.primhost = Some(host.into());
   }
 
+  pub fn set_default_group<G: AsRef<str>>(&mut self, group: G) {
+    let group = group.as_ref();
+    if !self.groups.contains_key(group) {
+      log_error!("ProxyGatewayConfig::set_default_group: unknown group = {:?}", group);
+      panic!();
+    }
+    self.def_group = Some(group.into());
+  }
+
+  // Routes matching requests to `rule.group`, ahead of the host mapping;
+  // see `RouteTable` for which rule wins when several match.
+  pub fn add_route(&mut self, mut rule: RouteRule) {
+    if !self.groups.contains_key(&rule.group) {
+      log_error!("ProxyGatewayConfig::add_route: unknown group = {:?}", rule.group);
+      panic!();
+    }
+    match &rule.path {
+      &PathMatch::Prefix(ref p) | &PathMatch::Exact(ref p) if !p.starts_with('/') => {
+        log_error!("ProxyGatewayConfig::add_route: path = {:?} must start with `/`", p);
+        panic!();
+      }
+      _ => {}
+    }
+    if rule.methods.iter().any(|m| m.is_empty()) || rule.headers.iter().any(|&(ref name, _)| name.is_empty()) {
+      log_error!("ProxyGatewayConfig::add_route: empty method or header name (group = {:?})", rule.group);
+      panic!();
+    }
+    for rewrite in rule.rewrites.iter() {
+      let res = match rewrite {
+        &Rewrite::StripPrefix(ref p) | &Rewrite::AddPrefix(ref p) if !p.starts_with('/') => {
+          Err(format!("prefix = {:?} must start with `/`", p).into())
+        }
+        &Rewrite::StripPrefix(_) | &Rewrite::AddPrefix(_) => Ok(()),
+        &Rewrite::Regex(ref re, ref template) => crate::rewrite::check_template(template, re.groups()),
+        &Rewrite::Redirect(status, _, _) if !REDIRECT_STATUSES.contains(&status) => {
+          Err(format!("redirect status = {} must be one of {:?}", status, REDIRECT_STATUSES).into())
+        }
+        &Rewrite::Redirect(_, ref re, ref template) => crate::rewrite::check_template(template, re.groups()),
+      };
+      let res = match res {
+        Ok(_) if rule.host.is_none() && rewrite.uses_host() => {
+          Err("`${host}` needs a route for a host".into())
+        }
+        res => res
+      };
+      if let Err(e) = res {
+        log_error!("ProxyGatewayConfig::add_route: invalid rewrite = {:?}: {} (group = {:?})", rewrite, e, rule.group);
+        panic!();
+      }
+    }
+    rule.host = rule.host.map(|host| normalize_host(&host));
+    log_info!("ProxyGatewayConfig::add_route: host = {:?} path = {:?} methods = {:?} group = {:?}",
+        rule.host, rule.path, rule.methods, rule.group);
+    self.routes.insert(rule);
+  }
+
   pub fn set_default_port(&mut self, port: u16) {
-    if port & 1 != 0 {
-      println!("ERROR:  ProxyGatewayConfig::set_default_port: port = {} must be even", port);
+    let group = self.port_group(port);
+    self.set_default_group(group);
+  }
+
+  pub fn set_error_page_dir<P: AsRef<Path>>(&mut self, dir: P) {
+    let dir = dir.as_ref();
+    log_info!("ProxyGatewayConfig::set_error_page_dir: dir = {:?}", dir);
+    self.errpage_dir = Some(dir.to_owned());
+  }
+
+  pub fn set_max_workers(&mut self, workers: usize) {
+    if workers == 0 {
+      log_error!("ProxyGatewayConfig::set_max_workers: workers must be nonzero");
+      panic!();
+    }
+    self.pool.workers = workers;
+  }
+
+  pub fn set_accept_queue_len(&mut self, queue_cap: usize) {
+    self.pool.queue_cap = queue_cap;
+  }
+
+  pub fn set_overload_policy(&mut self, overload: Overload) {
+    self.pool.overload = overload;
+  }
+
+  pub fn set_conn_timeout(&mut self, timeout: StdDuration) {
+    self.pool.conn_timeout = timeout;
+  }
+
+  pub fn set_engine(&mut self, engine: EngineKind) {
+    log_info!("ProxyGatewayConfig::set_engine: engine = {:?}", engine);
+    self.engine = engine;
+  }
+
+  pub fn set_reactor_threads(&mut self, threads: usize) {
+    if threads == 0 {
+      log_error!("ProxyGatewayConfig::set_reactor_threads: threads must be nonzero");
+      panic!();
+    }
+    self.reactor.threads = threads;
+  }
+
+  pub fn set_max_conns(&mut self, max_conns: usize) {
+    self.reactor.max_conns = max_conns;
+  }
+
+  pub fn set_idle_timeout(&mut self, timeout: StdDuration) {
+    self.reactor.idle_timeout = timeout;
+  }
+
+  pub fn set_sandbox_user<U: AsRef<str>>(&mut self, user: U, group: Option<&str>) {
+    let user = user.as_ref();
+    if user.is_empty() || group.map_or(false, |g| g.is_empty()) {
+      log_error!("ProxyGatewayConfig::set_sandbox_user: empty user or group");
+      panic!();
+    }
+    self.sandbox.user = user.into();
+    self.sandbox.group = group.map(|g| g.into());
+  }
+
+  pub fn set_chroot_dir<P: AsRef<Path>>(&mut self, dir: P) {
+    let dir = dir.as_ref();
+    if !dir.is_absolute() {
+      log_error!("ProxyGatewayConfig::set_chroot_dir: path must be absolute: {:?}", dir);
+      panic!();
+    }
+    self.sandbox.chroot_dir = dir.to_owned();
+  }
+
+  pub fn add_bind_mount<P: AsRef<Path>>(&mut self, path: P) {
+    let path = path.as_ref();
+    if !path.is_absolute() {
+      log_error!("ProxyGatewayConfig::add_bind_mount: path must be absolute: {:?}", path);
+      panic!();
+    }
+    self.sandbox.bind_mounts.push(path.to_owned());
+  }
+
+  pub fn set_tls_dir<P: AsRef<Path>>(&mut self, dir: P) {
+    let dir = dir.as_ref();
+    if !dir.is_absolute() {
+      log_error!("ProxyGatewayConfig::set_tls_dir: path must be absolute: {:?}", dir);
+      panic!();
+    }
+    self.sandbox.tls_dir = dir.to_owned();
+  }
+
+  pub fn set_log_level(&mut self, level: Level) {
+    self.log.level = level;
+  }
+
+  pub fn set_module_log_level<M: AsRef<str>>(&mut self, module: M, level: Level) {
+    let module = module.as_ref();
+    let module = module.strip_prefix("proxy_gateway::").unwrap_or(module);
+    if module.is_empty() {
+      log_error!("ProxyGatewayConfig::set_module_log_level: empty module");
+      panic!();
+    }
+    self.log.modules.insert(module.into(), level);
+  }
+
+  pub fn set_log_format(&mut self, format: LogFormat) {
+    self.log.format = format;
+  }
+
+  // Serves the admin API, including `/metrics` in the Prometheus text
+  // format, on a loopback TCP address.
+  pub fn set_admin_listener<A: AsRef<str>>(&mut self, addr: A) {
+    let addr = addr.as_ref();
+    let addr: SocketAddr = match addr.parse() {
+      Err(_) => {
+        log_error!("ProxyGatewayConfig::set_admin_listener: invalid address = {:?}", addr);
+        panic!();
+      }
+      Ok(addr) => addr
+    };
+    if !addr.ip().is_loopback() {
+      log_error!("ProxyGatewayConfig::set_admin_listener: address = {:?} must be loopback", addr);
+      panic!();
+    }
+    self.admin = Some(AdminAddr::Tcp(addr));
+  }
+
+  // Serves the admin API on a Unix socket, accessible only to its owner.
+  pub fn set_admin_socket<P: AsRef<Path>>(&mut self, path: P) {
+    let path = path.as_ref();
+    if !path.is_absolute() {
+      log_error!("ProxyGatewayConfig::set_admin_socket: path = {:?} must be absolute", path);
+      panic!();
+    }
+    self.admin = Some(AdminAddr::Unix(path.to_owned()));
+  }
+
+  // Whether to keep a valid `X-Request-Id` sent by the client, instead of
+  // always generating a fresh id; enable only behind a trusted proxy.
+  pub fn set_trust_request_id(&mut self, trust: bool) {
+    self.trust_request_id = trust;
+  }
+
+  // Exports request spans to an OTLP/HTTP collector, and propagates W3C
+  // `traceparent` and `tracestate` headers to backends.
+  pub fn set_tracing(&mut self, trace: TraceConfig) {
+    if !trace.endpoint.starts_with("http://") {
+      log_error!("ProxyGatewayConfig::set_tracing: endpoint = {:?} must be http://", trace.endpoint);
+      panic!();
+    }
+    if !(trace.sample_ratio >= 0.0 && trace.sample_ratio <= 1.0) {
+      log_error!("ProxyGatewayConfig::set_tracing: sample_ratio = {} must be in [0, 1]", trace.sample_ratio);
+      panic!();
+    }
+    if trace.batch_size == 0 || trace.queue_cap == 0 {
+      log_error!("ProxyGatewayConfig::set_tracing: batch_size and queue_cap must be nonzero");
       panic!();
     }
-    self.allports.insert(port);
-    self.def_port = Some(port);
+    self.trace = Some(trace);
+  }
+
+  pub fn set_access_log(&mut self, access_log: AccessLogConfig) {
+    if access_log.path.as_os_str().is_empty() {
+      log_error!("ProxyGatewayConfig::set_access_log: empty path");
+      panic!();
+    }
+    if access_log.max_bytes == Some(0) {
+      log_error!("ProxyGatewayConfig::set_access_log: max_bytes must be nonzero");
+      panic!();
+    }
+    self.access_log = Some(access_log);
+  }
+
+  // Logs only the values of these headers; all others are masked.
+  pub fn set_log_header_allowlist<H: AsRef<str>>(&mut self, headers: &[H]) {
+    self.redact.header_allow = Some(headers.iter().map(|h| h.as_ref().to_ascii_lowercase().into()).collect());
+  }
+
+  pub fn add_log_header_denylist<H: AsRef<str>>(&mut self, header: H) {
+    let header = header.as_ref();
+    if header.is_empty() {
+      log_error!("ProxyGatewayConfig::add_log_header_denylist: empty header name");
+      panic!();
+    }
+    self.redact.header_deny.insert(header.to_ascii_lowercase().into());
+  }
+
+  pub fn add_redacted_query_param<P: AsRef<str>>(&mut self, param: P) {
+    let param = param.as_ref();
+    if param.is_empty() {
+      log_error!("ProxyGatewayConfig::add_redacted_query_param: empty parameter name");
+      panic!();
+    }
+    self.redact.query_params.insert(param.to_ascii_lowercase().into());
+  }
+
+  // NB: for debugging only; request bodies may carry credentials.
+  pub fn set_log_request_body<H: AsRef<str>>(&mut self, host: H, enabled: bool) {
+    let host = normalize_host(host.as_ref());
+    let host = host.as_str();
+    if !self.hostgroup.contains_key(host) {
+      log_error!("ProxyGatewayConfig::set_log_request_body: unknown host = {:?}", host);
+      panic!();
+    }
+    if enabled {
+      log_warn!("ProxyGatewayConfig::set_log_request_body: logging request bodies for host = {:?}", host);
+      self.redact.body_hosts.insert(host.into());
+    } else {
+      self.redact.body_hosts.remove(host);
+    }
+  }
+
+  pub fn set_hardening(&mut self, hardening: HardeningConfig) {
+    self.hardening = Some(hardening);
+  }
+
+  pub fn set_handoff_path<P: AsRef<Path>>(&mut self, path: P) {
+    self.handoff_path = Some(path.as_ref().to_owned());
+  }
+
+  pub fn set_shutdown_grace(&mut self, grace: StdDuration) {
+    self.shutdown_grace = Some(grace);
+  }
+
+  pub fn set_backend_conns(&mut self, min_conns: usize, max_conns: usize) {
+    if max_conns == 0 || min_conns > max_conns {
+      log_error!("ProxyGatewayConfig::set_backend_conns: invalid range {}..{}", min_conns, max_conns);
+      panic!();
+    }
+    self.backend.min_conns = min_conns;
+    self.backend.max_conns = max_conns;
+  }
+
+  pub fn set_backend_queue(&mut self, queue_cap: usize, max_age: StdDuration) {
+    if queue_cap == 0 {
+      log_error!("ProxyGatewayConfig::set_backend_queue: queue_cap must be nonzero");
+      panic!();
+    }
+    self.backend.queue_cap = queue_cap;
+    self.backend.queue_max_age = max_age;
+  }
+
+  pub fn set_retry_policy(&mut self, retry: RetryPolicy) {
+    if retry.max_attempts == 0 {
+      log_error!("ProxyGatewayConfig::set_retry_policy: max_attempts must be at least 1");
+      panic!();
+    }
+    if !(retry.budget_ratio >= 0.0) {
+      log_error!("ProxyGatewayConfig::set_retry_policy: invalid budget_ratio = {}", retry.budget_ratio);
+      panic!();
+    }
+    self.backend.retry = retry;
   }
 
   pub fn service_main(self) {
@@ -94,10 +574,12 @@ impl ProxyGatewayConfig {
 }
 
 pub fn service_main(config: Config) {
+  crate::log::init(&config.log);
   let t0 = get_time_usec();
-  println!("INFO:   proxy_gateway::service_main: build: {}.{}", crate::build::timestamp(), crate::build::digest());
-  println!("INFO:   proxy_gateway::service_main: startup: {}", t0.utc().rfc3339_nsec());
+  log_info!("proxy_gateway::service_main: build: {}.{}", crate::build::timestamp(), crate::build::digest());
+  log_info!("proxy_gateway::service_main: startup: {}", t0.utc().rfc3339_nsec());
   crate::signal::init_signals();
+  crate::request_id::init();
   let host = "127.0.0.1";
   //let port80: u16 = 80;
   let port443: u16 = 443;
@@ -113,13 +595,46 @@ pub fn service_main(config: Config) {
         continue;
       }
       Ok(bind) => {
-        println!("INFO:   proxy_gateway::service_main: listening on {}:{}", host, port80);
+        log_info!("proxy_gateway::service_main: listening on {}:{}", host, port80);
         break bind;
       }
     }
   };*/
+  crate::systemd::init_notify();
+  let mut activated = crate::systemd::listen_fds();
+  // NB: with more than one socket, the one named `https` (see systemd's
+  // `FileDescriptorName=`) is the port 443 listener.
+  let activated = match activated.len() {
+    0 => None,
+    1 => activated.pop().map(|(_, bind)| bind),
+    n => match activated.iter().position(|&(ref name, _)| name == "https") {
+      None => {
+        log_error!("proxy_gateway::service_main: socket activation: {} sockets and none named \"https\"", n);
+        panic!();
+      }
+      Some(pos) => {
+        log_info!("proxy_gateway::service_main: socket activation: using \"https\" of {} sockets", n);
+        Some(activated.swap_remove(pos).1)
+      }
+    }
+  };
+  let inherited = match config.handoff_path.as_ref() {
+    _ if activated.is_some() => activated,
+    None => None,
+    Some(path) => match crate::handoff::take_listeners(path) {
+      Err(e) => {
+        log_warn!("proxy_gateway::service_main: handoff: failed: {:?}", e);
+        None
+      }
+      Ok(listeners) => listeners.and_then(|mut listeners| listeners.pop())
+    }
+  };
   let mut bind_ct = 0;
-  let bind443 = loop {
+  let bind443 = if let Some(bind) = inherited {
+    log_info!("proxy_gateway::service_main: listening on inherited socket");
+    bind.set_nonblocking(true).unwrap();
+    bind
+  } else { loop {
     if bind_ct >= 50 {
       sleep(StdDuration::from_secs(2));
     } else if bind_ct > 0 {
@@ -131,20 +646,65 @@ pub fn service_main(config: Config) {
         continue;
       }
       Ok(bind) => {
-        println!("INFO:   proxy_gateway::service_main: listening on {}:{}", host, port443);
-        println!("DEBUG:  proxy_gateway::service_main:   bind ct = {}", bind_ct);
+        log_info!("proxy_gateway::service_main: listening on {}:{}", host, port443);
+        log_debug!("proxy_gateway::service_main:   bind ct = {}", bind_ct);
         bind.set_nonblocking(true).unwrap();
-        println!("DEBUG:  proxy_gateway::service_main:   set nonblocking");
+        log_debug!("proxy_gateway::service_main:   set nonblocking");
         break bind;
       }
     }
+  }};
+  if let Some(path) = config.handoff_path.as_ref() {
+    if let Err(e) = crate::handoff::serve(path, vec![bind443.try_clone().unwrap()]) {
+      log_warn!("proxy_gateway::service_main: handoff: failed to serve: {:?}", e);
+    }
+  }
+  // NB: bound before the sandbox, so that a Unix socket lives outside of it.
+  let admin = match config.admin.as_ref() {
+    None => None,
+    Some(addr) => match crate::admin::bind(addr) {
+      Err(e) => {
+        log_warn!("proxy_gateway::service_main: admin: failed to bind {:?}: {:?}", addr, e);
+        None
+      }
+      Ok(admin) => Some(admin)
+    }
+  };
+  // NB: started before the sandbox, which may not allow name resolution.
+  if let Some(trace) = config.trace.as_ref() {
+    if let Err(e) = crate::trace::init(trace) {
+      log_warn!("proxy_gateway::service_main: trace: failed to start exporter: {:?}", e);
+    }
+  }
+  let sandbox = match Sandbox::prepare(&config.sandbox) {
+    Err(e) => {
+      log_error!("proxy_gateway::service_main: sandbox: prepare failed: {:?}", e);
+      panic!();
+    }
+    Ok(sandbox) => sandbox
+  };
+  // NB: TLS setup happens before chroot, so that openssl can still read its
+  // config and the identity can be loaded from outside of the sandbox.
+  let tls_acceptor = match tls_acceptor443(&config, &sandbox.outside_path(&config.sandbox.tls_dir)) {
+    None => return,
+    Some(a) => a
   };
-  // TODO: do openssl-related setup before chroot.
-  let chroot_dir = "/var/lib/proxy_gateway/new_root";
-  //crate::daemon::mount(chroot_dir).unwrap();
-  crate::daemon::protect(chroot_dir, 297, 297).unwrap();
-  crate::daemon::mkdir().unwrap();
-  // TODO TODO
+  if let Some(hardening) = config.hardening.as_ref() {
+    if let Err(e) = crate::daemon::harden_pre(hardening) {
+      log_error!("proxy_gateway::service_main: hardening: failed: {:?}", e);
+      panic!();
+    }
+  }
+  if let Err(e) = sandbox.enter() {
+    log_error!("proxy_gateway::service_main: sandbox: enter failed: {:?}", e);
+    panic!();
+  }
+  if let Some(hardening) = config.hardening.as_ref() {
+    if let Err(e) = crate::daemon::harden(hardening) {
+      log_error!("proxy_gateway::service_main: hardening: failed: {:?}", e);
+      panic!();
+    }
+  }
   let config = Arc::new(config);
   let context = Context::new();
   /*let cfg = config.clone();
@@ -154,16 +714,17 @@ pub fn service_main(config: Config) {
   let cfg = config;
   let ctx = context;
   let bind = bind443.try_clone().unwrap();
-  let th443 = spawn(move || gateway443(cfg, ctx, bind));
+  let th443 = spawn(move || gateway443(cfg, ctx, bind, tls_acceptor, admin));
   /*th80.join().unwrap();*/
-  th443.join().unwrap();
-  //println!("INFO:   proxy_gateway::service_main: hup: received");
+  let stop = th443.join().unwrap();
+  crate::handoff::stop();
+  //log_info!("proxy_gateway::service_main: hup: received");
   // NB: small delay after HUP and before unbind.
   sleep(StdDuration::from_secs(1));
   drop(bind443);
   let t_hup = get_time_usec();
-  println!("INFO:   proxy_gateway::service_main: hup: done: {}", t_hup.utc().rfc3339_nsec());
-  loop {
+  log_info!("proxy_gateway::service_main: hup: done: {}", t_hup.utc().rfc3339_nsec());
+  while stop == Stop::Hup {
     let sig = crate::signal::signals();
     if sig.get_int() || sig.get_term() {
       break;
@@ -171,7 +732,7 @@ pub fn service_main(config: Config) {
     sleep(StdDuration::from_secs(1));
   }
   let t_end = get_time_usec();
-  println!("INFO:   proxy_gateway::service_main: shutdown: done: {}", t_end.utc().rfc3339_nsec());
+  log_info!("proxy_gateway::service_main: shutdown: done: {}", t_end.utc().rfc3339_nsec());
 }
 
 pub fn safe_ascii(s: &[u8]) -> SmolStr {
@@ -188,9 +749,98 @@ pub fn safe_ascii(s: &[u8]) -> SmolStr {
   buf.into()
 }
 
+pub fn raw_header<'a>(head: &'a [u8], name: &str) -> Option<&'a [u8]> {
+  for line in head.split(|&x| x == b'\n').skip(1) {
+    let line = match line.last() {
+      Some(&b'\r') => &line[ .. line.len() - 1],
+      _ => line
+    };
+    if line.is_empty() {
+      break;
+    }
+    let colon = match line.iter().position(|&x| x == b':') {
+      None => continue,
+      Some(colon) => colon
+    };
+    if line[ .. colon].eq_ignore_ascii_case(name.as_bytes()) {
+      let mut value = &line[colon + 1 .. ];
+      while let Some((&x, rest)) = value.split_first() {
+        if x != b' ' && x != b'\t' { break; }
+        value = rest;
+      }
+      while let Some((&x, rest)) = value.split_last() {
+        if x != b' ' && x != b'\t' { break; }
+        value = rest;
+      }
+      return Some(value);
+    }
+  }
+  None
+}
+
+pub fn find_head_end(buf: &[u8]) -> Option<usize> {
+  buf.windows(4).position(|w| w == b"\r\n\r\n").map(|pos| pos + 4)
+}
+
+pub const RETRY_AFTER_SECS: u32 = 1;
+
+#[derive(Clone)]
+pub struct ErrorReply {
+  pub accept: Option<Vec<u8>>,
+  pub request_id: String,
+  pub host: Option<SmolStr>,
+}
+
+impl ErrorReply {
+  pub fn encode(&self, pages: &RwLock<ErrorPages>, status: u16) -> Vec<u8> {
+    let vars = ErrorPageVars{
+      status,
+      request_id: &self.request_id,
+      host: self.host.as_ref().map(|s| s.as_str()),
+    };
+    let page = pages.read().unwrap().render(self.accept.as_ref().map(|v| &v[ .. ]), &vars);
+    let rep = HttpResponse::from_status(http_status(status));
+    let rep = match page {
+      None => rep,
+      Some((body, mime)) => rep.with_payload_str_mime(body, mime)
+    };
+    let mut rep = rep.to_raw();
+    rep.push_header_raw(crate::request_id::HEADER, &self.request_id);
+    if status == 503 {
+      rep.push_header(http1::HeaderName::RetryAfter, &format!("{}", RETRY_AFTER_SECS));
+    }
+    rep.push_header(http1::HeaderName::Connection, "close");
+    let mut buf = Vec::new();
+    rep.encode(&mut buf).unwrap();
+    buf
+  }
+
+  pub fn encode_redirect(&self, status: u16, location: &str) -> Vec<u8> {
+    let mut rep = HttpResponse::from_status(http_status(status)).to_raw();
+    rep.push_header(http1::HeaderName::Location, location);
+    rep.push_header_raw(crate::request_id::HEADER, &self.request_id);
+    rep.push_header(http1::HeaderName::Connection, "close");
+    let mut buf = Vec::new();
+    rep.encode(&mut buf).unwrap();
+    buf
+  }
+
+  pub fn write<W: Write>(&self, pages: &RwLock<ErrorPages>, stream: &mut W, status: u16) {
+    let buf = self.encode(pages, status);
+    if let Err(e) = stream.write_all(&buf).and_then(|_| stream.flush()) {
+      log_info!("write error: {:?}", e);
+      return;
+    }
+    log_info!("write done");
+  }
+}
+
 #[derive(Clone)]
 pub struct Context {
   pub router: Arc<Mutex<Router>>,
+  // Set while `gateway80` serves `router`, which answers the ACME HTTP-01
+  // challenges.
+  pub http01: Arc<AtomicBool>,
 }
 
 impl Context {
@@ -198,12 +848,14 @@ impl Context {
     let router = Router::new();
     Context{
       router: Arc::new(Mutex::new(router)),
+      http01: Arc::new(AtomicBool::new(false)),
     }
   }
 }
 
-pub fn gateway80(_config: Arc<Config>, ctx: Context, bind: TcpListener) -> () {
+pub fn gateway80(config: Arc<Config>, ctx: Context, bind: TcpListener) -> () {
   let base_url = http1::Url::parse("http://127.0.0.1").unwrap();
+  ctx.http01.store(true, Ordering::Release);
   ctx.router.lock().unwrap()
     .insert_get((), Box::new(|_, _, _| {
       ok().with_payload_str_mime("Hello world!\n", http1::Mime::TextHtml).into()
@@ -215,18 +867,18 @@ pub fn gateway80(_config: Arc<Config>, ctx: Context, bind: TcpListener) -> () {
   /*let acme_ctx = ctx.clone();
   let domain: SmolStr = match config.primhost.as_ref() {
     None => {
-      println!("ERROR:  tls: not configured with primary host");
+      log_error!("tls: not configured with primary host");
       return;
     }
     Some(s) => s.into()
   };
-  let alt_domains: Vec<SmolStr> = config.hostport.iter().filter(|(s, _)| s != &domain).map(|(s, _)| s.into()).collect();
+  let alt_domains: Vec<SmolStr> = config.hostgroup.iter().filter(|(s, _)| s != &domain).map(|(s, _)| s.into()).collect();
   let tls_identity = crate::acme::Acme::identity(&domain, acme_ctx);
   //let tls_identity = crate::acme::Acme::fresh_identity(&domain, &alt_domains as &[_], acme_ctx);
   if let Err(e) = tls_identity {
-    println!("INFO:   tls: error initializing identity: {:?}", e);
+    log_info!("tls: error initializing identity: {:?}", e);
   } else {
-    println!("INFO:   tls: ok");
+    log_info!("tls: ok");
   }*/
   // TODO
   let mut seq_nr = 0;
@@ -236,32 +888,32 @@ pub fn gateway80(_config: Arc<Config>, ctx: Context, bind: TcpListener) -> () {
       }
       Ok((mut stream, addr)) => {
         seq_nr += 1;
-        println!("INFO:   accepted {}: {:?}", seq_nr, addr);
+        log_info!("accepted {}: {:?}", seq_nr, addr);
         let mut rbuf = Vec::new();
         rbuf.resize(8192, 0);
         match stream.read(&mut rbuf) {
           Err(e) => {
-            println!("INFO:       read error: {:?}", e);
+            log_info!("read error: {:?}", e);
             continue;
           }
           Ok(n) => {
-            println!("INFO:       read {} bytes", n);
-            println!("INFO:         buf={:?}", safe_ascii(&rbuf[ .. n]));
+            log_info!("read {} bytes", n);
+            log_info!("request: {}", config.redact.request(&rbuf[ .. n], None));
             let mut parser = http1::RequestParser::new((&rbuf[ .. n]).iter().map(|&x| x));
             let mut req = http1::Request::default();
             if let Err(e) = parser.parse_first_line(&base_url, &mut req) {
-              println!("INFO:       invalid first line: {:?}", e);
+              log_info!("invalid first line: {:?}", e);
               continue;
             }
             if let Err(e) = parser.parse_headers(&mut req) {
-              println!("INFO:       invalid headers: {:?}", e);
+              log_info!("invalid headers: {:?}", e);
               continue;
             }
-            println!("INFO:       valid request");
+            log_info!("valid request");
             // TODO: payload.
             let req = match HttpRequest::try_from_raw_strip_headers(req) {
               Err(_) => {
-                println!("INFO:       request conversion failure");
+                log_info!("request conversion failure");
                 continue;
               }
               Ok((req, _)) => req
@@ -269,32 +921,32 @@ pub fn gateway80(_config: Arc<Config>, ctx: Context, bind: TcpListener) -> () {
             let port = 80;
             match ctx.router.lock().unwrap().match_(port, &req) {
               Err(_) => {
-                println!("INFO:       match error");
+                log_info!("match error");
                 continue;
               }
               Ok(None) => {
-                println!("INFO:       no match");
+                log_info!("no match");
                 //continue;
                 let rep = HttpResponse::not_found();
                 let rep = rep.to_raw();
                 let mut buf = BufWriter::new(&mut stream);
                 rep.encode(&mut buf).unwrap();
                 buf.flush().unwrap();
-                println!("INFO:       write done");
+                log_info!("write done");
               }
               Ok(Some(rep)) => {
-                println!("INFO:       matched response");
+                log_info!("matched response");
                 let mut rep = rep.to_raw();
                 let mut buf = BufWriter::new(&mut stream);
                 rep.encode(&mut buf).unwrap();
                 buf.flush().unwrap();
-                println!("INFO:       write done");
+                log_info!("write done");
               }
             }
             /*
             match req.url.as_ref() {
               None => {
-                println!("INFO:       invalid url");
+                log_info!("invalid url");
                 continue;
               }
               Some(url) => {
@@ -302,21 +954,21 @@ pub fn gateway80(_config: Arc<Config>, ctx: Context, bind: TcpListener) -> () {
                 let method = req.method.unwrap();
                 match ctx.router.lock().unwrap().match_url(port, method, url) {
                   Err(_) => {
-                    println!("INFO:       match error");
+                    log_info!("match error");
                     continue;
                   }
                   Ok(None) => {
-                    println!("INFO:       no match");
+                    log_info!("no match");
                     //continue;
                     let rep = HttpResponse::not_found();
                     let rep = rep.to_raw();
                     let mut buf = BufWriter::new(&mut stream);
                     rep.encode(&mut buf).unwrap();
                     buf.flush().unwrap();
-                    println!("INFO:       write done");
+                    log_info!("write done");
                   }
                   Ok(Some(rep)) => {
-                    println!("INFO:       matched response");
+                    log_info!("matched response");
                     let mut rep = rep.to_raw();
                     /*// FIXME: following should go in `to_raw`.
                     if let Some(buf) = rep.payload.as_ref() {
@@ -330,7 +982,7 @@ pub fn gateway80(_config: Arc<Config>, ctx: Context, bind: TcpListener) -> () {
                     let mut buf = BufWriter::new(&mut stream);
                     rep.encode(&mut buf).unwrap();
                     buf.flush().unwrap();
-                    println!("INFO:       write done");
+                    log_info!("write done");
                   }
                 }
               }
@@ -346,168 +998,135 @@ pub fn gateway80(_config: Arc<Config>, ctx: Context, bind: TcpListener) -> () {
         write!(&mut buf, "\r\n").unwrap();
         write!(&mut buf, "Hello world!\n").unwrap();
         buf.flush().unwrap();
-        println!("INFO:       write done");*/
+        log_info!("write done");*/
       }
     }
   }
 }
 
-pub fn gateway443(config: Arc<Config>, ctx: Context, bind: TcpListener) -> () {
-  let base_url = http1::Url::parse("http://127.0.0.1").unwrap();
-  let acme_ctx = ctx;
+pub const DEFAULT_SHUTDOWN_GRACE: StdDuration = StdDuration::from_secs(30);
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Stop {
+  // Stopped serving on HUP; the process waits for INT/TERM to exit.
+  Hup,
+  // Stopped serving on INT/TERM; the process exits right away.
+  Exit,
+}
+
+pub fn tls_acceptor443(config: &Config, tls_dir: &Path) -> Option<TlsAcceptor> {
   let domain: SmolStr = match config.primhost.as_ref() {
     None => {
-      println!("ERROR:  tls: not configured with primary host");
-      return;
+      log_error!("tls: not configured with primary host");
+      return None;
     }
     Some(s) => s.into()
   };
-  let tls_identity = match crate::acme::Acme::identity(&domain, acme_ctx) {
+  let tls_identity = match crate::acme::Acme::load_identity(tls_dir, &domain) {
     Err(e) => {
-      println!("INFO:   tls: error initializing identity: {:?}", e);
-      return;
+      log_info!("tls: error initializing identity from {:?}: {:?}", tls_dir, e);
+      return None;
     }
     Ok(i) => {
-      println!("INFO:   tls: identity: ok");
+      log_info!("tls: identity: ok");
       i
     }
   };
-  let tls_acceptor = match TlsAcceptor::new(tls_identity) {
+  match TlsAcceptor::new(tls_identity) {
     Err(e) => {
-      println!("INFO:   tls: failed to create acceptor: {:?}", e);
-      return;
+      log_info!("tls: failed to create acceptor: {:?}", e);
+      None
     }
     Ok(a) => {
-      println!("INFO:   tls: acceptor: ok");
-      a
+      log_info!("tls: acceptor: ok");
+      Some(a)
     }
-  };
-  let mut backends = BTreeMap::new();
-  for &port in config.allports.iter() {
-  let (front_tx, back_rx) = channel::<(Timespec, HttpRequest, SyncSender<Option<HttpResponse>>)>();
-  let _ = spawn(move || {
-    println!("INFO:   backend: start");
-    let host = "127.0.0.1";
-    let port_start = port;
-    let port_fin = port + 1;
-    let mut port = port_start;
-    let mut retry: Option<(Timespec, HttpRequest, SyncSender<Option<HttpResponse>>)> = None;
-    let mut first = Some(());
-    'outer: loop {
-      if first.take().is_none() {
-        sleep(StdDuration::from_secs(2));
-      }
-      let addr = (host, port).to_socket_addrs().unwrap().next().unwrap();
-      let stream = match TcpStream::connect_timeout(&addr, StdDuration::from_secs(2)) {
-        Ok(stream) => stream,
-        Err(_) => {
-          //println!("DEBUG:  backend:   connect: failed: port={}", port);
-          if port >= port_fin {
-            port = port_start;
-          } else {
-            port += 1;
-          }
-          continue 'outer;
-        }
-      };
-      let mut chan: Chan = Chan::new(stream);
-      match chan.query(&Msg::OKQ) {
-        Ok(Msg::OKR) => {}
-        /*Ok(Msg::HUP) => {
-          // TODO
-        }*/
-        _ => {
-          //println!("DEBUG:  backend:   setup: failed: port={}", port);
-          if port >= port_fin {
-            port = port_start;
-          } else {
-            port += 1;
-          }
-          continue 'outer;
-        }
-      }
-      println!("INFO:   backend: connected on {}:{}", host, port);
-      if retry.is_some() {
-        // FIXME: soft real-time.
-        let t = get_time_coarse();
-        for (t0, req, back_tx) in retry.take().into_iter() {
-          if (t - t0) >= Duration::seconds(2) {
-            continue;
-          }
-          let req = Msg::H1Q(req);
-          let maybe_rep = match chan.query(&req) {
-            Ok(Msg::Top) => None,
-            Ok(Msg::H1P(rep)) => Some(rep),
-            /*Ok(Msg::HUP) => {
-              // TODO
-            }*/
-            _ => {
-              println!("DEBUG:  backend:   query: retry failed");
-              let req = match req {
-                Msg::H1Q(req) => req,
-                _ => unreachable!()
-              };
-              retry = Some((t0, req, back_tx));
-              println!("INFO:   backend: disconnected");
-              continue 'outer;
-            }
-          };
-          match back_tx.send(maybe_rep) {
-            Ok(_) => {}
-            _ => {}
-          }
-        }
+  }
+}
+
+// NB: `ctx` is handed to the admin API, whose certificate renewal publishes
+// its HTTP-01 challenges on `ctx.router`.
+pub fn gateway443(config: Arc<Config>, ctx: Context, bind: TcpListener, tls_acceptor: TlsAcceptor, admin: Option<AdminListener>) -> Stop {
+  let base_url = http1::Url::parse("http://127.0.0.1").unwrap();
+  let errpages = match config.errpage_dir.as_ref() {
+    None => ErrorPages::empty(),
+    Some(dir) => match ErrorPages::load(dir) {
+      Err(e) => {
+        log_warn!("errpage: failed to load templates from {:?}: {:?}", dir, e);
+        ErrorPages::empty()
       }
-      loop {
-        match back_rx.recv() {
-          Ok((t0, req, back_tx)) => {
-            // FIXME: soft real-time.
-            let t = get_time_coarse();
-            if (t - t0) >= Duration::seconds(2) {
-              continue;
-            }
-            let req = Msg::H1Q(req);
-            let maybe_rep = match chan.query(&req) {
-              Ok(Msg::Top) => None,
-              Ok(Msg::H1P(rep)) => Some(rep),
-              /*Ok(Msg::HUP) => {
-                // TODO
-              }*/
-              _ => {
-                println!("DEBUG:  backend:   query: failed");
-                let req = match req {
-                  Msg::H1Q(req) => req,
-                  _ => unreachable!()
-                };
-                retry = Some((t0, req, back_tx));
-                println!("INFO:   backend: disconnected");
-                continue 'outer;
-              }
-            };
-            match back_tx.send(maybe_rep) {
-              Ok(_) => {}
-              _ => {}
-            }
-          }
-          _ => {
-          }
-        }
+      Ok(pages) => pages
+    }
+  };
+  // NB: opened inside the sandbox, so the path is relative to the new root.
+  let access_log = match config.access_log.as_ref() {
+    None => None,
+    Some(cfg) => match AccessLog::open(cfg.clone()) {
+      Err(e) => {
+        log_warn!("access: failed to open {:?}: {:?}", cfg.path, e);
+        None
       }
-      unreachable!();
+      Ok(access_log) => Some(access_log)
     }
-    println!("INFO:   backend: end");
+  };
+  let mut groups = BTreeMap::new();
+  for (name, group_cfg) in config.groups.iter() {
+    groups.insert(name.clone(), Arc::new(BackendGroup::start(name, group_cfg, &config.backend)));
+  }
+  let gw = Arc::new(Gateway443{
+    config: config.clone(),
+    base_url,
+    groups,
+    tls_acceptor: RwLock::new(tls_acceptor),
+    errpages: RwLock::new(errpages),
+    shutdown: AtomicBool::new(false),
+    access_log,
+    conns: Mutex::new(BTreeMap::new()),
   });
-  backends.insert(port, Mutex::new(front_tx));
+  if let Some(admin) = admin {
+    crate::admin::start(gw.clone(), ctx, admin);
   }
-  let backends = Arc::new(backends);
-  let timeout = StdDuration::from_secs(2);
+  let mut engine = Engine::start(gw.clone());
+  let shedder = {
+    let gw = gw.clone();
+    WorkerPool::new("shedder", 1, config.pool.queue_cap, move |(stream, seq_nr): (TcpStream, u64)| {
+      shed_reply443(&gw, stream, seq_nr)
+    })
+  };
+  let mut bind_poll = Poll::new().unwrap();
+  bind_poll.register(&bind, bind.as_raw_fd() as Token, Interest::Read).unwrap();
+  let watchdog = crate::systemd::watchdog_interval();
+  let timeout = watchdog.map_or(StdDuration::from_secs(2), |w| w.min(StdDuration::from_secs(2)));
+  let mut last_watchdog = Instant::now();
   let mut seq_nr = 0;
-  loop {
-    if crate::signal::signals().get_hup() {
-      // FIXME: clean shutdown backend thread.
-      break;
+  crate::systemd::notify_ready();
+  let stop = loop {
+    if let Some(w) = watchdog {
+      if last_watchdog.elapsed() >= w {
+        crate::systemd::notify_watchdog();
+        last_watchdog = Instant::now();
+      }
+    }
+    let sig = crate::signal::signals();
+    if sig.get_hup() {
+      break Stop::Hup;
+    }
+    if sig.get_int() || sig.get_term() {
+      break Stop::Exit;
+    }
+    if crate::handoff::handed_off() {
+      break Stop::Exit;
     }
-    match crate::net::select_read_fd_timeout(&bind, timeout) {
+    if crate::signal::signals().get_usr1() {
+      log_info!("reload: received");
+      gw.reload();
+    }
+    if crate::signal::signals().get_usr2() {
+      if let Some(access_log) = gw.access_log.as_ref() {
+        access_log.reopen();
+      }
+    }
+    match crate::net::poll_read_fd_timeout(&mut bind_poll, &bind, timeout) {
       Err(_) |
       Ok(None) => {
         continue;
@@ -521,204 +1140,699 @@ pub fn gateway443(config: Arc<Config>, ctx: Context, bind: TcpListener) -> () {
         }
         Ok((stream, addr)) => {
           seq_nr += 1;
-          println!("INFO:   accepted {}: {:?}", seq_nr, addr);
+          log_info!("accepted {}: {:?}", seq_nr, addr);
+          metrics().connections_accepted.inc();
           stream
         }
       };
-      // FIXME: set stream timeout.
-      /*
-      match stream.set_read_timeout(Some(Duration::from_secs(5))) {
-      }
-      match stream.set_write_timeout(Some(Duration::from_secs(5))) {
-      }
-      */
-      let config = config.clone();
-      let base_url = base_url.clone();
-      let backends = backends.clone();
-      let tls_acceptor = tls_acceptor.clone();
-      let _ = spawn(move || {
-        let mut stream = match tls_acceptor.accept(stream) {
-          Err(e) => {
-            println!("INFO:       tls: failed to accept: {:?}", e);
-            return;
-          }
-          Ok(stream) => stream
-        };
-        println!("INFO:       tls: accepted");
-        let rcap = 8192;
-        let mut rbuf = Vec::new();
-        rbuf.resize(rcap, 0);
-        match stream.read(&mut rbuf) {
-          Err(e) => {
-            println!("INFO:       read error: {:?}", e);
-            return;
-          }
-          Ok(r_sz) => {
-            println!("INFO:       read {} bytes", r_sz);
-            println!("INFO:         buf={:?}", safe_ascii(&rbuf[ .. r_sz]));
-            let mut parser = http1::RequestParser::new((&rbuf[ .. r_sz]).iter().map(|&x| x));
-            let mut req = http1::Request::default();
-            if let Err(e) = parser.parse_first_line(&base_url, &mut req) {
-              println!("INFO:       invalid first line: {:?}", e);
-              return;
-            }
-            if let Err(e) = parser.parse_headers(&mut req) {
-              println!("INFO:       invalid headers: {:?}", e);
-              return;
-            }
-            let header_len = parser.pos();
-            drop(parser);
-            println!("INFO:       valid request header: len={}", header_len);
-            let mut route_host: Option<SmolStr> = None;
-            let mut payload_len = None;
-            for h in req.headers.iter() {
-              if route_host.is_some() &&
-                 payload_len.is_some()
-              {
-                break;
-              }
-              match (h.name.as_ref(), h.value.as_ref()) {
-                (Ok(&http1::HeaderName::Host), Ok(&http1::HeaderValue::Domain(ref host_s))) => {
-                  println!("INFO:       valid host: {:?}", safe_ascii(host_s.as_bytes()));
-                  if route_host.is_none() {
-                    route_host = Some(host_s.into());
-                  }
-                }
-                (Ok(&http1::HeaderName::ContentLength), Ok(&http1::HeaderValue::Length(len))) => {
-                  if payload_len.is_none() {
-                    payload_len = Some(len as usize);
-                  }
-                }
-                _ => {}
+      match engine.try_submit((stream, seq_nr)) {
+        Ok(_) => {}
+        Err((stream, seq_nr)) => {
+          log_info!("engine: at capacity, shedding {}", seq_nr);
+          metrics().connections_shed.inc();
+          match config.pool.overload {
+            Overload::Reply => {
+              if let Err(_) = shedder.try_submit((stream, seq_nr)) {
+                log_info!("pool: shedder full, closing {}", seq_nr);
               }
             }
-            let mut route_port = if let Some(host_s) = route_host.as_ref() {
-              config.hostport.get(host_s).map(|&port| port)
-            } else {
-              None
-            };
-            if route_port.is_none() {
-              route_port = config.def_port
-            };
-            if route_port.is_none() {
-              println!("INFO:       no route to host");
-              let rep = HttpResponse::not_found();
-              let rep = rep.to_raw();
-              let mut buf = BufWriter::new(&mut stream);
-              rep.encode(&mut buf).unwrap();
-              buf.flush().unwrap();
-              println!("INFO:       write done");
-              return;
-            }
-            let route_port = route_port.unwrap();
-            let payload_len = payload_len.unwrap_or(0);
-            if payload_len <= 0 {
-              println!("INFO:       no payload");
-            } else {
-              println!("INFO:       payload len={}", payload_len);
-            }
-            const MAX_PAYLOAD: usize = 8192;
-            //const MAX_PAYLOAD: usize = 65536;
-            if payload_len > MAX_PAYLOAD {
-              println!("INFO:       payload too large");
-              let rep = HttpResponse::from_status(HttpStatus::BadRequest);
-              let rep = rep.to_raw();
-              let mut buf = BufWriter::new(&mut stream);
-              rep.encode(&mut buf).unwrap();
-              buf.flush().unwrap();
-              println!("INFO:       write done");
-              return;
-            } else if r_sz < header_len + payload_len {
-              rbuf.resize(max(rcap, header_len + payload_len), 0);
-              if let Err(e) = stream.read_exact(&mut rbuf[r_sz .. header_len + payload_len]) {
-                println!("INFO:       payload read error: {:?}", e);
-                let rep = HttpResponse::from_status(HttpStatus::BadRequest);
-                let rep = rep.to_raw();
-                let mut buf = BufWriter::new(&mut stream);
-                rep.encode(&mut buf).unwrap();
-                buf.flush().unwrap();
-                println!("INFO:       write done");
-                return;
-              }
+            Overload::Close => {
+              drop(stream);
             }
-            req.set_payload(&rbuf[header_len .. header_len + payload_len]);
-            let req = match HttpRequest::try_from_raw_strip_headers(req) {
-              Err(_) => {
-                println!("INFO:       request conversion failure");
-                let rep = HttpResponse::from_status(HttpStatus::BadRequest);
-                let rep = rep.to_raw();
-                let mut buf = BufWriter::new(&mut stream);
-                rep.encode(&mut buf).unwrap();
-                buf.flush().unwrap();
-                println!("INFO:       write done");
-                return;
-              }
-              Ok((req, _)) => req
-            };
-            let (back_tx, front_rx) = sync_channel(1);
-            println!("INFO:       route to port = {:?}", route_port);
-            let front_tx = match backends.get(&route_port) {
-              None => {
-                println!("INFO:       bug: no backend for port = {}", route_port);
-                let rep = HttpResponse::not_found();
-                let rep = rep.to_raw();
-                let mut buf = BufWriter::new(&mut stream);
-                rep.encode(&mut buf).unwrap();
-                buf.flush().unwrap();
-                println!("INFO:       write done");
-                return;
-              }
-              Some(front_tx) => front_tx
-            };
-            match front_tx.lock().unwrap().send((get_time_coarse(), req, back_tx)) {
-              Ok(_) => {}
-              _ => {
-                println!("INFO:       backend: send error");
-                let rep = HttpResponse::not_found();
-                let rep = rep.to_raw();
-                let mut buf = BufWriter::new(&mut stream);
-                rep.encode(&mut buf).unwrap();
-                buf.flush().unwrap();
-                println!("INFO:       write done");
-                return;
-              }
+          }
+        }
+      }
+    }
+  };
+  drop(bind_poll);
+  drop(bind);
+  gw.begin_shutdown();
+  crate::systemd::notify_stopping();
+  log_info!("shutdown: {:?}: stopped accepting, draining", stop);
+  let grace = config.shutdown_grace.unwrap_or(DEFAULT_SHUTDOWN_GRACE);
+  let t0 = Instant::now();
+  let (conns, backend_reqs) = loop {
+    let conns = engine.num_active();
+    let backend_reqs = gw.num_outstanding();
+    if (conns == 0 && backend_reqs == 0) || t0.elapsed() >= grace {
+      break (conns, backend_reqs);
+    }
+    sleep(StdDuration::from_millis(100));
+  };
+  log_info!("shutdown: summary: accepted = {} elapsed = {:?} grace = {:?} abandoned conns = {} abandoned backend requests = {}",
+      seq_nr, t0.elapsed(), grace, conns, backend_reqs);
+  stop
+}
+
+pub struct BackendJob {
+  pub t0: Timespec,
+  pub queued: Instant,
+  // When the client stops waiting for the reply.
+  pub deadline: Instant,
+  pub req: HttpRequest,
+  pub reply: BackendReply,
+  pub retryable: bool,
+  pub attempts: u32,
+  pub log_ctx: LogCtx,
+}
+
+// When a job was queued, sent to a backend member, and answered.
+#[derive(Clone, Copy, Debug)]
+pub struct BackendTiming {
+  pub queued: Instant,
+  pub sent: Instant,
+  pub received: Instant,
+}
+
+impl BackendTiming {
+  pub fn upstream(&self) -> StdDuration {
+    self.received - self.sent
+  }
+}
+
+pub struct BackendOutcome {
+  pub res: Result<Option<HttpResponse>, u16>,
+  // The member that answered, and the timing of the exchange with it.
+  pub member: Option<SmolStr>,
+  pub timing: Option<BackendTiming>,
+}
+
+impl BackendOutcome {
+  pub fn failed(status: u16) -> BackendOutcome {
+    BackendOutcome{res: Err(status), member: None, timing: None}
+  }
+}
+
+pub struct BackendReply {
+  tx: SyncSender<BackendOutcome>,
+  notify: Option<(Arc<Notify>, Token)>,
+}
+
+impl BackendReply {
+  pub fn new(notify: Option<(Arc<Notify>, Token)>) -> (BackendReply, Receiver<BackendOutcome>) {
+    let (tx, rx) = sync_channel(1);
+    (BackendReply{tx, notify}, rx)
+  }
+
+  pub fn send(self, rep: Option<HttpResponse>, member: &str, timing: BackendTiming) -> Result<(), ()> {
+    let outcome = BackendOutcome{
+      res: Ok(rep),
+      member: Some(member.into()),
+      timing: Some(timing),
+    };
+    let res = self.tx.send(outcome).map_err(|_| ());
+    if let Some((notify, token)) = self.notify {
+      notify.notify(token);
+    }
+    res
+  }
+
+  pub fn fail(self, status: u16) {
+    match self.tx.send(BackendOutcome::failed(status)) {
+      Ok(_) => {}
+      Err(_) => {}
+    }
+    if let Some((notify, token)) = self.notify {
+      notify.notify(token);
+    }
+  }
+}
+
+pub struct Gateway443 {
+  pub config: Arc<Config>,
+  pub base_url: http1::Url,
+  pub groups: BTreeMap<SmolStr, Arc<BackendGroup>>,
+  pub tls_acceptor: RwLock<TlsAcceptor>,
+  pub errpages: RwLock<ErrorPages>,
+  pub shutdown: AtomicBool,
+  pub access_log: Option<AccessLog>,
+  pub conns: Mutex<BTreeMap<u64, ConnInfo>>,
+}
+
+#[derive(Clone, Debug)]
+pub struct ConnInfo {
+  pub client_ip: Option<IpAddr>,
+  pub t0: Instant,
+  pub nreqs: u64,
+  // The most recent request on the connection.
+  pub host: Option<SmolStr>,
+  pub method: Option<SmolStr>,
+  pub target: Option<SmolStr>,
+}
+
+pub enum Routed {
+  Backend(Arc<BackendGroup>),
+  Redirect(u16, String),
+}
+
+pub struct RequestHead {
+  pub req: http1::Request,
+  pub header_len: usize,
+  pub payload_len: usize,
+  pub route_host: Option<SmolStr>,
+  pub accept: Option<Vec<u8>>,
+  pub cookie: Option<Vec<u8>>,
+  pub client_ip: Option<IpAddr>,
+  pub keep_alive: bool,
+  pub request_id: SmolStr,
+  pub method: SmolStr,
+  pub target: SmolStr,
+  // As sent, e.g. `HTTP/1.0`.
+  pub version: SmolStr,
+  pub path: SmolStr,
+  pub query: Option<SmolStr>,
+  // The target to forward instead, if a route rule rewrote it.
+  pub rewrite: Option<String>,
+  pub referer: Option<SmolStr>,
+  pub user_agent: Option<SmolStr>,
+  pub t0: Instant,
+  pub trace: Option<RequestTrace>,
+}
+
+impl RequestHead {
+  pub fn err_reply(&self) -> ErrorReply {
+    ErrorReply{
+      accept: self.accept.clone(),
+      request_id: self.request_id.to_string(),
+      host: self.route_host.clone(),
+    }
+  }
+
+  pub fn total_len(&self) -> usize {
+    self.header_len + self.payload_len
+  }
+}
+
+pub const MAX_PAYLOAD: usize = 8192;
+//const MAX_PAYLOAD: usize = 65536;
+
+impl Gateway443 {
+  pub fn is_shutting_down(&self) -> bool {
+    self.shutdown.load(Ordering::Acquire)
+  }
+
+  pub fn begin_shutdown(&self) {
+    self.shutdown.store(true, Ordering::Release);
+  }
+
+  pub fn tls_acceptor(&self) -> TlsAcceptor {
+    self.tls_acceptor.read().unwrap().clone()
+  }
+
+  // Reloads what can change on disk without a restart: error page
+  // templates, the TLS identity, and the access log file.
+  pub fn reload(&self) {
+    crate::systemd::notify_reloading();
+    if let Err(e) = self.errpages.write().unwrap().reload() {
+      log_warn!("reload: failed to reload error pages: {:?}", e);
+    }
+    // NB: runs inside the sandbox, where `tls_dir` is at its own path.
+    if let Some(acceptor) = tls_acceptor443(&self.config, &self.config.sandbox.tls_dir) {
+      *self.tls_acceptor.write().unwrap() = acceptor;
+    }
+    if let Some(access_log) = self.access_log.as_ref() {
+      access_log.reopen();
+    }
+    crate::systemd::notify_ready();
+  }
+
+  pub fn conn_opened(&self, seq_nr: u64, client_ip: Option<IpAddr>) {
+    metrics().connections_active.inc();
+    self.conns.lock().unwrap().insert(seq_nr, ConnInfo{
+      client_ip,
+      t0: Instant::now(),
+      nreqs: 0,
+      host: None,
+      method: None,
+      target: None,
+    });
+  }
+
+  pub fn conn_request(&self, seq_nr: u64, head: &RequestHead) {
+    if let Some(info) = self.conns.lock().unwrap().get_mut(&seq_nr) {
+      info.nreqs += 1;
+      info.host = head.route_host.clone();
+      info.method = Some(head.method.clone());
+      info.target = Some(head.target.clone());
+    }
+  }
+
+  pub fn conn_closed(&self, seq_nr: u64) {
+    if self.conns.lock().unwrap().remove(&seq_nr).is_some() {
+      metrics().connections_active.dec();
+    }
+  }
+
+  pub fn num_outstanding(&self) -> usize {
+    self.groups.values()
+      .flat_map(|group| group.members().iter())
+      .map(|member| member.outstanding())
+      .sum()
+  }
+
+  pub fn parse_head(&self, buf: &[u8], client_ip: Option<IpAddr>) -> Result<RequestHead, ()> {
+    let t_parse = Instant::now();
+    let mut parser = http1::RequestParser::new(buf.iter().map(|&x| x));
+    let mut req = http1::Request::default();
+    if let Err(e) = parser.parse_first_line(&self.base_url, &mut req) {
+      log_info!("invalid first line: {:?}", e);
+      metrics().request_parse_errors.inc();
+      return Err(());
+    }
+    if let Err(e) = parser.parse_headers(&mut req) {
+      log_info!("invalid headers: {:?}", e);
+      metrics().request_parse_errors.inc();
+      return Err(());
+    }
+    let header_len = parser.pos();
+    drop(parser);
+    log_info!("valid request header: len={}", header_len);
+    let mut route_host: Option<SmolStr> = None;
+    let mut payload_len = None;
+    for h in req.headers.iter() {
+      if route_host.is_some() &&
+         payload_len.is_some()
+      {
+        break;
+      }
+      match (h.name.as_ref(), h.value.as_ref()) {
+        (Ok(&http1::HeaderName::Host), Ok(&http1::HeaderValue::Domain(ref host_s))) => {
+          log_info!("valid host: {:?}", safe_ascii(host_s.as_bytes()));
+          if route_host.is_none() {
+            route_host = Some(host_s.into());
+          }
+        }
+        (Ok(&http1::HeaderName::ContentLength), Ok(&http1::HeaderValue::Length(len))) => {
+          if payload_len.is_none() {
+            payload_len = Some(len as usize);
+          }
+        }
+        _ => {}
+      }
+    }
+    let head = &buf[ .. header_len];
+    let first_line = head.split(|&x| x == b'\r').next().unwrap_or(b"");
+    let http10 = first_line.ends_with(b"HTTP/1.0");
+    let mut first_parts = first_line.split(|&x| x == b' ');
+    let method = safe_ascii(first_parts.next().unwrap_or(b""));
+    let target = safe_ascii(first_parts.next().unwrap_or(b""));
+    let version = safe_ascii(first_parts.next().unwrap_or(b""));
+    let path: SmolStr = crate::routing::target_path(&target).into();
+    // NB: route rules match on the path as sent, so a path that a backend
+    // could resolve elsewhere, e.g. `/static/../api`, is refused.
+    if has_dot_segment(&path) {
+      log_info!("invalid path: dot segment");
+      metrics().request_parse_errors.inc();
+      return Err(());
+    }
+    let query = target.find('?').map(|pos| target[pos + 1 .. ].into());
+    // NB: the parsed host may carry a port or a trailing dot; if it did not
+    // parse as a domain at all, fall back to the raw header value.
+    let route_host = match route_host {
+      Some(host_s) => Some(normalize_host(&host_s)),
+      None => raw_header(head, "host")
+        .filter(|v| !v.is_empty() && v.iter().all(|&x| x.is_ascii_alphanumeric() || b"-._:[]".contains(&x)))
+        .map(|v| normalize_host(&safe_ascii(v)))
+    };
+    let request_id = match raw_header(head, "x-request-id") {
+      Some(id) if self.config.trust_request_id && crate::request_id::valid(id) => safe_ascii(id),
+      _ => crate::request_id::generate()
+    };
+    let keep_alive = match raw_header(head, "connection") {
+      Some(v) if v.eq_ignore_ascii_case(b"close") => false,
+      _ => !http10 && !self.is_shutting_down()
+    };
+    let trace = match self.config.trace.as_ref() {
+      Some(cfg) if crate::trace::enabled() => {
+        let ctx = TraceCtx::from_headers(
+            raw_header(head, TRACEPARENT),
+            raw_header(head, TRACESTATE),
+            cfg.sample_ratio,
+        );
+        Some(RequestTrace::new(ctx, (t_parse, Instant::now())))
+      }
+      _ => None
+    };
+    Ok(RequestHead{
+      req,
+      header_len,
+      payload_len: payload_len.unwrap_or(0),
+      route_host,
+      accept: raw_header(head, "accept").map(|v| v.to_owned()),
+      cookie: raw_header(head, "cookie").map(|v| v.to_owned()),
+      client_ip,
+      keep_alive,
+      request_id,
+      method: method.into(),
+      target: self.config.redact.mask_target(&target),
+      version,
+      path,
+      query,
+      rewrite: None,
+      referer: raw_header(head, "referer").map(|v| safe_ascii(v).into()),
+      user_agent: raw_header(head, "user-agent").map(|v| safe_ascii(v).into()),
+      t0: t_parse,
+      trace,
+    })
+  }
+
+  // Picks the backend group for a request, after applying the rewrites of
+  // the matching route rule; a rewritten target is left in `head.rewrite`.
+  pub fn route(&self, head: &mut RequestHead, buf: &[u8]) -> Option<Routed> {
+    let host = head.route_host.as_ref().map(|s| s.as_str());
+    let mut route_group = match self.config.routes.lookup(host, &head.method, &head.path, &buf[ .. head.header_len]) {
+      None => None,
+      Some(rule) => {
+        log_info!("route rule: group = {:?}", rule.group);
+        if !rule.rewrites.is_empty() {
+          let target = match head.query.as_ref() {
+            None => head.path.to_string(),
+            Some(query) => format!("{}?{}", head.path, query)
+          };
+          // NB: `${host}` expands to the rule's host, not to the header.
+          let rule_host = rule.host.as_ref().map_or("", |h| h.as_str());
+          match crate::rewrite::apply(&rule.rewrites, rule_host, &target) {
+            RewriteOutcome::Redirect(status, location) => {
+              log_info!("rewrite: redirect: status = {} location = {:?}", status, location);
+              return Some(Routed::Redirect(status, location));
             }
-            drop(front_tx);
-            match front_rx.recv_timeout(StdDuration::from_secs(2)) {
-              Err(_) => {
-                println!("INFO:       backend: recv error");
-                let rep = HttpResponse::not_found();
-                let rep = rep.to_raw();
-                let mut buf = BufWriter::new(&mut stream);
-                rep.encode(&mut buf).unwrap();
-                buf.flush().unwrap();
-                println!("INFO:       write done");
-              }
-              Ok(None) => {
-                println!("INFO:       no match");
-                let rep = HttpResponse::not_found();
-                let rep = rep.to_raw();
-                let mut buf = BufWriter::new(&mut stream);
-                rep.encode(&mut buf).unwrap();
-                buf.flush().unwrap();
-                println!("INFO:       write done");
-              }
-              Ok(Some(rep)) => {
-                println!("INFO:       matched response");
-                let mut rep = rep.to_raw();
-                rep.push_header(http1::HeaderName::StrictTransportSecurity, "max-age=63072000");
-                rep.push_header(http1::HeaderName::ContentSecurityPolicy, "default-src 'none'; script-src 'self'; style-src 'self'; connect-src 'self'; form-action 'self'; img-src 'self'; frame-ancestors 'self'; base-uri 'none'");
-                rep.push_header(http1::HeaderName::XContentTypeOptions, "nosniff");
-                rep.push_header(http1::HeaderName::XFrameOptions, "SAMEORIGIN");
-                let mut buf = BufWriter::new(&mut stream);
-                rep.encode(&mut buf).unwrap();
-                buf.flush().unwrap();
-                println!("INFO:       write done");
+            RewriteOutcome::Forward(rewritten) => {
+              if rewritten != target {
+                log_info!("rewrite: target = {:?}", rewritten);
+                head.rewrite = Some(rewritten);
               }
             }
           }
         }
-      });
+        Some(&rule.group)
+      }
+    };
+    if route_group.is_none() {
+      route_group = self.config.host_group(host);
+    }
+    match route_group.and_then(|g| self.groups.get(g)) {
+      None => {
+        log_info!("no route to host");
+        metrics().route_misses.inc();
+        None
+      }
+      Some(group) => Some(Routed::Backend(group.clone()))
+    }
+  }
+
+  pub fn check_payload(&self, head: &RequestHead) -> Result<(), u16> {
+    if head.payload_len <= 0 {
+      log_info!("no payload");
+    } else {
+      log_info!("payload len={}", head.payload_len);
+    }
+    if head.payload_len > MAX_PAYLOAD {
+      log_info!("payload too large");
+      return Err(400);
+    }
+    Ok(())
+  }
+
+  // Submits the request to a member of `group`; the client waits for the
+  // reply until `deadline`.
+  pub fn dispatch(&self, head: RequestHead, buf: &[u8], group: &BackendGroup, reply: BackendReply, deadline: Instant) -> Result<(), u16> {
+    let retryable = self.config.backend.retry.allows(
+        head.req.method.as_ref(),
+        raw_header(&buf[ .. head.header_len], "idempotency-key").is_some(),
+    );
+    let mut req = head.req;
+    if let Some(target) = head.rewrite.as_ref() {
+      let first_line = buf.split(|&x| x == b'\r').next().unwrap_or(b"");
+      let version = first_line.rsplit(|&x| x == b' ').next().unwrap_or(b"HTTP/1.1");
+      let mut line = Vec::new();
+      line.extend_from_slice(head.method.as_bytes());
+      line.push(b' ');
+      line.extend_from_slice(target.as_bytes());
+      line.push(b' ');
+      line.extend_from_slice(version);
+      line.extend_from_slice(b"\r\n");
+      let mut parser = http1::RequestParser::new(line.iter().map(|&x| x));
+      if let Err(e) = parser.parse_first_line(&self.base_url, &mut req) {
+        log_info!("rewrite: invalid target = {:?}: {:?}", target, e);
+        return Err(400);
+      }
+    }
+    // NB: the backend sees exactly one id, the one the gateway logs.
+    let traced = head.trace.is_some();
+    req.headers.retain(|h| match h.name.as_ref() {
+      Err(name) => {
+        !name.eq_ignore_ascii_case(crate::request_id::HEADER.as_bytes()) &&
+        !(traced && (name.eq_ignore_ascii_case(TRACEPARENT.as_bytes()) ||
+                     name.eq_ignore_ascii_case(TRACESTATE.as_bytes())))
+      }
+      Ok(_) => true
+    });
+    req.push_header_raw(crate::request_id::HEADER, head.request_id.as_bytes());
+    // NB: the backend span is the parent of whatever the backend records.
+    if let Some(trace) = head.trace.as_ref() {
+      req.push_header_raw(TRACEPARENT, trace.backend_traceparent().as_bytes());
+      if let Some(tracestate) = trace.ctx.tracestate.as_ref() {
+        req.push_header_raw(TRACESTATE, tracestate.as_bytes());
+      }
+    }
+    req.set_payload(&buf[head.header_len .. head.header_len + head.payload_len]);
+    let req = match HttpRequest::try_from_raw_strip_headers(req) {
+      Err(_) => {
+        log_info!("request conversion failure");
+        return Err(400);
+      }
+      Ok((req, _)) => req
+    };
+    let key = PickKey{
+      client_ip: head.client_ip,
+      cookie: head.cookie.as_ref().map(|v| &v[ .. ]),
+    };
+    let backend = match group.pick(&key) {
+      None => {
+        log_info!("no backend available in group = {:?}", group.name());
+        return Err(503);
+      }
+      Some(backend) => backend
+    };
+    log_info!("route to backend = {} (group = {:?})", backend.addr(), group.name());
+    let job = BackendJob{
+      t0: get_time_coarse(),
+      queued: Instant::now(),
+      deadline,
+      req,
+      reply,
+      retryable,
+      attempts: 0,
+      log_ctx: crate::log::context(),
+    };
+    match backend.submit(job) {
+      Ok(_) => {}
+      Err(_) => {
+        log_info!("backend: queue full: depth = {}", backend.queue_len());
+        return Err(503);
+      }
     }
+    Ok(())
   }
+
+  pub fn complete(&self, err_reply: &ErrorReply, outcome: BackendOutcome, keep_alive: bool, entry: &mut AccessEntry) -> Vec<u8> {
+    entry.set_backend(outcome.member, outcome.timing);
+    match outcome.res {
+      Err(status) => {
+        log_info!("backend: error: status = {}", status);
+        err_reply.encode(&self.errpages, status)
+      }
+      Ok(None) => {
+        log_info!("no match");
+        err_reply.encode(&self.errpages, 404)
+      }
+      Ok(Some(rep)) => {
+        log_info!("matched response");
+        let mut rep = rep.to_raw();
+        rep.push_header_raw(crate::request_id::HEADER, &err_reply.request_id);
+        rep.push_header(http1::HeaderName::StrictTransportSecurity, "max-age=63072000");
+        rep.push_header(http1::HeaderName::ContentSecurityPolicy, "default-src 'none'; script-src 'self'; style-src 'self'; connect-src 'self'; form-action 'self'; img-src 'self'; frame-ancestors 'self'; base-uri 'none'");
+        rep.push_header(http1::HeaderName::XContentTypeOptions, "nosniff");
+        rep.push_header(http1::HeaderName::XFrameOptions, "SAMEORIGIN");
+        if !keep_alive {
+          rep.push_header(http1::HeaderName::Connection, "close");
+        }
+        let mut buf = Vec::new();
+        rep.encode(&mut buf).unwrap();
+        buf
+      }
+    }
+  }
+
+  pub fn finish_request(&self, mut entry: AccessEntry, out: &[u8], written: usize) {
+    entry.finish(out, written);
+    let m = metrics();
+    m.requests.with(format!("{}", entry.status)).inc();
+    if let Some(total) = entry.total {
+      m.request_duration.observe(total);
+    }
+    if let (Some(member), Some(upstream)) = (entry.member.as_ref(), entry.upstream) {
+      m.upstream_duration.with(member).observe(upstream);
+    }
+    if let Some(access_log) = self.access_log.as_ref() {
+      access_log.write(&entry);
+    }
+    if let Some(trace) = entry.trace.as_ref() {
+      let mut attrs = vec![
+        ("http.request.method", entry.method.clone()),
+        ("url.path", entry.target.split('?').next().unwrap_or("").into()),
+        ("http.response.status_code", format!("{}", entry.status).into()),
+        ("http.request.header.x-request-id", entry.request_id.clone()),
+      ];
+      if let Some(host) = entry.host.as_ref() {
+        attrs.push(("server.address", host.clone()));
+      }
+      if let Some(client_ip) = entry.client_ip {
+        attrs.push(("client.address", format!("{}", client_ip).into()));
+      }
+      trace.export(Instant::now(), attrs, entry.status, entry.member.as_ref().map(|m| m.as_str()));
+    }
+  }
+}
+
+fn write_reply443<W: Write>(gw: &Gateway443, stream: &mut W, out: &[u8], mut entry: AccessEntry) {
+  entry.begin_write();
+  match stream.write_all(out).and_then(|_| stream.flush()) {
+    Err(e) => {
+      log_info!("write error: {:?}", e);
+      gw.finish_request(entry, out, 0);
+    }
+    Ok(_) => {
+      log_info!("write done");
+      gw.finish_request(entry, out, out.len());
+    }
+  }
+}
+
+fn set_stream_timeouts(stream: &TcpStream, timeout: StdDuration) {
+  if let Err(e) = stream.set_read_timeout(Some(timeout)) {
+    log_info!("set read timeout: {:?}", e);
+  }
+  if let Err(e) = stream.set_write_timeout(Some(timeout)) {
+    log_info!("set write timeout: {:?}", e);
+  }
+}
+
+pub fn shed_reply443(gw: &Gateway443, stream: TcpStream, seq_nr: u64) {
+  let _log = crate::log::scope(LogCtx{conn: Some(seq_nr), req: None, request_id: None});
+  set_stream_timeouts(&stream, gw.config.pool.shed_timeout);
+  let mut stream = match gw.tls_acceptor().accept(stream) {
+    Err(e) => {
+      log_info!("tls: failed to accept: {:?}", e);
+      metrics().tls_handshake_failures.inc();
+      return;
+    }
+    Ok(stream) => stream
+  };
+  let err_reply = ErrorReply{
+    accept: None,
+    request_id: crate::request_id::generate().to_string(),
+    host: None,
+  };
+  err_reply.write(&gw.errpages, &mut stream, 503);
+}
+
+pub fn serve443(gw: &Gateway443, stream: TcpStream, seq_nr: u64) {
+  let _log = crate::log::scope(LogCtx{conn: Some(seq_nr), req: Some(1), request_id: None});
+  set_stream_timeouts(&stream, gw.config.pool.conn_timeout);
+  let t_tls = Instant::now();
+  let mut stream = HelloStream::new(stream);
+  let hello = match stream.read_hello() {
+    Err(e) => {
+      log_info!("tls: failed to read ClientHello: {:?}", e);
+      return;
+    }
+    Ok(None) => {
+      log_info!("tls: timed out reading ClientHello");
+      return;
+    }
+    Ok(Some(hello)) => hello
+  };
+  let mut stream = match gw.tls_acceptor().accept(stream) {
+    Err(e) => {
+      log_info!("tls: failed to accept: {:?}", e);
+      metrics().tls_handshake_failures.inc();
+      return;
+    }
+    Ok(stream) => stream
+  };
+  log_info!("tls: accepted");
+  let tls = (t_tls, Instant::now());
+  let rcap = 8192;
+  let mut rbuf = Vec::new();
+  rbuf.resize(rcap, 0);
+  let r_sz = match stream.read(&mut rbuf) {
+    Err(e) => {
+      log_info!("read error: {:?}", e);
+      return;
+    }
+    Ok(r_sz) => r_sz
+  };
+  log_info!("read {} bytes", r_sz);
+  let client_ip = stream.get_ref().get_ref().peer_addr().ok().map(|addr| addr.ip());
+  let mut head = match gw.parse_head(&rbuf[ .. r_sz], client_ip) {
+    Err(_) => return,
+    Ok(head) => head
+  };
+  if let Some(trace) = head.trace.as_mut() {
+    trace.tls = Some(tls);
+  }
+  log_info!("request: {}", gw.config.redact.request(&rbuf[ .. r_sz], head.route_host.as_ref().map(|s| s.as_str())));
+  gw.conn_request(seq_nr, &head);
+  crate::log::set_context(LogCtx{conn: Some(seq_nr), req: Some(1), request_id: Some(head.request_id.clone())});
+  let err_reply = head.err_reply();
+  let mut entry = AccessEntry::new(&head);
+  entry.set_tls(&hello);
+  let group = match gw.route(&mut head, &rbuf[ .. r_sz]) {
+    None => {
+      let out = err_reply.encode(&gw.errpages, 404);
+      write_reply443(gw, &mut stream, &out, entry);
+      return;
+    }
+    Some(Routed::Redirect(status, location)) => {
+      let out = err_reply.encode_redirect(status, &location);
+      write_reply443(gw, &mut stream, &out, entry);
+      return;
+    }
+    Some(Routed::Backend(group)) => group
+  };
+  if let Err(status) = gw.check_payload(&head) {
+    let out = err_reply.encode(&gw.errpages, status);
+    write_reply443(gw, &mut stream, &out, entry);
+    return;
+  }
+  if r_sz < head.total_len() {
+    rbuf.resize(max(rcap, head.total_len()), 0);
+    if let Err(e) = stream.read_exact(&mut rbuf[r_sz .. head.total_len()]) {
+      log_info!("payload read error: {:?}", e);
+      let out = err_reply.encode(&gw.errpages, 400);
+      write_reply443(gw, &mut stream, &out, entry);
+      return;
+    }
+  }
+  let (reply, front_rx) = BackendReply::new(None);
+  let timeout = StdDuration::from_secs(2);
+  if let Err(status) = gw.dispatch(head, &rbuf, &group, reply, Instant::now() + timeout) {
+    let out = err_reply.encode(&gw.errpages, status);
+    write_reply443(gw, &mut stream, &out, entry);
+    return;
+  }
+  let outcome = match front_rx.recv_timeout(timeout) {
+    Err(RecvTimeoutError::Timeout) => {
+      log_info!("backend: timed out");
+      BackendOutcome::failed(504)
+    }
+    Err(RecvTimeoutError::Disconnected) => {
+      log_info!("backend: recv error");
+      BackendOutcome::failed(502)
+    }
+    Ok(outcome) => outcome
+  };
+  let out = gw.complete(&err_reply, outcome, false, &mut entry);
+  write_reply443(gw, &mut stream, &out, entry);
 }
@@ -0,0 +1,250 @@
+#![forbid(unsafe_code)]
+
+use smol_str::{SmolStr};
+use time::{get_time_usec};
+
+use std::cell::{RefCell};
+use std::collections::{BTreeMap};
+use std::fmt::{Arguments, Write as FmtWrite};
+use std::io::{Write};
+use std::mem::{replace, take};
+use std::sync::{RwLock};
+use std::sync::atomic::{AtomicU8, Ordering};
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum Level {
+  Error = 1,
+  Warn = 2,
+  Info = 3,
+  Debug = 4,
+  Trace = 5,
+}
+
+impl Level {
+  pub fn as_str(&self) -> &'static str {
+    match self {
+      &Level::Error => "ERROR",
+      &Level::Warn => "WARN",
+      &Level::Info => "INFO",
+      &Level::Debug => "DEBUG",
+      &Level::Trace => "TRACE",
+    }
+  }
+
+  pub fn parse(s: &str) -> Option<Level> {
+    match &s.to_ascii_lowercase()[ .. ] {
+      "error" => Some(Level::Error),
+      "warn" => Some(Level::Warn),
+      "info" => Some(Level::Info),
+      "debug" => Some(Level::Debug),
+      "trace" => Some(Level::Trace),
+      _ => None
+    }
+  }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum LogFormat {
+  Text,
+  // One JSON object per line.
+  Json,
+}
+
+#[derive(Clone, Debug)]
+pub struct LogConfig {
+  pub level: Level,
+  // Per-module overrides, keyed by module path relative to the crate root
+  // (e.g. `backend` or `engine`).
+  pub modules: BTreeMap<SmolStr, Level>,
+  pub format: LogFormat,
+}
+
+impl Default for LogConfig {
+  fn default() -> LogConfig {
+    LogConfig{
+      level: Level::Info,
+      modules: BTreeMap::new(),
+      format: LogFormat::Text,
+    }
+  }
+}
+
+struct LogState {
+  level: Level,
+  modules: BTreeMap<SmolStr, Level>,
+  format: LogFormat,
+}
+
+static STATE: RwLock<LogState> = RwLock::new(LogState{
+  level: Level::Info,
+  modules: BTreeMap::new(),
+  format: LogFormat::Text,
+});
+
+// NB: the most verbose level enabled for any module, so that disabled lines
+// skip the lock.
+static MAX_LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+
+pub fn init(cfg: &LogConfig) {
+  let max_level = cfg.modules.values().fold(cfg.level, |max, &level| max.max(level));
+  let mut state = STATE.write().unwrap();
+  state.level = cfg.level;
+  state.modules = cfg.modules.clone();
+  state.format = cfg.format;
+  MAX_LEVEL.store(max_level as u8, Ordering::Release);
+}
+
+// Identifies the connection and the request on it that a log line belongs to.
+#[derive(Clone, Default, PartialEq, Eq, Debug)]
+pub struct LogCtx {
+  pub conn: Option<u64>,
+  pub req: Option<u64>,
+  pub request_id: Option<SmolStr>,
+}
+
+thread_local! {
+  static CTX: RefCell<LogCtx> = RefCell::new(LogCtx::default());
+}
+
+pub fn context() -> LogCtx {
+  CTX.with(|c| c.borrow().clone())
+}
+
+pub fn set_context(ctx: LogCtx) {
+  CTX.with(|c| *c.borrow_mut() = ctx);
+}
+
+// Sets the context of the current thread until the guard is dropped.
+pub fn scope(ctx: LogCtx) -> CtxGuard {
+  let prev = CTX.with(|c| replace(&mut *c.borrow_mut(), ctx));
+  CtxGuard{prev}
+}
+
+pub struct CtxGuard {
+  prev: LogCtx,
+}
+
+impl Drop for CtxGuard {
+  fn drop(&mut self) {
+    let prev = take(&mut self.prev);
+    CTX.with(|c| *c.borrow_mut() = prev);
+  }
+}
+
+// NB: the crate root keeps its full name.
+fn module_name(path: &'static str) -> &'static str {
+  match path.find("::") {
+    None => path,
+    Some(pos) => &path[pos + 2 .. ]
+  }
+}
+
+pub fn enabled(level: Level, module_path: &'static str) -> bool {
+  if level as u8 > MAX_LEVEL.load(Ordering::Acquire) {
+    return false;
+  }
+  let state = STATE.read().unwrap();
+  let module = module_name(module_path);
+  let mut max = state.level;
+  let mut best = 0;
+  for (prefix, &level) in state.modules.iter() {
+    let matches = module == prefix.as_str() ||
+        (module.starts_with(prefix.as_str()) && module[prefix.len() .. ].starts_with("::"));
+    if matches && prefix.len() >= best {
+      best = prefix.len();
+      max = level;
+    }
+  }
+  level <= max
+}
+
+pub fn log(level: Level, module_path: &'static str, args: Arguments) {
+  let format = STATE.read().unwrap().format;
+  let ts = get_time_usec().utc().rfc3339_nsec();
+  let module = module_name(module_path);
+  let ctx = context();
+  let mut line = String::new();
+  match format {
+    LogFormat::Text => {
+      let _ = write!(&mut line, "{} {:<5} [{}]", ts, level.as_str(), module);
+      match (ctx.conn, ctx.req) {
+        (Some(conn), Some(req)) => { let _ = write!(&mut line, " [{}.{}]", conn, req); }
+        (Some(conn), None) => { let _ = write!(&mut line, " [{}]", conn); }
+        _ => {}
+      }
+      if let Some(request_id) = ctx.request_id.as_ref() {
+        let _ = write!(&mut line, " [{}]", request_id);
+      }
+      let _ = write!(&mut line, " {}", args);
+    }
+    LogFormat::Json => {
+      let _ = write!(&mut line, "{{\"ts\":\"{}\",\"level\":\"{}\",\"module\":\"{}\"", ts, level.as_str(), module);
+      if let Some(conn) = ctx.conn {
+        let _ = write!(&mut line, ",\"conn\":{}", conn);
+      }
+      if let Some(req) = ctx.req {
+        let _ = write!(&mut line, ",\"req\":{}", req);
+      }
+      if let Some(request_id) = ctx.request_id.as_ref() {
+        line.push_str(",\"request_id\":\"");
+        json_escape_into(&mut line, request_id);
+        line.push('"');
+      }
+      line.push_str(",\"msg\":\"");
+      json_escape_into(&mut line, &format!("{}", args));
+      line.push_str("\"}");
+    }
+  }
+  line.push('\n');
+  let stdout = std::io::stdout();
+  let mut out = stdout.lock();
+  let _ = out.write_all(line.as_bytes());
+}
+
+pub fn json_escape_into(buf: &mut String, s: &str) {
+  for c in s.chars() {
+    match c {
+      '"' => buf.push_str("\\\""),
+      '\\' => buf.push_str("\\\\"),
+      '\n' => buf.push_str("\\n"),
+      '\r' => buf.push_str("\\r"),
+      '\t' => buf.push_str("\\t"),
+      c if (c as u32) < 0x20 => { let _ = write!(buf, "\\u{:04x}", c as u32); }
+      c => buf.push(c),
+    }
+  }
+}
+
+#[macro_export]
+macro_rules! log_at {
+  ($level:expr, $($arg:tt)+) => {
+    if $crate::log::enabled($level, module_path!()) {
+      $crate::log::log($level, module_path!(), format_args!($($arg)+));
+    }
+  };
+}
+
+#[macro_export]
+macro_rules! log_error {
+  ($($arg:tt)+) => { $crate::log_at!($crate::log::Level::Error, $($arg)+) };
+}
+
+#[macro_export]
+macro_rules! log_warn {
+  ($($arg:tt)+) => { $crate::log_at!($crate::log::Level::Warn, $($arg)+) };
+}
+
+#[macro_export]
+macro_rules! log_info {
+  ($($arg:tt)+) => { $crate::log_at!($crate::log::Level::Info, $($arg)+) };
+}
+
+#[macro_export]
+macro_rules! log_debug {
+  ($($arg:tt)+) => { $crate::log_at!($crate::log::Level::Debug, $($arg)+) };
+}
+
+#[macro_export]
+macro_rules! log_trace {
+  ($($arg:tt)+) => { $crate::log_at!($crate::log::Level::Trace, $($arg)+) };
+}
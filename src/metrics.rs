@@ -0,0 +1,345 @@
+#![forbid(unsafe_code)]
+
+use smol_str::{SmolStr};
+
+use std::collections::{BTreeMap};
+use std::fmt::{Write as FmtWrite};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::time::{Duration as StdDuration};
+
+pub struct Counter {
+  value: AtomicU64,
+}
+
+impl Default for Counter {
+  fn default() -> Counter {
+    Counter::new()
+  }
+}
+
+impl Counter {
+  pub const fn new() -> Counter {
+    Counter{value: AtomicU64::new(0)}
+  }
+
+  pub fn inc(&self) {
+    self.add(1);
+  }
+
+  pub fn add(&self, n: u64) {
+    self.value.fetch_add(n, Ordering::Relaxed);
+  }
+
+  pub fn get(&self) -> u64 {
+    self.value.load(Ordering::Relaxed)
+  }
+}
+
+pub struct Gauge {
+  value: AtomicI64,
+}
+
+impl Default for Gauge {
+  fn default() -> Gauge {
+    Gauge::new()
+  }
+}
+
+impl Gauge {
+  pub const fn new() -> Gauge {
+    Gauge{value: AtomicI64::new(0)}
+  }
+
+  pub fn set(&self, v: i64) {
+    self.value.store(v, Ordering::Relaxed);
+  }
+
+  pub fn inc(&self) {
+    self.value.fetch_add(1, Ordering::Relaxed);
+  }
+
+  pub fn dec(&self) {
+    self.value.fetch_sub(1, Ordering::Relaxed);
+  }
+
+  pub fn get(&self) -> i64 {
+    self.value.load(Ordering::Relaxed)
+  }
+}
+
+// Upper bounds in seconds; the implicit last bucket is `+Inf`.
+pub const LATENCY_BUCKETS: [f64; 12] = [0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0];
+
+pub struct Histogram {
+  buckets: [AtomicU64; 12],
+  count: AtomicU64,
+  sum_us: AtomicU64,
+}
+
+impl Default for Histogram {
+  fn default() -> Histogram {
+    Histogram::new()
+  }
+}
+
+impl Histogram {
+  pub const fn new() -> Histogram {
+    const ZERO: AtomicU64 = AtomicU64::new(0);
+    Histogram{
+      buckets: [ZERO; 12],
+      count: AtomicU64::new(0),
+      sum_us: AtomicU64::new(0),
+    }
+  }
+
+  pub fn observe(&self, d: StdDuration) {
+    let secs = d.as_secs_f64();
+    // NB: buckets are stored non-cumulative and summed when rendered.
+    if let Some(i) = LATENCY_BUCKETS.iter().position(|&le| secs <= le) {
+      self.buckets[i].fetch_add(1, Ordering::Relaxed);
+    }
+    self.count.fetch_add(1, Ordering::Relaxed);
+    self.sum_us.fetch_add(d.as_micros() as u64, Ordering::Relaxed);
+  }
+}
+
+// A metric with a single label, e.g. a counter per backend member.
+pub struct Family<M> {
+  label: &'static str,
+  metrics: Mutex<BTreeMap<SmolStr, Arc<M>>>,
+}
+
+impl<M: Default> Family<M> {
+  pub const fn new(label: &'static str) -> Family<M> {
+    Family{label, metrics: Mutex::new(BTreeMap::new())}
+  }
+
+  pub fn with<S: AsRef<str>>(&self, value: S) -> Arc<M> {
+    let value = value.as_ref();
+    let mut metrics = self.metrics.lock().unwrap();
+    match metrics.get(value) {
+      Some(m) => m.clone(),
+      None => {
+        let m = Arc::new(M::default());
+        metrics.insert(value.into(), m.clone());
+        m
+      }
+    }
+  }
+
+  fn snapshot(&self) -> Vec<(SmolStr, Arc<M>)> {
+    self.metrics.lock().unwrap().iter().map(|(k, m)| (k.clone(), m.clone())).collect()
+  }
+}
+
+pub struct Metrics {
+  pub connections_accepted: Counter,
+  pub connections_shed: Counter,
+  pub connections_active: Gauge,
+  pub tls_handshake_failures: Counter,
+  pub request_parse_errors: Counter,
+  pub route_misses: Counter,
+  pub requests: Family<Counter>,
+  pub request_duration: Histogram,
+  pub upstream_duration: Family<Histogram>,
+  pub backend_connects: Family<Counter>,
+  pub backend_connect_failures: Family<Counter>,
+  pub backend_disconnects: Family<Counter>,
+  pub backend_retries: Family<Counter>,
+  pub acme_renewals: Family<Counter>,
+}
+
+static METRICS: Metrics = Metrics{
+  connections_accepted: Counter::new(),
+  connections_shed: Counter::new(),
+  connections_active: Gauge::new(),
+  tls_handshake_failures: Counter::new(),
+  request_parse_errors: Counter::new(),
+  route_misses: Counter::new(),
+  requests: Family::new("status"),
+  request_duration: Histogram::new(),
+  upstream_duration: Family::new("member"),
+  backend_connects: Family::new("member"),
+  backend_connect_failures: Family::new("member"),
+  backend_disconnects: Family::new("member"),
+  backend_retries: Family::new("member"),
+  acme_renewals: Family::new("result"),
+};
+
+pub fn metrics() -> &'static Metrics {
+  &METRICS
+}
+
+// Renders metrics in the Prometheus text exposition format (version 0.0.4).
+pub struct Encoder {
+  buf: String,
+}
+
+impl Encoder {
+  pub fn new() -> Encoder {
+    Encoder{buf: String::new()}
+  }
+
+  pub fn finish(self) -> String {
+    self.buf
+  }
+
+  fn header(&mut self, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(&mut self.buf, "# HELP {} {}", name, help);
+    let _ = writeln!(&mut self.buf, "# TYPE {} {}", name, kind);
+  }
+
+  fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: f64) {
+    self.buf.push_str(name);
+    if !labels.is_empty() {
+      self.buf.push('{');
+      for (i, &(k, v)) in labels.iter().enumerate() {
+        if i > 0 {
+          self.buf.push(',');
+        }
+        let _ = write!(&mut self.buf, "{}=\"", k);
+        for c in v.chars() {
+          match c {
+            '\\' => self.buf.push_str("\\\\"),
+            '"' => self.buf.push_str("\\\""),
+            '\n' => self.buf.push_str("\\n"),
+            c => self.buf.push(c),
+          }
+        }
+        self.buf.push('"');
+      }
+      self.buf.push('}');
+    }
+    let _ = writeln!(&mut self.buf, " {}", value);
+  }
+
+  pub fn counter(&mut self, name: &str, help: &str, c: &Counter) {
+    self.header(name, "counter", help);
+    self.sample(name, &[], c.get() as f64);
+  }
+
+  pub fn gauge(&mut self, name: &str, help: &str, g: &Gauge) {
+    self.header(name, "gauge", help);
+    self.sample(name, &[], g.get() as f64);
+  }
+
+  pub fn counter_family(&mut self, name: &str, help: &str, f: &Family<Counter>) {
+    self.header(name, "counter", help);
+    for (value, c) in f.snapshot() {
+      self.sample(name, &[(f.label, &value)], c.get() as f64);
+    }
+  }
+
+  // Gauges computed at scrape time, one sample per label value.
+  pub fn gauge_values<'a, I: IntoIterator<Item=(&'a str, f64)>>(&mut self, name: &str, help: &str, label: &str, values: I) {
+    self.header(name, "gauge", help);
+    for (value, v) in values {
+      self.sample(name, &[(label, value)], v);
+    }
+  }
+
+  pub fn counter_values<'a, I: IntoIterator<Item=(&'a str, f64)>>(&mut self, name: &str, help: &str, label: &str, values: I) {
+    self.header(name, "counter", help);
+    for (value, v) in values {
+      self.sample(name, &[(label, value)], v);
+    }
+  }
+
+  fn histogram_samples(&mut self, name: &str, labels: &[(&str, &str)], h: &Histogram) {
+    let bucket = format!("{}_bucket", name);
+    let mut cumulative = 0;
+    for (i, le) in LATENCY_BUCKETS.iter().enumerate() {
+      cumulative += h.buckets[i].load(Ordering::Relaxed);
+      let le = format!("{}", le);
+      let mut bucket_labels = labels.to_vec();
+      bucket_labels.push(("le", &le));
+      self.sample(&bucket, &bucket_labels, cumulative as f64);
+    }
+    let count = h.count.load(Ordering::Relaxed);
+    let mut bucket_labels = labels.to_vec();
+    bucket_labels.push(("le", "+Inf"));
+    self.sample(&bucket, &bucket_labels, count as f64);
+    self.sample(&format!("{}_sum", name), labels, h.sum_us.load(Ordering::Relaxed) as f64 / 1e6);
+    self.sample(&format!("{}_count", name), labels, count as f64);
+  }
+
+  pub fn histogram(&mut self, name: &str, help: &str, h: &Histogram) {
+    self.header(name, "histogram", help);
+    self.histogram_samples(name, &[], h);
+  }
+
+  pub fn histogram_family(&mut self, name: &str, help: &str, f: &Family<Histogram>) {
+    self.header(name, "histogram", help);
+    for (value, h) in f.snapshot() {
+      self.histogram_samples(name, &[(f.label, &value)], &h);
+    }
+  }
+}
+
+pub fn encode_registry(enc: &mut Encoder) {
+  let m = metrics();
+  enc.counter("gateway_connections_accepted_total", "Connections accepted on the TLS listener.", &m.connections_accepted);
+  enc.counter("gateway_connections_shed_total", "Connections shed because the engine was at capacity.", &m.connections_shed);
+  enc.gauge("gateway_connections_active", "Connections currently held by the engine.", &m.connections_active);
+  enc.counter("gateway_tls_handshake_failures_total", "Failed TLS handshakes.", &m.tls_handshake_failures);
+  enc.counter("gateway_request_parse_errors_total", "Requests rejected with an unparseable head.", &m.request_parse_errors);
+  enc.counter("gateway_route_misses_total", "Requests with no route to a backend group.", &m.route_misses);
+  enc.counter_family("gateway_requests_total", "Requests answered, by status.", &m.requests);
+  enc.histogram("gateway_request_duration_seconds", "Time from request head to response written.", &m.request_duration);
+  enc.histogram_family("gateway_upstream_duration_seconds", "Time spent in the exchange with a backend member.", &m.upstream_duration);
+  enc.counter_family("gateway_backend_connects_total", "Backend connections established.", &m.backend_connects);
+  enc.counter_family("gateway_backend_connect_failures_total", "Failed backend connection attempts.", &m.backend_connect_failures);
+  enc.counter_family("gateway_backend_disconnects_total", "Backend connections lost while serving a request.", &m.backend_disconnects);
+  enc.counter_family("gateway_backend_retries_total", "Requests retried after a backend failure.", &m.backend_retries);
+  enc.counter_family("gateway_acme_renewals_total", "ACME certificate renewal attempts, by result.", &m.acme_renewals);
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_histogram() {
+    let h = Histogram::new();
+    for &ms in [0, 1, 3, 3, 40, 700, 6000].iter() {
+      h.observe(StdDuration::from_millis(ms));
+    }
+    let mut enc = Encoder::new();
+    enc.histogram("t_seconds", "Test.", &h);
+    let expected = concat!(
+      "# HELP t_seconds Test.\n",
+      "# TYPE t_seconds histogram\n",
+      "t_seconds_bucket{le=\"0.001\"} 2\n",
+      "t_seconds_bucket{le=\"0.0025\"} 2\n",
+      "t_seconds_bucket{le=\"0.005\"} 4\n",
+      "t_seconds_bucket{le=\"0.01\"} 4\n",
+      "t_seconds_bucket{le=\"0.025\"} 4\n",
+      "t_seconds_bucket{le=\"0.05\"} 5\n",
+      "t_seconds_bucket{le=\"0.1\"} 5\n",
+      "t_seconds_bucket{le=\"0.25\"} 5\n",
+      "t_seconds_bucket{le=\"0.5\"} 5\n",
+      "t_seconds_bucket{le=\"1\"} 6\n",
+      "t_seconds_bucket{le=\"2.5\"} 6\n",
+      "t_seconds_bucket{le=\"5\"} 6\n",
+      "t_seconds_bucket{le=\"+Inf\"} 7\n",
+      "t_seconds_sum 6.747\n",
+      "t_seconds_count 7\n",
+    );
+    assert_eq!(enc.finish(), expected);
+  }
+
+  #[test]
+  fn test_histogram_family() {
+    let f: Family<Histogram> = Family::new("member");
+    f.with("a\"b").observe(StdDuration::from_millis(2));
+    let mut enc = Encoder::new();
+    enc.histogram_family("t_seconds", "Test.", &f);
+    let out = enc.finish();
+    assert!(out.contains("t_seconds_bucket{member=\"a\\\"b\",le=\"0.001\"} 0\n"), "{}", out);
+    assert!(out.contains("t_seconds_bucket{member=\"a\\\"b\",le=\"0.0025\"} 1\n"), "{}", out);
+    assert!(out.contains("t_seconds_bucket{member=\"a\\\"b\",le=\"+Inf\"} 1\n"), "{}", out);
+    assert!(out.contains("t_seconds_sum{member=\"a\\\"b\"} 0.002\n"), "{}", out);
+    assert!(out.contains("t_seconds_count{member=\"a\\\"b\"} 1\n"), "{}", out);
+  }
+}
@@ -1,7 +1,12 @@
+#![forbid(unsafe_code)]
+
 use unix2::{FdSet, select};
 
-use std::io::{Error as IoError};
-use std::os::unix::io::{AsRawFd};
+use std::io::{Error as IoError, ErrorKind as IoErrorKind, Read, Write};
+use std::mem::{replace};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::{UnixStream};
+use std::sync::{Mutex};
 use std::time::{Duration as StdDuration};
 
 pub fn select_read_fd_timeout<F: AsRawFd>(fd: &F, timeout: StdDuration) -> Result<Option<()>, IoError> {
@@ -14,3 +19,170 @@ pub fn select_read_fd_timeout<F: AsRawFd>(fd: &F, timeout: StdDuration) -> Resul
   assert!(fd < end_fd);
   select(end_fd, &mut read, &mut write, &mut except, timeout)
 }
+
+pub type Token = u64;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Interest {
+  None,
+  Read,
+  Write,
+  ReadWrite,
+}
+
+impl Interest {
+  fn events(self) -> u32 {
+    let events = match self {
+      Interest::None => return 0,
+      Interest::Read => libc::EPOLLIN,
+      Interest::Write => libc::EPOLLOUT,
+      Interest::ReadWrite => libc::EPOLLIN | libc::EPOLLOUT,
+    };
+    (events | libc::EPOLLRDHUP) as u32
+  }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Event {
+  pub token: Token,
+  pub readable: bool,
+  pub writable: bool,
+  pub hangup: bool,
+}
+
+pub struct Poll {
+  epfd: RawFd,
+  events: Vec<libc::epoll_event>,
+}
+
+impl Drop for Poll {
+  fn drop(&mut self) {
+    let _ = crate::sys::close(self.epfd);
+  }
+}
+
+impl Poll {
+  pub fn new() -> Result<Poll, IoError> {
+    let epfd = crate::sys::epoll_create()?;
+    let events = vec![libc::epoll_event{events: 0, u64: 0}; 1024];
+    Ok(Poll{epfd, events})
+  }
+
+  pub fn register<F: AsRawFd>(&self, fd: &F, token: Token, interest: Interest) -> Result<(), IoError> {
+    crate::sys::epoll_ctl(self.epfd, libc::EPOLL_CTL_ADD, fd.as_raw_fd(), interest.events(), token)
+  }
+
+  pub fn reregister<F: AsRawFd>(&self, fd: &F, token: Token, interest: Interest) -> Result<(), IoError> {
+    crate::sys::epoll_ctl(self.epfd, libc::EPOLL_CTL_MOD, fd.as_raw_fd(), interest.events(), token)
+  }
+
+  pub fn deregister<F: AsRawFd>(&self, fd: &F) -> Result<(), IoError> {
+    crate::sys::epoll_ctl(self.epfd, libc::EPOLL_CTL_DEL, fd.as_raw_fd(), 0, 0)
+  }
+
+  pub fn wait(&mut self, out: &mut Vec<Event>, timeout: Option<StdDuration>) -> Result<(), IoError> {
+    let timeout_ms = match timeout {
+      None => -1,
+      Some(t) => t.as_millis().min(libc::c_int::max_value() as u128) as libc::c_int
+    };
+    out.clear();
+    let n = crate::sys::epoll_wait(self.epfd, &mut self.events, timeout_ms)?;
+    for ev in self.events[ .. n].iter() {
+      let events = ev.events as libc::c_int;
+      out.push(Event{
+        token: ev.u64,
+        readable: events & (libc::EPOLLIN | libc::EPOLLPRI) != 0,
+        writable: events & libc::EPOLLOUT != 0,
+        hangup: events & (libc::EPOLLHUP | libc::EPOLLRDHUP | libc::EPOLLERR) != 0,
+      });
+    }
+    Ok(())
+  }
+}
+
+pub fn poll_read_fd_timeout<F: AsRawFd>(poll: &mut Poll, fd: &F, timeout: StdDuration) -> Result<Option<()>, IoError> {
+  let mut events = Vec::with_capacity(1);
+  poll.wait(&mut events, Some(timeout))?;
+  for ev in events.iter() {
+    if ev.token == fd.as_raw_fd() as Token && (ev.readable || ev.hangup) {
+      return Ok(Some(()));
+    }
+  }
+  Ok(None)
+}
+
+pub struct Waker {
+  tx: UnixStream,
+  rx: UnixStream,
+}
+
+impl Waker {
+  pub fn new() -> Result<Waker, IoError> {
+    let (tx, rx) = UnixStream::pair()?;
+    tx.set_nonblocking(true)?;
+    rx.set_nonblocking(true)?;
+    Ok(Waker{tx, rx})
+  }
+
+  pub fn wake(&self) -> Result<(), IoError> {
+    match (&self.tx).write(&[1]) {
+      Ok(_) => Ok(()),
+      // NB: a full socket buffer means a wakeup is already pending.
+      Err(ref e) if e.kind() == IoErrorKind::WouldBlock => Ok(()),
+      Err(e) => Err(e)
+    }
+  }
+
+  pub fn drain(&self) {
+    let mut buf = [0; 64];
+    loop {
+      match (&self.rx).read(&mut buf) {
+        Ok(0) | Err(_) => break,
+        Ok(_) => {}
+      }
+    }
+  }
+}
+
+impl AsRawFd for Waker {
+  fn as_raw_fd(&self) -> RawFd {
+    self.rx.as_raw_fd()
+  }
+}
+
+pub struct Notify {
+  waker: Waker,
+  ready: Mutex<Vec<Token>>,
+}
+
+impl Notify {
+  pub fn new() -> Result<Notify, IoError> {
+    Ok(Notify{
+      waker: Waker::new()?,
+      ready: Mutex::new(Vec::new()),
+    })
+  }
+
+  pub fn wake(&self) -> Result<(), IoError> {
+    self.waker.wake()
+  }
+
+  pub fn notify(&self, token: Token) {
+    self.ready.lock().unwrap().push(token);
+    let _ = self.waker.wake();
+  }
+
+  pub fn drain(&self) {
+    self.waker.drain()
+  }
+
+  pub fn take(&self) -> Vec<Token> {
+    replace(&mut *self.ready.lock().unwrap(), Vec::new())
+  }
+}
+
+impl AsRawFd for Notify {
+  fn as_raw_fd(&self) -> RawFd {
+    self.waker.as_raw_fd()
+  }
+}
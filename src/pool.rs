@@ -0,0 +1,141 @@
+#![forbid(unsafe_code)]
+
+use std::panic::{AssertUnwindSafe, catch_unwind};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{SyncSender, Receiver, TrySendError, sync_channel};
+use std::thread::{Builder as ThreadBuilder, JoinHandle};
+use std::time::{Duration as StdDuration};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Overload {
+  // Reply with a 503 from a dedicated shedder thread.
+  Reply,
+  // Close the TCP connection without a TLS handshake.
+  Close,
+}
+
+#[derive(Clone, Debug)]
+pub struct PoolConfig {
+  pub workers: usize,
+  pub queue_cap: usize,
+  pub overload: Overload,
+  pub conn_timeout: StdDuration,
+  pub shed_timeout: StdDuration,
+}
+
+impl Default for PoolConfig {
+  fn default() -> PoolConfig {
+    PoolConfig{
+      workers: 64,
+      queue_cap: 256,
+      overload: Overload::Reply,
+      conn_timeout: StdDuration::from_secs(5),
+      shed_timeout: StdDuration::from_secs(1),
+    }
+  }
+}
+
+pub struct WorkerPool<T> {
+  tx: SyncSender<T>,
+  workers: Vec<JoinHandle<()>>,
+  pending: Arc<AtomicUsize>,
+}
+
+impl<T: Send + 'static> WorkerPool<T> {
+  pub fn new<F: Fn(T) + Send + Sync + 'static>(name: &str, nworkers: usize, queue_cap: usize, handler: F) -> WorkerPool<T> {
+    assert!(nworkers > 0);
+    let (tx, rx) = sync_channel::<T>(queue_cap);
+    let rx = Arc::new(Mutex::new(rx));
+    let handler = Arc::new(handler);
+    let pending = Arc::new(AtomicUsize::new(0));
+    let mut workers = Vec::with_capacity(nworkers);
+    for rank in 0 .. nworkers {
+      let rx = rx.clone();
+      let handler = handler.clone();
+      let pending = pending.clone();
+      let name = format!("{}-{}", name, rank);
+      let h = ThreadBuilder::new()
+        .name(name.clone())
+        .spawn(move || worker_loop(&name, &*rx, &*pending, &*handler))
+        .unwrap();
+      workers.push(h);
+    }
+    log_info!("pool: {}: workers = {} queue cap = {}", name, nworkers, queue_cap);
+    WorkerPool{tx, workers, pending}
+  }
+
+  pub fn num_workers(&self) -> usize {
+    self.workers.len()
+  }
+
+  // NB: jobs queued or being handled.
+  pub fn num_pending(&self) -> usize {
+    self.pending.load(Ordering::Acquire)
+  }
+
+  pub fn try_submit(&self, job: T) -> Result<(), T> {
+    self.pending.fetch_add(1, Ordering::AcqRel);
+    match self.tx.try_send(job) {
+      Ok(_) => Ok(()),
+      Err(TrySendError::Full(job)) |
+      Err(TrySendError::Disconnected(job)) => {
+        self.pending.fetch_sub(1, Ordering::AcqRel);
+        Err(job)
+      }
+    }
+  }
+}
+
+// Counts a job as done when dropped, also when its handler panics.
+struct PendingGuard<'a>(&'a AtomicUsize);
+
+impl<'a> Drop for PendingGuard<'a> {
+  fn drop(&mut self) {
+    self.0.fetch_sub(1, Ordering::AcqRel);
+  }
+}
+
+fn worker_loop<T, F: Fn(T)>(name: &str, rx: &Mutex<Receiver<T>>, pending: &AtomicUsize, handler: &F) {
+  loop {
+    let job = match rx.lock().unwrap().recv() {
+      Err(_) => break,
+      Ok(job) => job
+    };
+    let _pending = PendingGuard(pending);
+    // NB: a panicking handler loses its job, but not the worker.
+    if catch_unwind(AssertUnwindSafe(|| handler(job))).is_err() {
+      log_error!("pool: {}: handler panicked", name);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use std::sync::mpsc::{channel};
+
+  #[test]
+  fn test_handler_panic() {
+    let (tx, rx) = channel();
+    let tx = Mutex::new(tx);
+    let pool = WorkerPool::new("test", 1, 4, move |n: u32| {
+      if n == 0 {
+        panic!("job {}", n);
+      }
+      tx.lock().unwrap().send(n).unwrap();
+    });
+    pool.try_submit(0).unwrap();
+    pool.try_submit(1).unwrap();
+    // NB: the same worker survives the panic and handles the next job.
+    assert_eq!(rx.recv_timeout(StdDuration::from_secs(5)).unwrap(), 1);
+    for _ in 0 .. 100 {
+      if pool.num_pending() == 0 {
+        break;
+      }
+      std::thread::sleep(StdDuration::from_millis(10));
+    }
+    assert_eq!(pool.num_pending(), 0);
+  }
+}
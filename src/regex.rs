@@ -0,0 +1,735 @@
+#![forbid(unsafe_code)]
+
+// A small regular expression engine for route, host and rewrite patterns.
+//
+// Supported: literals and escapes, `.`, classes (`[a-z]`, `[^/]`, `\d`,
+// `\w`, `\s` and their negations), `^` and `$`, capturing `( )` and
+// non-capturing `(?: )` groups, `|`, and the quantifiers `*`, `+`, `?`,
+// `{m}`, `{m,}`, `{m,n}` with lazy variants. A leading `(?i)` makes the
+// whole pattern case-insensitive. Matching is on bytes; non-ASCII bytes
+// only match themselves.
+//
+// NB: matching is a bounded backtracker (each instruction is tried at most
+// once per input position), so patterns from config cannot blow up on
+// hostile input.
+
+use smol_str::{SmolStr};
+
+use std::fmt;
+
+// Caps the size of the compiled program, mostly against large counted
+// repetitions like `(a{100}){100}`.
+pub const MAX_INSTS: usize = 4096;
+pub const MAX_REPEAT: u32 = 256;
+// Caps the memory of a single match, which tracks `prog.len() *
+// (text.len() + 1)` visited states, at 1 MiB; longer texts do not match.
+pub const MAX_VISITED_BITS: usize = 8 << 20;
+
+#[derive(Clone, Debug)]
+pub enum RegexErr {
+  Syntax(usize, &'static str),
+  TooLarge,
+}
+
+impl fmt::Display for RegexErr {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      &RegexErr::Syntax(pos, msg) => write!(f, "{} at offset {}", msg, pos),
+      &RegexErr::TooLarge => write!(f, "pattern too large"),
+    }
+  }
+}
+
+type ByteSet = [u64; 4];
+
+fn set_insert(set: &mut ByteSet, x: u8) {
+  set[(x >> 6) as usize] |= 1 << (x & 63);
+}
+
+fn set_contains(set: &ByteSet, x: u8) -> bool {
+  set[(x >> 6) as usize] & (1 << (x & 63)) != 0
+}
+
+fn set_range(set: &mut ByteSet, lo: u8, hi: u8) {
+  for x in lo ..= hi {
+    set_insert(set, x);
+  }
+}
+
+fn set_negate(set: &mut ByteSet) {
+  for w in set.iter_mut() {
+    *w = !*w;
+  }
+}
+
+fn set_fold_case(set: &mut ByteSet) {
+  for x in b'a' ..= b'z' {
+    if set_contains(set, x) || set_contains(set, x.to_ascii_uppercase()) {
+      set_insert(set, x);
+      set_insert(set, x.to_ascii_uppercase());
+    }
+  }
+}
+
+#[derive(Clone, Debug)]
+enum Node {
+  Empty,
+  Byte(u8),
+  Any,
+  Set(ByteSet),
+  Start,
+  End,
+  Group(Box<Node>, Option<usize>),
+  Concat(Vec<Node>),
+  Alt(Vec<Node>),
+  Repeat(Box<Node>, u32, Option<u32>, bool),
+}
+
+struct Parser<'a> {
+  pat: &'a [u8],
+  pos: usize,
+  ngroups: usize,
+  icase: bool,
+}
+
+impl<'a> Parser<'a> {
+  fn err<T>(&self, msg: &'static str) -> Result<T, RegexErr> {
+    Err(RegexErr::Syntax(self.pos, msg))
+  }
+
+  fn peek(&self) -> Option<u8> {
+    self.pat.get(self.pos).map(|&x| x)
+  }
+
+  fn eat(&mut self, x: u8) -> bool {
+    if self.peek() == Some(x) {
+      self.pos += 1;
+      true
+    } else {
+      false
+    }
+  }
+
+  fn parse_alt(&mut self) -> Result<Node, RegexErr> {
+    let mut alts = vec![self.parse_concat()?];
+    while self.eat(b'|') {
+      alts.push(self.parse_concat()?);
+    }
+    if alts.len() == 1 {
+      Ok(alts.pop().unwrap())
+    } else {
+      Ok(Node::Alt(alts))
+    }
+  }
+
+  fn parse_concat(&mut self) -> Result<Node, RegexErr> {
+    let mut items = Vec::new();
+    loop {
+      match self.peek() {
+        None | Some(b'|') | Some(b')') => break,
+        _ => {}
+      }
+      let atom = self.parse_atom()?;
+      let atom = self.parse_repeat(atom)?;
+      items.push(atom);
+    }
+    match items.len() {
+      0 => Ok(Node::Empty),
+      1 => Ok(items.pop().unwrap()),
+      _ => Ok(Node::Concat(items))
+    }
+  }
+
+  fn parse_number(&mut self) -> Option<u32> {
+    let start = self.pos;
+    while let Some(b'0' ..= b'9') = self.peek() {
+      self.pos += 1;
+    }
+    std::str::from_utf8(&self.pat[start .. self.pos]).ok()?.parse().ok()
+  }
+
+  fn parse_repeat(&mut self, mut atom: Node) -> Result<Node, RegexErr> {
+    loop {
+      let (min, max) = match self.peek() {
+        Some(b'*') => { self.pos += 1; (0, None) }
+        Some(b'+') => { self.pos += 1; (1, None) }
+        Some(b'?') => { self.pos += 1; (0, Some(1)) }
+        Some(b'{') => {
+          self.pos += 1;
+          let min = match self.parse_number() {
+            None => return self.err("expected repetition count"),
+            Some(n) => n
+          };
+          let max = if self.eat(b',') {
+            match self.peek() {
+              Some(b'}') => None,
+              _ => match self.parse_number() {
+                None => return self.err("expected repetition count"),
+                Some(n) => Some(n)
+              }
+            }
+          } else {
+            Some(min)
+          };
+          if !self.eat(b'}') {
+            return self.err("expected `}`");
+          }
+          if min > MAX_REPEAT || max.map_or(false, |max| max > MAX_REPEAT || max < min) {
+            return self.err("invalid repetition count");
+          }
+          (min, max)
+        }
+        _ => return Ok(atom)
+      };
+      match atom {
+        Node::Empty | Node::Start | Node::End => return self.err("nothing to repeat"),
+        _ => {}
+      }
+      let greedy = !self.eat(b'?');
+      atom = Node::Repeat(Box::new(atom), min, max, greedy);
+    }
+  }
+
+  fn parse_atom(&mut self) -> Result<Node, RegexErr> {
+    let x = match self.peek() {
+      None => return self.err("unexpected end of pattern"),
+      Some(x) => x
+    };
+    self.pos += 1;
+    match x {
+      b'.' => Ok(Node::Any),
+      b'^' => Ok(Node::Start),
+      b'$' => Ok(Node::End),
+      b'[' => self.parse_class(),
+      b'(' => {
+        let index = if self.eat(b'?') {
+          if !self.eat(b':') {
+            return self.err("unsupported group flag");
+          }
+          None
+        } else {
+          self.ngroups += 1;
+          Some(self.ngroups)
+        };
+        let inner = self.parse_alt()?;
+        if !self.eat(b')') {
+          return self.err("expected `)`");
+        }
+        Ok(Node::Group(Box::new(inner), index))
+      }
+      b')' => self.err("unbalanced `)`"),
+      b'*' | b'+' | b'?' | b'{' => self.err("nothing to repeat"),
+      b'\\' => {
+        match self.parse_escape()? {
+          Err(set) => Ok(self.fold(Node::Set(set))),
+          Ok(x) => Ok(self.fold(Node::Byte(x)))
+        }
+      }
+      x => Ok(self.fold(Node::Byte(x)))
+    }
+  }
+
+  fn fold(&self, node: Node) -> Node {
+    if !self.icase {
+      return node;
+    }
+    match node {
+      Node::Byte(x) if x.is_ascii_alphabetic() => {
+        let mut set = [0; 4];
+        set_insert(&mut set, x);
+        set_fold_case(&mut set);
+        Node::Set(set)
+      }
+      Node::Set(mut set) => {
+        set_fold_case(&mut set);
+        Node::Set(set)
+      }
+      node => node
+    }
+  }
+
+  // Returns either a single byte or, for class escapes like `\d`, a set.
+  fn parse_escape(&mut self) -> Result<Result<u8, ByteSet>, RegexErr> {
+    let x = match self.peek() {
+      None => return self.err("trailing `\\`"),
+      Some(x) => x
+    };
+    self.pos += 1;
+    let mut set = [0; 4];
+    match x {
+      b'd' | b'D' => set_range(&mut set, b'0', b'9'),
+      b'w' | b'W' => {
+        set_range(&mut set, b'0', b'9');
+        set_range(&mut set, b'a', b'z');
+        set_range(&mut set, b'A', b'Z');
+        set_insert(&mut set, b'_');
+      }
+      b's' | b'S' => {
+        for &x in b" \t\r\n\x0b\x0c".iter() {
+          set_insert(&mut set, x);
+        }
+      }
+      b'n' => return Ok(Ok(b'\n')),
+      b'r' => return Ok(Ok(b'\r')),
+      b't' => return Ok(Ok(b'\t')),
+      x if x.is_ascii_alphanumeric() => return self.err("unsupported escape"),
+      x => return Ok(Ok(x))
+    }
+    if x.is_ascii_uppercase() {
+      set_negate(&mut set);
+    }
+    Ok(Err(set))
+  }
+
+  fn parse_class(&mut self) -> Result<Node, RegexErr> {
+    let negate = self.eat(b'^');
+    let mut set = [0; 4];
+    let mut first = true;
+    loop {
+      let x = match self.peek() {
+        None => return self.err("expected `]`"),
+        Some(x) => x
+      };
+      if x == b']' && !first {
+        self.pos += 1;
+        break;
+      }
+      first = false;
+      self.pos += 1;
+      let lo = if x == b'\\' {
+        match self.parse_escape()? {
+          Err(esc) => {
+            for i in 0 .. 4 {
+              set[i] |= esc[i];
+            }
+            continue;
+          }
+          Ok(x) => x
+        }
+      } else {
+        x
+      };
+      if self.peek() == Some(b'-') && self.pat.get(self.pos + 1).map_or(false, |&x| x != b']') {
+        self.pos += 1;
+        let hi = match self.peek() {
+          Some(b'\\') => {
+            self.pos += 1;
+            match self.parse_escape()? {
+              Err(_) => return self.err("invalid class range"),
+              Ok(x) => x
+            }
+          }
+          Some(x) => {
+            self.pos += 1;
+            x
+          }
+          None => return self.err("expected `]`")
+        };
+        if hi < lo {
+          return self.err("invalid class range");
+        }
+        set_range(&mut set, lo, hi);
+      } else {
+        set_insert(&mut set, lo);
+      }
+    }
+    if self.icase {
+      set_fold_case(&mut set);
+    }
+    if negate {
+      set_negate(&mut set);
+    }
+    Ok(Node::Set(set))
+  }
+}
+
+#[derive(Clone, Debug)]
+enum Inst {
+  Byte(u8),
+  Any,
+  Set(usize),
+  Split(usize, usize),
+  Jmp(usize),
+  Save(usize),
+  Start,
+  End,
+  Match,
+}
+
+struct Compiler {
+  prog: Vec<Inst>,
+  sets: Vec<ByteSet>,
+}
+
+impl Compiler {
+  fn push(&mut self, inst: Inst) -> Result<usize, RegexErr> {
+    if self.prog.len() >= MAX_INSTS {
+      return Err(RegexErr::TooLarge);
+    }
+    self.prog.push(inst);
+    Ok(self.prog.len() - 1)
+  }
+
+  fn compile(&mut self, node: &Node) -> Result<(), RegexErr> {
+    match node {
+      &Node::Empty => {}
+      &Node::Byte(x) => { self.push(Inst::Byte(x))?; }
+      &Node::Any => { self.push(Inst::Any)?; }
+      &Node::Set(ref set) => {
+        self.sets.push(*set);
+        self.push(Inst::Set(self.sets.len() - 1))?;
+      }
+      &Node::Start => { self.push(Inst::Start)?; }
+      &Node::End => { self.push(Inst::End)?; }
+      &Node::Group(ref inner, index) => {
+        if let Some(i) = index {
+          self.push(Inst::Save(2 * i))?;
+        }
+        self.compile(inner)?;
+        if let Some(i) = index {
+          self.push(Inst::Save(2 * i + 1))?;
+        }
+      }
+      &Node::Concat(ref items) => {
+        for item in items.iter() {
+          self.compile(item)?;
+        }
+      }
+      &Node::Alt(ref alts) => {
+        let mut jmps = Vec::new();
+        for (i, alt) in alts.iter().enumerate() {
+          if i + 1 < alts.len() {
+            let split = self.push(Inst::Split(0, 0))?;
+            self.compile(alt)?;
+            jmps.push(self.push(Inst::Jmp(0))?);
+            let next = self.prog.len();
+            self.prog[split] = Inst::Split(split + 1, next);
+          } else {
+            self.compile(alt)?;
+          }
+        }
+        let end = self.prog.len();
+        for j in jmps {
+          self.prog[j] = Inst::Jmp(end);
+        }
+      }
+      &Node::Repeat(ref inner, min, max, greedy) => {
+        for _ in 0 .. min {
+          self.compile(inner)?;
+        }
+        match max {
+          None => {
+            let split = self.push(Inst::Split(0, 0))?;
+            self.compile(inner)?;
+            self.push(Inst::Jmp(split))?;
+            let out = self.prog.len();
+            self.prog[split] = self.split(greedy, split + 1, out);
+          }
+          Some(max) => {
+            let mut splits = Vec::new();
+            for _ in min .. max {
+              splits.push(self.push(Inst::Split(0, 0))?);
+              self.compile(inner)?;
+            }
+            let out = self.prog.len();
+            for split in splits {
+              self.prog[split] = self.split(greedy, split + 1, out);
+            }
+          }
+        }
+      }
+    }
+    Ok(())
+  }
+
+  fn split(&self, greedy: bool, body: usize, out: usize) -> Inst {
+    if greedy {
+      Inst::Split(body, out)
+    } else {
+      Inst::Split(out, body)
+    }
+  }
+}
+
+// Capture positions: `(start, end)` byte offsets per group, group 0 being
+// the whole match.
+pub type Captures = Vec<Option<(usize, usize)>>;
+
+#[derive(Clone)]
+pub struct Regex {
+  pattern: SmolStr,
+  prog: Vec<Inst>,
+  sets: Vec<ByteSet>,
+  ngroups: usize,
+  anchored: bool,
+}
+
+impl fmt::Debug for Regex {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "Regex({:?})", self.pattern)
+  }
+}
+
+impl Regex {
+  pub fn new<S: AsRef<str>>(pattern: S) -> Result<Regex, RegexErr> {
+    let pattern = pattern.as_ref();
+    let (icase, body) = match pattern.strip_prefix("(?i)") {
+      None => (false, pattern),
+      Some(body) => (true, body)
+    };
+    let mut parser = Parser{pat: body.as_bytes(), pos: 0, ngroups: 0, icase};
+    let node = parser.parse_alt()?;
+    if parser.pos < parser.pat.len() {
+      return parser.err("unbalanced `)`");
+    }
+    let anchored = match &node {
+      &Node::Start => true,
+      &Node::Concat(ref items) => match items.first() {
+        Some(&Node::Start) => true,
+        _ => false
+      },
+      _ => false
+    };
+    let mut compiler = Compiler{prog: Vec::new(), sets: Vec::new()};
+    compiler.push(Inst::Save(0))?;
+    compiler.compile(&node)?;
+    compiler.push(Inst::Save(1))?;
+    compiler.push(Inst::Match)?;
+    Ok(Regex{
+      pattern: pattern.into(),
+      prog: compiler.prog,
+      sets: compiler.sets,
+      ngroups: parser.ngroups,
+      anchored,
+    })
+  }
+
+  pub fn as_str(&self) -> &str {
+    &self.pattern
+  }
+
+  // The number of capturing groups, not counting group 0.
+  pub fn groups(&self) -> usize {
+    self.ngroups
+  }
+
+  pub fn is_match(&self, text: &str) -> bool {
+    self.captures(text).is_some()
+  }
+
+  // Finds the leftmost match, preferring earlier alternatives and greedy
+  // repetition as in Perl.
+  pub fn captures(&self, text: &str) -> Option<Captures> {
+    let text = text.as_bytes();
+    let width = text.len() + 1;
+    let bits = self.prog.len().checked_mul(width)?;
+    if bits > MAX_VISITED_BITS {
+      return None;
+    }
+    let mut visited = vec![0u64; (bits + 63) / 64];
+    let mut slots = vec![None; 2 * (self.ngroups + 1)];
+    let last = if self.anchored { 0 } else { text.len() };
+    for start in 0 ..= last {
+      if self.exec(text, start, &mut visited, &mut slots) {
+        let caps = (0 ..= self.ngroups).map(|i| {
+          match (slots[2 * i], slots[2 * i + 1]) {
+            (Some(s), Some(e)) => Some((s, e)),
+            _ => None
+          }
+        }).collect();
+        return Some(caps);
+      }
+    }
+    None
+  }
+
+  fn exec(&self, text: &[u8], start: usize, visited: &mut [u64], slots: &mut [Option<usize>]) -> bool {
+    enum Job {
+      Run(usize, usize),
+      Restore(usize, Option<usize>),
+    }
+    let width = text.len() + 1;
+    let mut stack = vec![Job::Run(0, start)];
+    while let Some(job) = stack.pop() {
+      let (mut pc, mut pos) = match job {
+        Job::Restore(slot, old) => {
+          slots[slot] = old;
+          continue;
+        }
+        Job::Run(pc, pos) => (pc, pos)
+      };
+      loop {
+        // NB: a state that was already reached failed the first time, and
+        // captures do not change whether it matches.
+        let bit = pc * width + pos;
+        if visited[bit / 64] & (1 << (bit % 64)) != 0 {
+          break;
+        }
+        visited[bit / 64] |= 1 << (bit % 64);
+        match &self.prog[pc] {
+          &Inst::Byte(x) => {
+            if text.get(pos) != Some(&x) {
+              break;
+            }
+            pc += 1;
+            pos += 1;
+          }
+          &Inst::Any => {
+            match text.get(pos) {
+              None | Some(&b'\n') => break,
+              Some(_) => {}
+            }
+            pc += 1;
+            pos += 1;
+          }
+          &Inst::Set(i) => {
+            match text.get(pos) {
+              Some(&x) if set_contains(&self.sets[i], x) => {}
+              _ => break
+            }
+            pc += 1;
+            pos += 1;
+          }
+          &Inst::Split(x, y) => {
+            stack.push(Job::Run(y, pos));
+            pc = x;
+          }
+          &Inst::Jmp(x) => {
+            pc = x;
+          }
+          &Inst::Save(slot) => {
+            stack.push(Job::Restore(slot, slots[slot]));
+            slots[slot] = Some(pos);
+            pc += 1;
+          }
+          &Inst::Start => {
+            if pos != 0 {
+              break;
+            }
+            pc += 1;
+          }
+          &Inst::End => {
+            if pos != text.len() {
+              break;
+            }
+            pc += 1;
+          }
+          &Inst::Match => {
+            return true;
+          }
+        }
+      }
+    }
+    false
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_is_match() {
+    let cases: &[(&str, &str, bool)] = &[
+      ("abc", "abc", true),
+      ("abc", "xabcx", true),
+      ("abc", "ab", false),
+      ("^abc$", "abc", true),
+      ("^abc$", "abcd", false),
+      ("^abc", "xabc", false),
+      ("a.c", "abc", true),
+      ("a.c", "a\nc", false),
+      ("^a*$", "", true),
+      ("^a+$", "", false),
+      ("^ab?c$", "ac", true),
+      ("^ab?c$", "abbc", false),
+      ("^a{2}$", "aa", true),
+      ("^a{2}$", "aaa", false),
+      ("^a{2,}$", "aaaa", true),
+      ("^a{2,3}$", "aaaa", false),
+      ("^(?:ab)+$", "ababab", true),
+      ("^(cat|dog)s?$", "dogs", true),
+      ("^(cat|dog)s?$", "cow", false),
+      ("^[a-c]+$", "abcabc", true),
+      ("^[^/]+$", "a/b", false),
+      ("^\\d+$", "0123", true),
+      ("^\\D+$", "12", false),
+      ("^\\w+$", "a_Z9", true),
+      ("^\\s$", "\t", true),
+      ("^\\S$", " ", false),
+      ("^[\\d.]+$", "1.2.3", true),
+      ("^\\.$", ".", true),
+      ("^\\.$", "a", false),
+      ("(?i)^example\\.com$", "EXAMPLE.com", true),
+      ("(?i)^[a-z]+$", "MiXeD", true),
+      ("^[a-z]+$", "MiXeD", false),
+      ("^/api/v[12]/", "/api/v2/users", true),
+      ("^/api/v[12]/", "/api/v3/users", false),
+      ("^(a|ab)(c|bcd)(d*)$", "abcd", true),
+      ("^(a*)*$", "aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaab", false),
+      ("^a{2}*$", "aaaa", true),
+      ("^a{2}*$", "aaa", false),
+    ];
+    for &(pattern, text, expected) in cases.iter() {
+      let re = Regex::new(pattern).unwrap();
+      assert_eq!(re.is_match(text), expected, "pattern: {:?} text: {:?}", pattern, text);
+    }
+  }
+
+  #[test]
+  fn test_captures() {
+    let cases: &[(&str, &str, &[Option<(usize, usize)>])] = &[
+      ("b+", "abbbc", &[Some((1, 4))]),
+      ("^/users/(\\d+)/posts$", "/users/42/posts", &[Some((0, 15)), Some((7, 9))]),
+      ("^(a)?b$", "b", &[Some((0, 1)), None]),
+      ("^(\\w+)-(\\w+)$", "ab-cd", &[Some((0, 5)), Some((0, 2)), Some((3, 5))]),
+      ("^(a+)(a*)$", "aaa", &[Some((0, 3)), Some((0, 3)), Some((3, 3))]),
+      ("^(a+?)(a*)$", "aaa", &[Some((0, 3)), Some((0, 1)), Some((1, 3))]),
+      ("^(a|ab)(c|bcd)$", "abcd", &[Some((0, 4)), Some((0, 1)), Some((1, 4))]),
+      ("^(?:(a)|b)+$", "ab", &[Some((0, 2)), Some((0, 1))]),
+      ("(\\d+)", "ab12cd345", &[Some((2, 4)), Some((2, 4))]),
+    ];
+    for &(pattern, text, expected) in cases.iter() {
+      let re = Regex::new(pattern).unwrap();
+      assert_eq!(re.captures(text).as_ref().map(|c| &c[ .. ]), Some(expected), "pattern: {:?} text: {:?}", pattern, text);
+    }
+    assert_eq!(Regex::new("^a$").unwrap().captures("b"), None);
+  }
+
+  #[test]
+  fn test_errors() {
+    let cases: &[&str] = &[
+      "(",
+      "(abc",
+      "abc)",
+      "[a-",
+      "[abc",
+      "*a",
+      "a{3,2}",
+      "\\",
+      "a{1000}",
+      "(?x)",
+    ];
+    for &pattern in cases.iter() {
+      match Regex::new(pattern) {
+        Err(RegexErr::Syntax(_, _)) => {}
+        res => panic!("pattern: {:?}: expected a syntax error, got {:?}", pattern, res),
+      }
+    }
+    match Regex::new("((((a{200}){200}){200}){200})") {
+      Err(RegexErr::TooLarge) => {}
+      res => panic!("expected TooLarge, got {:?}", res),
+    }
+  }
+
+  #[test]
+  fn test_long_text() {
+    let re = Regex::new("b$").unwrap();
+    let mut text = "a".repeat(MAX_VISITED_BITS / re.prog.len() - 2);
+    text.push('b');
+    assert!(re.is_match(&text));
+    text.insert(0, 'a');
+    text.insert(0, 'a');
+    assert!(!re.is_match(&text));
+  }
+}
@@ -0,0 +1,51 @@
+#![forbid(unsafe_code)]
+
+use smol_str::{SmolStr};
+use time::{get_time_usec};
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+pub const HEADER: &'static str = "X-Request-Id";
+pub const MAX_LEN: usize = 128;
+
+static PREFIX: AtomicU64 = AtomicU64::new(0);
+static NEXT: AtomicU64 = AtomicU64::new(1);
+
+// Picks the per-process prefix of generated ids; without this, ids are
+// only unique within the process.
+pub fn init() {
+  let mut buf = [0; 8];
+  let prefix = match crate::sys::getrandom(&mut buf) {
+    Err(e) => {
+      log_warn!("request_id: getrandom failed: {:?}", e);
+      let t = get_time_usec();
+      mix((t.sec as u64) << 20 ^ t.nsec as u64 ^ (std::process::id() as u64) << 44)
+    }
+    Ok(_) => u64::from_le_bytes(buf)
+  };
+  PREFIX.store(prefix, Ordering::Relaxed);
+}
+
+fn mix(mut x: u64) -> u64 {
+  x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+  x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+  x ^ (x >> 31)
+}
+
+// Unique within the process, and unpredictable enough for span ids.
+pub fn unique_u64() -> u64 {
+  mix(PREFIX.load(Ordering::Relaxed) ^ NEXT.fetch_add(1, Ordering::Relaxed))
+}
+
+// Returns 32 lowercase hex digits, so that an id can double as a trace id.
+pub fn generate() -> SmolStr {
+  let n = NEXT.fetch_add(1, Ordering::Relaxed);
+  format!("{:016x}{:016x}", PREFIX.load(Ordering::Relaxed), mix(n)).into()
+}
+
+// NB: ids end up in log lines and response headers, so only a
+// conservative set of characters is accepted from clients.
+pub fn valid(id: &[u8]) -> bool {
+  !id.is_empty() && id.len() <= MAX_LEN &&
+      id.iter().all(|&x| x.is_ascii_alphanumeric() || x == b'-' || x == b'_' || x == b'.' || x == b':')
+}
@@ -0,0 +1,234 @@
+#![forbid(unsafe_code)]
+
+// Per-route URL rewriting and redirects, applied to the request path
+// before the request is dispatched to a backend.
+//
+// Templates substitute `$0` to `$9` or `${N}` with regex captures (empty if
+// the group did not participate), `${host}`, `${path}` and `${query}` with
+// the request's host, current path and query (without the `?`), and `$$`
+// with a literal `$`. `${host}` is only allowed in rules for a host, see
+// `Rewrite::uses_host`, as the Host header is up to the client.
+
+use crate::regex::{Captures, Regex};
+
+use smol_str::{SmolStr};
+
+pub const REDIRECT_STATUSES: &'static [u16] = &[301, 302, 307, 308];
+
+#[derive(Clone, Debug)]
+pub enum Rewrite {
+  // Removes a path prefix on a segment boundary: with `/api`, `/api/v1`
+  // becomes `/v1` and `/api` becomes `/`; other paths are left alone.
+  StripPrefix(SmolStr),
+  // Prepends a prefix: with `/v2`, `/users` becomes `/v2/users`.
+  AddPrefix(SmolStr),
+  // Replaces the first match of the regex in the path with the template. A
+  // query in the template, e.g. `/posts?user=$1`, is put before the
+  // request's query, or replaces it if the template uses `${query}`.
+  Regex(Regex, SmolStr),
+  // If the regex matches the path, answers with a redirect of the given
+  // status to the template; later rules are not evaluated. The query is
+  // appended unless the expanded target has its own.
+  Redirect(u16, Regex, SmolStr),
+}
+
+impl Rewrite {
+  pub fn uses_host(&self) -> bool {
+    match self {
+      &Rewrite::StripPrefix(_) | &Rewrite::AddPrefix(_) => false,
+      &Rewrite::Regex(_, ref template) | &Rewrite::Redirect(_, _, ref template) => {
+        uses_var(template, Var::Host)
+      }
+    }
+  }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum RewriteOutcome {
+  // The request target to forward, path and query.
+  Forward(String),
+  Redirect(u16, String),
+}
+
+// Checks that `template` only refers to known variables and to groups
+// that `groups` (the regex's capture count) provides.
+pub fn check_template(template: &str, groups: usize) -> Result<(), SmolStr> {
+  let mut err = None;
+  expand_with(template, |var, _| {
+    match var {
+      Var::Group(i) if i > groups => {
+        err = Some(format!("no capture group ${} (regex has {})", i, groups).into());
+      }
+      Var::Unknown(name) => {
+        err = Some(format!("unknown variable `{}`", name).into());
+      }
+      _ => {}
+    }
+  });
+  match err {
+    None => Ok(()),
+    Some(e) => Err(e)
+  }
+}
+
+#[derive(PartialEq, Eq)]
+enum Var<'a> {
+  Group(usize),
+  Host,
+  Path,
+  Query,
+  Unknown(&'a str),
+}
+
+fn expand_with<'t, F: FnMut(Var<'t>, &mut String)>(template: &'t str, mut push: F) -> String {
+  let mut out = String::new();
+  let mut rest = template;
+  while let Some(pos) = rest.find('$') {
+    out.push_str(&rest[ .. pos]);
+    rest = &rest[pos + 1 .. ];
+    let (var, len) = match rest.as_bytes().first() {
+      Some(&b'$') => {
+        out.push('$');
+        rest = &rest[1 .. ];
+        continue;
+      }
+      Some(&x) if x.is_ascii_digit() => (Var::Group((x - b'0') as usize), 1),
+      Some(&b'{') => match rest.find('}') {
+        None => (Var::Unknown(rest), rest.len()),
+        Some(end) => {
+          let name = &rest[1 .. end];
+          let var = match name {
+            "host" => Var::Host,
+            "path" => Var::Path,
+            "query" => Var::Query,
+            _ => match name.parse::<usize>() {
+              Ok(i) => Var::Group(i),
+              Err(_) => Var::Unknown(name)
+            }
+          };
+          (var, end + 1)
+        }
+      },
+      // NB: a `$` not followed by a variable is kept as is.
+      _ => {
+        out.push('$');
+        continue;
+      }
+    };
+    push(var, &mut out);
+    rest = &rest[len .. ];
+  }
+  out.push_str(rest);
+  out
+}
+
+fn expand(template: &str, caps: &Captures, path: &str, host: &str, query: Option<&str>) -> String {
+  expand_with(template, |var, out| {
+    match var {
+      Var::Group(i) => {
+        if let Some(&Some((s, e))) = caps.get(i) {
+          out.push_str(&path[s .. e]);
+        }
+      }
+      Var::Host => out.push_str(host),
+      Var::Path => out.push_str(path),
+      Var::Query => out.push_str(query.unwrap_or("")),
+      Var::Unknown(_) => {}
+    }
+  })
+}
+
+fn uses_var(template: &str, wanted: Var) -> bool {
+  let mut found = false;
+  expand_with(template, |var, _| {
+    if var == wanted {
+      found = true;
+    }
+  });
+  found
+}
+
+fn uses_query(template: &str) -> bool {
+  uses_var(template, Var::Query)
+}
+
+fn merge_query(first: &str, second: &str) -> String {
+  match (first.is_empty(), second.is_empty()) {
+    (true, _) => second.to_string(),
+    (_, true) => first.to_string(),
+    _ => format!("{}&{}", first, second)
+  }
+}
+
+fn strip_prefix<'a>(path: &'a str, prefix: &str) -> Option<&'a str> {
+  let prefix = prefix.strip_suffix('/').unwrap_or(prefix);
+  let rest = path.strip_prefix(prefix)?;
+  if rest.is_empty() {
+    Some("/")
+  } else if rest.starts_with('/') {
+    Some(rest)
+  } else {
+    None
+  }
+}
+
+// Applies `rewrites` in order to `target` (path and optional query) of a
+// request for `host`.
+pub fn apply(rewrites: &[Rewrite], host: &str, target: &str) -> RewriteOutcome {
+  let (path, query) = match target.find('?') {
+    None => (target, None),
+    Some(pos) => (&target[ .. pos], Some(target[pos + 1 .. ].to_string()))
+  };
+  let mut path = path.to_string();
+  let mut query = query;
+  for rewrite in rewrites.iter() {
+    match rewrite {
+      &Rewrite::StripPrefix(ref prefix) => {
+        if let Some(rest) = strip_prefix(&path, prefix) {
+          path = rest.to_string();
+        }
+      }
+      &Rewrite::AddPrefix(ref prefix) => {
+        let prefix = prefix.strip_suffix('/').unwrap_or(prefix);
+        path = format!("{}{}", prefix, path);
+      }
+      &Rewrite::Regex(ref re, ref template) => {
+        if let Some(caps) = re.captures(&path) {
+          let (s, e) = caps[0].unwrap();
+          let expanded = expand(template, &caps, &path, host, query.as_deref());
+          let (new_path, new_query) = match expanded.find('?') {
+            None => (&expanded[ .. ], None),
+            Some(pos) => (&expanded[ .. pos], Some(&expanded[pos + 1 .. ]))
+          };
+          let replaced = format!("{}{}{}", &path[ .. s], new_path, &path[e .. ]);
+          path = replaced;
+          query = match (new_query, query.take()) {
+            (None, query) => query,
+            (Some(new_query), _) if uses_query(template) => Some(new_query.to_string()),
+            (Some(new_query), None) => Some(new_query.to_string()),
+            (Some(new_query), Some(query)) => Some(merge_query(new_query, &query)),
+          };
+        }
+      }
+      &Rewrite::Redirect(status, ref re, ref template) => {
+        if let Some(caps) = re.captures(&path) {
+          let mut location = expand(template, &caps, &path, host, query.as_deref());
+          if let Some(query) = query {
+            if !location.contains('?') {
+              location.push('?');
+              location.push_str(&query);
+            }
+          }
+          return RewriteOutcome::Redirect(status, location);
+        }
+      }
+    }
+    if !path.starts_with('/') {
+      path.insert(0, '/');
+    }
+  }
+  match query {
+    None => RewriteOutcome::Forward(path),
+    Some(query) => RewriteOutcome::Forward(format!("{}?{}", path, query))
+  }
+}
@@ -0,0 +1,330 @@
+#![forbid(unsafe_code)]
+
+// Route rules, which send requests to a backend group by host, path,
+// method and headers, and host patterns, which map hosts to a group by
+// wildcard or regex when there is no exact match.
+
+use crate::{raw_header};
+use crate::regex::{Regex};
+use crate::rewrite::{Rewrite};
+
+use smol_str::{SmolStr};
+
+use std::cmp::{Ordering};
+
+#[derive(Clone, Debug)]
+pub enum PathMatch {
+  Any,
+  // Matches the path itself and anything below it, on segment boundaries:
+  // `/api` matches `/api` and `/api/v1`, but not `/apix`.
+  Prefix(SmolStr),
+  Exact(SmolStr),
+  // NB: unanchored; use `^` and `$` to match the whole path.
+  Regex(Regex),
+}
+
+impl PathMatch {
+  pub fn matches(&self, path: &str) -> bool {
+    match self {
+      &PathMatch::Any => true,
+      &PathMatch::Prefix(ref prefix) => {
+        path.starts_with(prefix.as_str()) &&
+            (path.len() == prefix.len() || prefix.ends_with('/') || path.as_bytes()[prefix.len()] == b'/')
+      }
+      &PathMatch::Exact(ref exact) => path == exact.as_str(),
+      &PathMatch::Regex(ref re) => re.is_match(path),
+    }
+  }
+
+  // Orders path matches from least to most specific: any, regex, then
+  // prefixes by length, then exact.
+  fn rank(&self) -> (u8, usize) {
+    match self {
+      &PathMatch::Any => (0, 0),
+      &PathMatch::Regex(_) => (1, 0),
+      &PathMatch::Prefix(ref prefix) => (2, prefix.len()),
+      &PathMatch::Exact(ref exact) => (3, exact.len()),
+    }
+  }
+}
+
+#[derive(Clone, Debug)]
+pub enum HeaderMatch {
+  Present,
+  // Case-sensitive comparison of the whole value.
+  Exact(SmolStr),
+  Regex(Regex),
+}
+
+#[derive(Clone, Debug)]
+pub struct RouteRule {
+  pub host: Option<SmolStr>,
+  pub path: PathMatch,
+  // If non-empty, the request method must be one of these.
+  pub methods: Vec<SmolStr>,
+  // All of these must match; names are case-insensitive.
+  pub headers: Vec<(SmolStr, HeaderMatch)>,
+  pub group: SmolStr,
+  // Applied in order to matching requests; see `crate::rewrite`.
+  pub rewrites: Vec<Rewrite>,
+}
+
+impl RouteRule {
+  pub fn new<G: AsRef<str>>(group: G) -> RouteRule {
+    RouteRule{
+      host: None,
+      path: PathMatch::Any,
+      methods: Vec::new(),
+      headers: Vec::new(),
+      group: group.as_ref().into(),
+      rewrites: Vec::new(),
+    }
+  }
+
+  pub fn matches(&self, host: Option<&str>, method: &str, path: &str, head: &[u8]) -> bool {
+    if let Some(rule_host) = self.host.as_ref() {
+      match host {
+        Some(host) if host.eq_ignore_ascii_case(rule_host) => {}
+        _ => return false
+      }
+    }
+    if !self.methods.is_empty() && !self.methods.iter().any(|m| m == method) {
+      return false;
+    }
+    if !self.path.matches(path) {
+      return false;
+    }
+    self.headers.iter().all(|&(ref name, ref m)| {
+      match (raw_header(head, name), m) {
+        (None, _) => false,
+        (Some(_), &HeaderMatch::Present) => true,
+        (Some(v), &HeaderMatch::Exact(ref value)) => v == value.as_bytes(),
+        (Some(v), &HeaderMatch::Regex(ref re)) => match std::str::from_utf8(v) {
+          Err(_) => false,
+          Ok(v) => re.is_match(v)
+        }
+      }
+    })
+  }
+
+  fn specificity(&self) -> (bool, (u8, usize), bool, usize) {
+    (self.host.is_some(), self.path.rank(), !self.methods.is_empty(), self.headers.len())
+  }
+}
+
+// Rules ordered by precedence: the most specific match wins, i.e. a rule
+// for the host over one for any host, then the longest path match, then
+// rules that also constrain the method or more headers. Ties go to the
+// rule added first.
+#[derive(Clone, Default, Debug)]
+pub struct RouteTable {
+  rules: Vec<RouteRule>,
+}
+
+impl RouteTable {
+  pub fn is_empty(&self) -> bool {
+    self.rules.is_empty()
+  }
+
+  pub fn rules(&self) -> &[RouteRule] {
+    &self.rules
+  }
+
+  pub fn insert(&mut self, rule: RouteRule) {
+    let key = rule.specificity();
+    // NB: after all rules at least as specific, so that ties keep their
+    // insertion order.
+    let pos = self.rules.iter().position(|r| r.specificity().cmp(&key) == Ordering::Less)
+      .unwrap_or(self.rules.len());
+    self.rules.insert(pos, rule);
+  }
+
+  pub fn lookup(&self, host: Option<&str>, method: &str, path: &str, head: &[u8]) -> Option<&RouteRule> {
+    self.rules.iter().find(|r| r.matches(host, method, path, head))
+  }
+}
+
+// The path of a request target, without the query; for an absolute target
+// the part after the authority.
+pub fn target_path(target: &str) -> &str {
+  let target = match target.find('?') {
+    None => target,
+    Some(pos) => &target[ .. pos]
+  };
+  match target.find("://") {
+    None => target,
+    Some(scheme_end) => {
+      let rest = &target[scheme_end + 3 .. ];
+      match rest.find('/') {
+        None => "/",
+        Some(pos) => &rest[pos .. ]
+      }
+    }
+  }
+}
+
+// Whether a path has a `.` or `..` segment, also with the dots or the
+// slashes around them percent-encoded or with backslashes as separators,
+// as a backend may decode or resolve those; such a path could match a
+// prefix rule for a different part of the tree than it leads to.
+pub fn has_dot_segment(path: &str) -> bool {
+  fn hex(x: u8) -> Option<u8> {
+    match x {
+      b'0' ..= b'9' => Some(x - b'0'),
+      b'a' ..= b'f' => Some(x - b'a' + 10),
+      b'A' ..= b'F' => Some(x - b'A' + 10),
+      _ => None
+    }
+  }
+  let buf = path.as_bytes();
+  // The number of dots in the current segment, or `None` once it has
+  // anything else.
+  let mut dots = Some(0);
+  let mut pos = 0;
+  while pos < buf.len() {
+    let mut x = buf[pos];
+    pos += 1;
+    if x == b'%' && pos + 2 <= buf.len() {
+      if let (Some(hi), Some(lo)) = (hex(buf[pos]), hex(buf[pos + 1])) {
+        x = (hi << 4) | lo;
+        pos += 2;
+      }
+    }
+    match x {
+      b'/' | b'\\' => {
+        if let Some(1) | Some(2) = dots {
+          return true;
+        }
+        dots = Some(0);
+      }
+      b'.' => dots = dots.map(|n| n + 1),
+      _ => dots = None
+    }
+  }
+  match dots {
+    Some(1) | Some(2) => true,
+    _ => false
+  }
+}
+
+// Lowercases a host and strips a port suffix and a trailing dot, so that
+// `Example.COM.:443` is looked up as `example.com`.
+pub fn normalize_host(host: &str) -> SmolStr {
+  let host = host.trim();
+  let host = if host.starts_with('[') {
+    // NB: an IPv6 literal keeps its brackets.
+    match host.find(']') {
+      None => host,
+      Some(pos) => &host[ .. pos + 1]
+    }
+  } else {
+    match host.rfind(':') {
+      Some(pos) if host[pos + 1 .. ].bytes().all(|x| x.is_ascii_digit()) => &host[ .. pos],
+      _ => host
+    }
+  };
+  let host = host.strip_suffix('.').unwrap_or(host);
+  host.to_ascii_lowercase().into()
+}
+
+// Host patterns beyond exact names: wildcards like `*.example.com`, which
+// match subdomains at any depth but not `example.com` itself, and regexes,
+// which are tried in the order added and only if no wildcard matches.
+#[derive(Clone, Default, Debug)]
+pub struct HostTable {
+  // Suffixes with the leading dot, longest first.
+  wildcards: Vec<(SmolStr, SmolStr)>,
+  regexes: Vec<(Regex, SmolStr)>,
+}
+
+impl HostTable {
+  pub fn wildcards(&self) -> &[(SmolStr, SmolStr)] {
+    &self.wildcards
+  }
+
+  pub fn regexes(&self) -> &[(Regex, SmolStr)] {
+    &self.regexes
+  }
+
+  // `suffix` is the wildcard without its `*`, e.g. `.example.com`.
+  pub fn insert_wildcard(&mut self, suffix: SmolStr, group: SmolStr) {
+    self.wildcards.retain(|&(ref s, _)| s != &suffix);
+    let pos = self.wildcards.iter().position(|&(ref s, _)| s.len() < suffix.len())
+      .unwrap_or(self.wildcards.len());
+    self.wildcards.insert(pos, (suffix, group));
+  }
+
+  pub fn insert_regex(&mut self, re: Regex, group: SmolStr) {
+    self.regexes.push((re, group));
+  }
+
+  // `host` must already be normalized.
+  pub fn lookup(&self, host: &str) -> Option<&SmolStr> {
+    for &(ref suffix, ref group) in self.wildcards.iter() {
+      if host.len() > suffix.len() && host.ends_with(suffix.as_str()) {
+        return Some(group);
+      }
+    }
+    for &(ref re, ref group) in self.regexes.iter() {
+      if re.is_match(host) {
+        return Some(group);
+      }
+    }
+    None
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_normalize_host() {
+    let cases: &[(&str, &str)] = &[
+      ("example.com", "example.com"),
+      ("Example.COM", "example.com"),
+      ("example.com:443", "example.com"),
+      ("example.com.", "example.com"),
+      ("Example.COM.:8443", "example.com"),
+      (" example.com ", "example.com"),
+      ("example.com:", "example.com"),
+      ("example.com:https", "example.com:https"),
+      ("[::1]", "[::1]"),
+      ("[::1]:443", "[::1]"),
+      ("[2001:DB8::1]:8443", "[2001:db8::1]"),
+      ("[::1", "[::1"),
+      ("127.0.0.1:80", "127.0.0.1"),
+    ];
+    for &(host, expected) in cases.iter() {
+      assert_eq!(normalize_host(host), expected, "host: {:?}", host);
+    }
+  }
+
+  #[test]
+  fn test_has_dot_segment() {
+    let cases: &[(&str, bool)] = &[
+      ("/", false),
+      ("/static/app.js", false),
+      ("/static/.well-known", false),
+      ("/a/.../b", false),
+      ("/a..b/c", false),
+      ("/static/../api/x", true),
+      ("/static/./x", true),
+      ("/static/..", true),
+      ("/static/.", true),
+      ("/static/..%2Fapi/x", true),
+      ("/static/..%2fapi/x", true),
+      ("/static/%2E%2E/api/x", true),
+      ("/static/%2e./api/x", true),
+      ("/static%2F..%2Fapi", true),
+      ("/static/..\\api", true),
+      ("/static/..%5Capi", true),
+      ("/projects/group%2Fname", false),
+      ("/a%2/b", false),
+      ("/100%", false),
+    ];
+    for &(path, expected) in cases.iter() {
+      assert_eq!(has_dot_segment(path), expected, "path: {:?}", path);
+    }
+  }
+}
@@ -1,3 +1,5 @@
+#![forbid(unsafe_code)]
+
 pub use service_base::signal::{signals};
 use service_base::signal::*;
 
@@ -6,6 +8,8 @@ pub fn init_signals() {
   cfg.hup = true;
   cfg.int_ = true;
   cfg.term = true;
+  cfg.usr1 = true;
+  cfg.usr2 = true;
   //cfg.quit = true;
   cfg.init();
 }
@@ -0,0 +1,385 @@
+#![allow(unsafe_code)]
+
+use std::ffi::{CString};
+use std::io::{Error as IoError};
+use std::mem::{size_of, zeroed};
+use std::net::{TcpListener};
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::os::unix::net::{UnixDatagram};
+use std::ptr::{read_unaligned, write_unaligned};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+fn cvt(ret: libc::c_int) -> Result<libc::c_int, IoError> {
+  if ret < 0 {
+    Err(IoError::last_os_error())
+  } else {
+    Ok(ret)
+  }
+}
+
+pub fn close(fd: RawFd) -> Result<(), IoError> {
+  cvt(unsafe { libc::close(fd) })?;
+  Ok(())
+}
+
+pub fn epoll_create() -> Result<RawFd, IoError> {
+  cvt(unsafe { libc::epoll_create1(libc::EPOLL_CLOEXEC) })
+}
+
+pub fn epoll_ctl(epfd: RawFd, op: libc::c_int, fd: RawFd, events: u32, data: u64) -> Result<(), IoError> {
+  let mut ev = libc::epoll_event{events, u64: data};
+  cvt(unsafe { libc::epoll_ctl(epfd, op, fd, &mut ev) })?;
+  Ok(())
+}
+
+pub fn epoll_wait(epfd: RawFd, events: &mut [libc::epoll_event], timeout_ms: libc::c_int) -> Result<usize, IoError> {
+  let max_events = events.len().min(libc::c_int::max_value() as usize) as libc::c_int;
+  loop {
+    let ret = unsafe { libc::epoll_wait(epfd, events.as_mut_ptr(), max_events, timeout_ms) };
+    match cvt(ret) {
+      Err(e) => {
+        if e.kind() == std::io::ErrorKind::Interrupted {
+          continue;
+        }
+        return Err(e);
+      }
+      Ok(n) => return Ok(n as usize)
+    }
+  }
+}
+
+const LISTEN_FDS_START: RawFd = 3;
+
+static LISTEN_FDS_TAKEN: AtomicBool = AtomicBool::new(false);
+
+// Takes ownership of the `nfds` sockets passed by systemd socket activation,
+// starting at fd 3, and marks them close-on-exec.
+// NB: the caller must have checked `LISTEN_PID`; the fds are only taken
+// once, so later calls return nothing.
+pub fn take_listen_fds(nfds: RawFd) -> Vec<Result<TcpListener, (RawFd, IoError)>> {
+  if nfds <= 0 || LISTEN_FDS_TAKEN.swap(true, Ordering::AcqRel) {
+    return Vec::new();
+  }
+  (LISTEN_FDS_START .. LISTEN_FDS_START + nfds).map(|fd| {
+    set_cloexec(fd).map_err(|e| (fd, e))?;
+    // SAFETY: systemd passes these fds to this process, and nothing else
+    // in the process refers to them.
+    Ok(unsafe { TcpListener::from_raw_fd(fd) })
+  }).collect()
+}
+
+pub const MAX_PASS_FDS: usize = 16;
+
+pub fn send_fds(sock: RawFd, fds: &[RawFd], payload: &[u8]) -> Result<(), IoError> {
+  assert!(!fds.is_empty() && fds.len() <= MAX_PASS_FDS);
+  assert!(!payload.is_empty());
+  let data_len = fds.len() * size_of::<RawFd>();
+  let space = unsafe { libc::CMSG_SPACE(data_len as u32) } as usize;
+  let mut cbuf = vec![0_u64; (space + 7) / 8];
+  let mut iov = libc::iovec{
+    iov_base: payload.as_ptr() as *mut libc::c_void,
+    iov_len: payload.len(),
+  };
+  let mut msg: libc::msghdr = unsafe { zeroed() };
+  msg.msg_iov = &mut iov;
+  msg.msg_iovlen = 1;
+  msg.msg_control = cbuf.as_mut_ptr() as *mut libc::c_void;
+  msg.msg_controllen = space as _;
+  unsafe {
+    let cmsg = libc::CMSG_FIRSTHDR(&msg);
+    (*cmsg).cmsg_level = libc::SOL_SOCKET;
+    (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+    (*cmsg).cmsg_len = libc::CMSG_LEN(data_len as u32) as _;
+    let data = libc::CMSG_DATA(cmsg) as *mut RawFd;
+    for (i, &fd) in fds.iter().enumerate() {
+      write_unaligned(data.add(i), fd);
+    }
+  }
+  let ret = unsafe { libc::sendmsg(sock, &msg, libc::MSG_NOSIGNAL) };
+  if ret < 0 {
+    return Err(IoError::last_os_error());
+  }
+  Ok(())
+}
+
+// Receives listening sockets sent with `send_fds`, and the payload.
+pub fn recv_listeners(sock: RawFd, payload: &mut [u8]) -> Result<(usize, Vec<TcpListener>), IoError> {
+  let space = unsafe { libc::CMSG_SPACE((MAX_PASS_FDS * size_of::<RawFd>()) as u32) } as usize;
+  let mut cbuf = vec![0_u64; (space + 7) / 8];
+  let mut iov = libc::iovec{
+    iov_base: payload.as_mut_ptr() as *mut libc::c_void,
+    iov_len: payload.len(),
+  };
+  let mut msg: libc::msghdr = unsafe { zeroed() };
+  msg.msg_iov = &mut iov;
+  msg.msg_iovlen = 1;
+  msg.msg_control = cbuf.as_mut_ptr() as *mut libc::c_void;
+  msg.msg_controllen = space as _;
+  let ret = unsafe { libc::recvmsg(sock, &mut msg, libc::MSG_CMSG_CLOEXEC) };
+  if ret < 0 {
+    return Err(IoError::last_os_error());
+  }
+  let mut fds = Vec::new();
+  unsafe {
+    let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+    while !cmsg.is_null() {
+      if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_RIGHTS {
+        let data = libc::CMSG_DATA(cmsg) as *const RawFd;
+        let data_len = (*cmsg).cmsg_len as usize - (data as usize - cmsg as usize);
+        for i in 0 .. data_len / size_of::<RawFd>() {
+          // SAFETY: fds received with SCM_RIGHTS are new to this process.
+          fds.push(TcpListener::from_raw_fd(read_unaligned(data.add(i))));
+        }
+      }
+      cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+    }
+  }
+  Ok((ret as usize, fds))
+}
+
+// Returns the uid of the process on the other end of a Unix socket, as of
+// when it connected.
+pub fn peer_uid(sock: RawFd) -> Result<u32, IoError> {
+  let mut cred: libc::ucred = unsafe { zeroed() };
+  let mut len = size_of::<libc::ucred>() as libc::socklen_t;
+  cvt(unsafe { libc::getsockopt(sock, libc::SOL_SOCKET, libc::SO_PEERCRED, &mut cred as *mut libc::ucred as *mut libc::c_void, &mut len) })?;
+  if len as usize != size_of::<libc::ucred>() {
+    return Err(IoError::from_raw_os_error(libc::EINVAL));
+  }
+  Ok(cred.uid)
+}
+
+pub fn set_cloexec(fd: RawFd) -> Result<(), IoError> {
+  let flags = cvt(unsafe { libc::fcntl(fd, libc::F_GETFD) })?;
+  cvt(unsafe { libc::fcntl(fd, libc::F_SETFD, flags | libc::FD_CLOEXEC) })?;
+  Ok(())
+}
+
+pub fn getrandom(buf: &mut [u8]) -> Result<(), IoError> {
+  let mut pos = 0;
+  while pos < buf.len() {
+    let n = unsafe { libc::getrandom(buf[pos .. ].as_mut_ptr() as *mut libc::c_void, buf.len() - pos, 0) };
+    if n < 0 {
+      let e = IoError::last_os_error();
+      if e.raw_os_error() == Some(libc::EINTR) {
+        continue;
+      }
+      return Err(e);
+    }
+    pos += n as usize;
+  }
+  Ok(())
+}
+
+// NB: a leading `@` in `path` denotes the abstract namespace.
+pub fn unix_dgram_connect(path: &[u8]) -> Result<UnixDatagram, IoError> {
+  let mut addr: libc::sockaddr_un = unsafe { zeroed() };
+  addr.sun_family = libc::AF_UNIX as libc::sa_family_t;
+  if path.is_empty() || path.len() >= addr.sun_path.len() {
+    return Err(IoError::from_raw_os_error(libc::EINVAL));
+  }
+  for (dst, &x) in addr.sun_path.iter_mut().zip(path.iter()) {
+    *dst = x as libc::c_char;
+  }
+  if path[0] == b'@' {
+    addr.sun_path[0] = 0;
+  }
+  let addr_len = size_of::<libc::sa_family_t>() + path.len();
+  let fd = cvt(unsafe { libc::socket(libc::AF_UNIX, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0) })?;
+  let sock = unsafe { UnixDatagram::from_raw_fd(fd) };
+  cvt(unsafe { libc::connect(fd, &addr as *const libc::sockaddr_un as *const libc::sockaddr, addr_len as libc::socklen_t) })?;
+  Ok(sock)
+}
+
+const PW_BUF_LEN: usize = 16384;
+
+// Returns `(uid, gid)` of the named user.
+pub fn lookup_user(name: &str) -> Result<Option<(u32, u32)>, IoError> {
+  let name = CString::new(name).map_err(|_| IoError::from_raw_os_error(libc::EINVAL))?;
+  let mut pwd: libc::passwd = unsafe { zeroed() };
+  let mut buf = vec![0 as libc::c_char; PW_BUF_LEN];
+  let mut result: *mut libc::passwd = std::ptr::null_mut();
+  let ret = unsafe { libc::getpwnam_r(name.as_ptr(), &mut pwd, buf.as_mut_ptr(), buf.len(), &mut result) };
+  if ret != 0 {
+    return Err(IoError::from_raw_os_error(ret));
+  }
+  if result.is_null() {
+    return Ok(None);
+  }
+  Ok(Some((pwd.pw_uid, pwd.pw_gid)))
+}
+
+pub fn lookup_group(name: &str) -> Result<Option<u32>, IoError> {
+  let name = CString::new(name).map_err(|_| IoError::from_raw_os_error(libc::EINVAL))?;
+  let mut grp: libc::group = unsafe { zeroed() };
+  let mut buf = vec![0 as libc::c_char; PW_BUF_LEN];
+  let mut result: *mut libc::group = std::ptr::null_mut();
+  let ret = unsafe { libc::getgrnam_r(name.as_ptr(), &mut grp, buf.as_mut_ptr(), buf.len(), &mut result) };
+  if ret != 0 {
+    return Err(IoError::from_raw_os_error(ret));
+  }
+  if result.is_null() {
+    return Ok(None);
+  }
+  Ok(Some(grp.gr_gid))
+}
+
+// Returns `(uid, euid, gid, egid)`.
+pub fn current_ids() -> (u32, u32, u32, u32) {
+  unsafe { (libc::getuid(), libc::geteuid(), libc::getgid(), libc::getegid()) }
+}
+
+fn prctl(option: libc::c_int, arg2: libc::c_ulong) -> Result<libc::c_int, IoError> {
+  cvt(unsafe { libc::prctl(option, arg2, 0 as libc::c_ulong, 0 as libc::c_ulong, 0 as libc::c_ulong) })
+}
+
+pub const CAP_NET_BIND_SERVICE: u32 = 10;
+
+const CAP_VERSION_3: u32 = 0x20080522;
+
+#[repr(C)]
+struct CapHeader {
+  version: u32,
+  pid: libc::c_int,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct CapData {
+  effective: u32,
+  permitted: u32,
+  inheritable: u32,
+}
+
+fn cap_mask(caps: &[u32]) -> u64 {
+  caps.iter().fold(0, |mask, &cap| mask | (1_u64 << cap))
+}
+
+// Drops every capability not in `keep` from the bounding set; requires
+// CAP_SETPCAP, i.e. must run before dropping root.
+pub fn capbset_drop_except(keep: &[u32]) -> Result<(), IoError> {
+  for cap in 0 .. 64 {
+    if keep.contains(&cap) {
+      continue;
+    }
+    match prctl(libc::PR_CAPBSET_DROP, cap as libc::c_ulong) {
+      Err(ref e) if e.raw_os_error() == Some(libc::EINVAL) => break,
+      Err(e) => return Err(e),
+      Ok(_) => {}
+    }
+  }
+  Ok(())
+}
+
+pub fn set_keepcaps(keep: bool) -> Result<(), IoError> {
+  prctl(libc::PR_SET_KEEPCAPS, keep as libc::c_ulong)?;
+  Ok(())
+}
+
+pub fn clear_ambient_caps() -> Result<(), IoError> {
+  cvt(unsafe { libc::prctl(libc::PR_CAP_AMBIENT, libc::PR_CAP_AMBIENT_CLEAR_ALL as libc::c_ulong, 0 as libc::c_ulong, 0 as libc::c_ulong, 0 as libc::c_ulong) })?;
+  Ok(())
+}
+
+// Sets the effective and permitted sets to exactly `caps`, and clears the
+// inheritable set.
+pub fn capset(caps: &[u32]) -> Result<(), IoError> {
+  let mask = cap_mask(caps);
+  let mut hdr = CapHeader{version: CAP_VERSION_3, pid: 0};
+  let mut data = [CapData::default(); 2];
+  for i in 0 .. 2 {
+    data[i].effective = (mask >> (32 * i)) as u32;
+    data[i].permitted = (mask >> (32 * i)) as u32;
+  }
+  let ret = unsafe { libc::syscall(libc::SYS_capset, &mut hdr as *mut CapHeader, data.as_ptr()) };
+  cvt(ret as libc::c_int)?;
+  Ok(())
+}
+
+// Returns the `(effective, permitted)` capability masks.
+pub fn capget() -> Result<(u64, u64), IoError> {
+  let mut hdr = CapHeader{version: CAP_VERSION_3, pid: 0};
+  let mut data = [CapData::default(); 2];
+  let ret = unsafe { libc::syscall(libc::SYS_capget, &mut hdr as *mut CapHeader, data.as_mut_ptr()) };
+  cvt(ret as libc::c_int)?;
+  let effective = (data[0].effective as u64) | ((data[1].effective as u64) << 32);
+  let permitted = (data[0].permitted as u64) | ((data[1].permitted as u64) << 32);
+  Ok((effective, permitted))
+}
+
+pub fn set_no_new_privs() -> Result<(), IoError> {
+  prctl(libc::PR_SET_NO_NEW_PRIVS, 1)?;
+  Ok(())
+}
+
+const BPF_LD_W_ABS: u16 = 0x00 | 0x00 | 0x20;
+const BPF_JMP_JEQ_K: u16 = 0x05 | 0x10 | 0x00;
+const BPF_RET_K: u16 = 0x06 | 0x00;
+
+const SECCOMP_DATA_NR: u32 = 0;
+const SECCOMP_DATA_ARCH: u32 = 4;
+
+pub const SECCOMP_RET_ALLOW: u32 = 0x7fff_0000;
+pub const SECCOMP_RET_LOG: u32 = 0x7ffc_0000;
+pub const SECCOMP_RET_KILL_PROCESS: u32 = 0x8000_0000;
+
+const SECCOMP_SET_MODE_FILTER: libc::c_ulong = 1;
+const SECCOMP_FILTER_FLAG_TSYNC: libc::c_ulong = 1;
+
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: u32 = 0xc000_003e;
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: u32 = 0xc000_00b7;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct SockFilter {
+  code: u16,
+  jt: u8,
+  jf: u8,
+  k: u32,
+}
+
+#[repr(C)]
+struct SockFprog {
+  len: libc::c_ushort,
+  filter: *const SockFilter,
+}
+
+fn bpf_stmt(code: u16, k: u32) -> SockFilter {
+  SockFilter{code, jt: 0, jf: 0, k}
+}
+
+fn bpf_jump(code: u16, k: u32, jt: u8, jf: u8) -> SockFilter {
+  SockFilter{code, jt, jf, k}
+}
+
+// Installs a seccomp filter on all threads of the process that allows the
+// syscalls in `allow` and returns `default_action` for everything else.
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+pub fn seccomp_allowlist(allow: &[libc::c_long], default_action: u32) -> Result<(), IoError> {
+  let mut prog = Vec::with_capacity(allow.len() * 2 + 5);
+  prog.push(bpf_stmt(BPF_LD_W_ABS, SECCOMP_DATA_ARCH));
+  prog.push(bpf_jump(BPF_JMP_JEQ_K, AUDIT_ARCH, 1, 0));
+  prog.push(bpf_stmt(BPF_RET_K, default_action));
+  prog.push(bpf_stmt(BPF_LD_W_ABS, SECCOMP_DATA_NR));
+  for &nr in allow.iter() {
+    prog.push(bpf_jump(BPF_JMP_JEQ_K, nr as u32, 0, 1));
+    prog.push(bpf_stmt(BPF_RET_K, SECCOMP_RET_ALLOW));
+  }
+  prog.push(bpf_stmt(BPF_RET_K, default_action));
+  if prog.len() > libc::c_ushort::max_value() as usize {
+    return Err(IoError::from_raw_os_error(libc::E2BIG));
+  }
+  let fprog = SockFprog{len: prog.len() as libc::c_ushort, filter: prog.as_ptr()};
+  let ret = unsafe { libc::syscall(libc::SYS_seccomp, SECCOMP_SET_MODE_FILTER, SECCOMP_FILTER_FLAG_TSYNC, &fprog as *const SockFprog) };
+  if ret != 0 {
+    return Err(if ret < 0 { IoError::last_os_error() } else { IoError::from_raw_os_error(libc::ESRCH) });
+  }
+  Ok(())
+}
+
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+pub fn seccomp_allowlist(_allow: &[libc::c_long], _default_action: u32) -> Result<(), IoError> {
+  Err(IoError::from_raw_os_error(libc::ENOSYS))
+}
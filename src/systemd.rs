@@ -0,0 +1,99 @@
+#![forbid(unsafe_code)]
+
+use smol_str::{SmolStr};
+
+use std::env;
+use std::net::{TcpListener};
+use std::os::unix::ffi::{OsStrExt};
+use std::os::unix::net::{UnixDatagram};
+use std::sync::{Mutex};
+use std::time::{Duration as StdDuration};
+
+static NOTIFY: Mutex<Option<UnixDatagram>> = Mutex::new(None);
+
+// Takes the sockets passed by systemd socket activation, if any were passed
+// to this process, with their `FileDescriptorName=` (`unknown` if unset).
+pub fn listen_fds() -> Vec<(SmolStr, TcpListener)> {
+  let pid = env::var("LISTEN_PID").ok().and_then(|s| s.parse::<u32>().ok());
+  let nfds = env::var("LISTEN_FDS").ok().and_then(|s| s.parse::<i32>().ok());
+  let names = env::var("LISTEN_FDNAMES").unwrap_or_default();
+  env::remove_var("LISTEN_PID");
+  env::remove_var("LISTEN_FDS");
+  env::remove_var("LISTEN_FDNAMES");
+  match (pid, nfds) {
+    (Some(pid), Some(nfds)) if pid == std::process::id() && nfds > 0 => {
+      let mut names = names.split(':').filter(|s| !s.is_empty());
+      let mut listeners = Vec::with_capacity(nfds as usize);
+      for res in crate::sys::take_listen_fds(nfds) {
+        let name: SmolStr = names.next().unwrap_or("unknown").into();
+        match res {
+          Err((fd, e)) => {
+            log_warn!("systemd: listen fd {} ({}): {:?}", fd, name, e);
+          }
+          Ok(listener) => listeners.push((name, listener))
+        }
+      }
+      log_info!("systemd: socket activation: fds = {}", listeners.len());
+      listeners
+    }
+    _ => Vec::new()
+  }
+}
+
+// Connects to `NOTIFY_SOCKET`; must be called before chroot, since the
+// notify socket path is usually outside of it.
+pub fn init_notify() {
+  let path = match env::var_os("NOTIFY_SOCKET") {
+    None => return,
+    Some(path) => path
+  };
+  env::remove_var("NOTIFY_SOCKET");
+  match crate::sys::unix_dgram_connect(path.as_bytes()) {
+    Err(e) => {
+      log_warn!("systemd: failed to connect notify socket {:?}: {:?}", path, e);
+    }
+    Ok(sock) => {
+      log_info!("systemd: notify socket: {:?}", path);
+      *NOTIFY.lock().unwrap() = Some(sock);
+    }
+  }
+}
+
+pub fn notify(state: &str) {
+  let notify = NOTIFY.lock().unwrap();
+  if let Some(sock) = notify.as_ref() {
+    if let Err(e) = sock.send(state.as_bytes()) {
+      log_warn!("systemd: notify {:?}: {:?}", state, e);
+    }
+  }
+}
+
+pub fn notify_ready() {
+  notify("READY=1");
+}
+
+pub fn notify_reloading() {
+  notify("RELOADING=1");
+}
+
+pub fn notify_stopping() {
+  notify("STOPPING=1");
+}
+
+pub fn notify_watchdog() {
+  notify("WATCHDOG=1");
+}
+
+// Returns the interval at which to ping the watchdog (half of
+// `WATCHDOG_USEC`), if the watchdog is enabled for this process.
+pub fn watchdog_interval() -> Option<StdDuration> {
+  let usec = env::var("WATCHDOG_USEC").ok().and_then(|s| s.parse::<u64>().ok())?;
+  match env::var("WATCHDOG_PID").ok().and_then(|s| s.parse::<u32>().ok()) {
+    Some(pid) if pid != std::process::id() => return None,
+    _ => {}
+  }
+  if usec == 0 {
+    return None;
+  }
+  Some(StdDuration::from_micros(usec / 2))
+}
@@ -0,0 +1,289 @@
+#![forbid(unsafe_code)]
+
+// Reads the ClientHello ahead of the TLS handshake, for the SNI name and
+// the highest protocol version the client offers, neither of which
+// native_tls exposes. The bytes read are replayed to the handshake by
+// `HelloStream`.
+
+use smol_str::{SmolStr};
+
+use std::io::{Error as IoError, ErrorKind as IoErrorKind, Read, Write};
+use std::mem::{replace};
+use std::net::{TcpStream};
+
+// NB: a ClientHello larger than this is passed on unparsed.
+pub const MAX_HELLO: usize = 5 + 16384;
+
+#[derive(Clone, Default, PartialEq, Eq, Debug)]
+pub struct ClientHello {
+  pub sni: Option<SmolStr>,
+  // The highest version offered, e.g. `TLSv1.3`; the handshake may settle
+  // on a lower one.
+  pub version: Option<SmolStr>,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum HelloParse {
+  Incomplete,
+  // Also for input that is not a ClientHello at all, which the handshake
+  // will then reject.
+  Done(ClientHello),
+}
+
+fn be16(buf: &[u8], pos: usize) -> Option<usize> {
+  Some(((*buf.get(pos)? as usize) << 8) | *buf.get(pos + 1)? as usize)
+}
+
+fn version_name(v: usize) -> Option<SmolStr> {
+  let name = match v {
+    0x0304 => "TLSv1.3",
+    0x0303 => "TLSv1.2",
+    0x0302 => "TLSv1.1",
+    0x0301 => "TLSv1",
+    0x0300 => "SSLv3",
+    _ => return None
+  };
+  Some(name.into())
+}
+
+// Parses the ClientHello from the start of the client's first bytes, which
+// may span several handshake records.
+pub fn parse_client_hello(buf: &[u8]) -> HelloParse {
+  let mut msg = Vec::new();
+  let mut pos = 0;
+  loop {
+    // NB: the handshake message length is known once its header is in.
+    if msg.len() >= 4 && msg.len() >= 4 + ((msg[1] as usize) << 16 | (msg[2] as usize) << 8 | msg[3] as usize) {
+      break;
+    }
+    if buf.len() < pos + 5 {
+      return HelloParse::Incomplete;
+    }
+    if buf[pos] != 0x16 {
+      return HelloParse::Done(ClientHello::default());
+    }
+    let len = be16(buf, pos + 3).unwrap();
+    if buf.len() < pos + 5 + len {
+      return HelloParse::Incomplete;
+    }
+    msg.extend_from_slice(&buf[pos + 5 .. pos + 5 + len]);
+    pos += 5 + len;
+    if msg.first().map_or(false, |&t| t != 0x01) {
+      return HelloParse::Done(ClientHello::default());
+    }
+  }
+  HelloParse::Done(parse_hello_body(&msg[4 .. ]).unwrap_or_default())
+}
+
+fn parse_hello_body(body: &[u8]) -> Option<ClientHello> {
+  let mut hello = ClientHello::default();
+  let legacy_version = be16(body, 0)?;
+  // Random.
+  let mut pos = 2 + 32;
+  let session_id_len = *body.get(pos)? as usize;
+  pos += 1 + session_id_len;
+  let suites_len = be16(body, pos)?;
+  pos += 2 + suites_len;
+  let compression_len = *body.get(pos)? as usize;
+  pos += 1 + compression_len;
+  let mut max_version = None;
+  if let Some(ext_len) = be16(body, pos) {
+    pos += 2;
+    let end = (pos + ext_len).min(body.len());
+    while pos + 4 <= end {
+      let ext_type = be16(body, pos)?;
+      let len = be16(body, pos + 2)?;
+      let data = body.get(pos + 4 .. pos + 4 + len)?;
+      pos += 4 + len;
+      match ext_type {
+        // server_name: a list of (type, name); only host_name (0) exists.
+        0x0000 => {
+          let mut p = 2;
+          while p + 3 <= data.len() {
+            let name_len = be16(data, p + 1)?;
+            let name = data.get(p + 3 .. p + 3 + name_len)?;
+            if data[p] == 0 && !name.is_empty() &&
+               name.iter().all(|&x| x.is_ascii_alphanumeric() || x == b'-' || x == b'.' || x == b'_')
+            {
+              hello.sni = Some(String::from_utf8_lossy(name).to_ascii_lowercase().into());
+              break;
+            }
+            p += 3 + name_len;
+          }
+        }
+        // supported_versions.
+        0x002b => {
+          let n = *data.get(0)? as usize;
+          for v in data.get(1 .. 1 + n)?.chunks(2) {
+            if v.len() != 2 {
+              continue;
+            }
+            let v = ((v[0] as usize) << 8) | v[1] as usize;
+            // NB: skips GREASE values like 0x0a0a.
+            if v & 0x0f0f == 0x0a0a {
+              continue;
+            }
+            max_version = max_version.max(Some(v));
+          }
+        }
+        _ => {}
+      }
+    }
+  }
+  hello.version = version_name(max_version.unwrap_or(legacy_version));
+  Some(hello)
+}
+
+// A client connection whose first bytes were read to find the ClientHello,
+// and are read again from the buffer by the handshake.
+#[derive(Debug)]
+pub struct HelloStream {
+  stream: TcpStream,
+  buf: Vec<u8>,
+  pos: usize,
+  // Set when a write would block, e.g. while the handshake sends its
+  // flight to a slow client.
+  write_blocked: bool,
+}
+
+impl HelloStream {
+  pub fn new(stream: TcpStream) -> HelloStream {
+    HelloStream{stream, buf: Vec::new(), pos: 0, write_blocked: false}
+  }
+
+  pub fn get_ref(&self) -> &TcpStream {
+    &self.stream
+  }
+
+  // Whether a write blocked since the last call.
+  pub fn take_write_blocked(&mut self) -> bool {
+    replace(&mut self.write_blocked, false)
+  }
+
+  // Reads until the ClientHello is complete; on a nonblocking stream,
+  // returns `Ok(None)` when it has to wait for more.
+  pub fn read_hello(&mut self) -> Result<Option<ClientHello>, IoError> {
+    loop {
+      if let HelloParse::Done(hello) = parse_client_hello(&self.buf) {
+        return Ok(Some(hello));
+      }
+      if self.buf.len() >= MAX_HELLO {
+        return Ok(Some(ClientHello::default()));
+      }
+      let mut rbuf = [0; 4096];
+      let want = rbuf.len().min(MAX_HELLO - self.buf.len());
+      match self.stream.read(&mut rbuf[ .. want]) {
+        Ok(0) => return Err(IoError::new(IoErrorKind::UnexpectedEof, "tls: eof in ClientHello")),
+        Ok(n) => self.buf.extend_from_slice(&rbuf[ .. n]),
+        Err(ref e) if e.kind() == IoErrorKind::WouldBlock => return Ok(None),
+        Err(ref e) if e.kind() == IoErrorKind::Interrupted => {}
+        Err(e) => return Err(e)
+      }
+    }
+  }
+}
+
+impl Read for HelloStream {
+  fn read(&mut self, buf: &mut [u8]) -> Result<usize, IoError> {
+    if self.pos < self.buf.len() {
+      let n = buf.len().min(self.buf.len() - self.pos);
+      buf[ .. n].copy_from_slice(&self.buf[self.pos .. self.pos + n]);
+      self.pos += n;
+      if self.pos == self.buf.len() {
+        self.buf = Vec::new();
+        self.pos = 0;
+      }
+      return Ok(n);
+    }
+    self.stream.read(buf)
+  }
+}
+
+impl Write for HelloStream {
+  fn write(&mut self, buf: &[u8]) -> Result<usize, IoError> {
+    let res = self.stream.write(buf);
+    if let Err(ref e) = res {
+      if e.kind() == IoErrorKind::WouldBlock {
+        self.write_blocked = true;
+      }
+    }
+    res
+  }
+
+  fn flush(&mut self) -> Result<(), IoError> {
+    self.stream.flush()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // A ClientHello with the given extensions, as one or more records of at
+  // most `record_len` bytes.
+  fn hello(legacy_version: u16, exts: &[(u16, Vec<u8>)], record_len: usize) -> Vec<u8> {
+    let mut body = Vec::new();
+    body.extend_from_slice(&legacy_version.to_be_bytes());
+    body.extend_from_slice(&[7; 32]);
+    body.push(0);
+    body.extend_from_slice(&[0, 2, 0x13, 0x01]);
+    body.extend_from_slice(&[1, 0]);
+    let mut ext_buf = Vec::new();
+    for &(t, ref data) in exts.iter() {
+      ext_buf.extend_from_slice(&t.to_be_bytes());
+      ext_buf.extend_from_slice(&(data.len() as u16).to_be_bytes());
+      ext_buf.extend_from_slice(data);
+    }
+    body.extend_from_slice(&(ext_buf.len() as u16).to_be_bytes());
+    body.extend_from_slice(&ext_buf);
+    let mut msg = vec![1, 0, (body.len() >> 8) as u8, body.len() as u8];
+    msg.extend_from_slice(&body);
+    let mut out = Vec::new();
+    for chunk in msg.chunks(record_len) {
+      out.extend_from_slice(&[0x16, 3, 1, (chunk.len() >> 8) as u8, chunk.len() as u8]);
+      out.extend_from_slice(chunk);
+    }
+    out
+  }
+
+  fn sni(name: &str) -> (u16, Vec<u8>) {
+    let mut data = Vec::new();
+    data.extend_from_slice(&((name.len() + 3) as u16).to_be_bytes());
+    data.push(0);
+    data.extend_from_slice(&(name.len() as u16).to_be_bytes());
+    data.extend_from_slice(name.as_bytes());
+    (0x0000, data)
+  }
+
+  fn versions(vs: &[u16]) -> (u16, Vec<u8>) {
+    let mut data = vec![(vs.len() * 2) as u8];
+    for v in vs.iter() {
+      data.extend_from_slice(&v.to_be_bytes());
+    }
+    (0x002b, data)
+  }
+
+  fn done(sni: Option<&str>, version: Option<&str>) -> HelloParse {
+    HelloParse::Done(ClientHello{sni: sni.map(|s| s.into()), version: version.map(|s| s.into())})
+  }
+
+  #[test]
+  fn test_parse_client_hello() {
+    let cases: Vec<(&str, Vec<u8>, HelloParse)> = vec![
+      ("empty", vec![], HelloParse::Incomplete),
+      ("tls 1.2", hello(0x0303, &[sni("Example.COM")], 1 << 14), done(Some("example.com"), Some("TLSv1.2"))),
+      ("tls 1.3", hello(0x0303, &[versions(&[0x3a3a, 0x0304, 0x0303]), sni("a.example.com")], 1 << 14), done(Some("a.example.com"), Some("TLSv1.3"))),
+      ("no sni", hello(0x0301, &[], 1 << 14), done(None, Some("TLSv1"))),
+      ("split records", hello(0x0303, &[sni("example.com")], 16), done(Some("example.com"), Some("TLSv1.2"))),
+      ("bad sni", hello(0x0303, &[sni("exa mple")], 1 << 14), done(None, Some("TLSv1.2"))),
+      ("not tls", b"GET / HTTP/1.1\r\n\r\n".to_vec(), HelloParse::Done(ClientHello::default())),
+    ];
+    for (name, buf, expected) in cases {
+      assert_eq!(parse_client_hello(&buf), expected, "case: {}", name);
+    }
+    let buf = hello(0x0303, &[sni("example.com")], 16);
+    for len in 0 .. buf.len() {
+      assert_eq!(parse_client_hello(&buf[ .. len]), HelloParse::Incomplete, "prefix: {}", len);
+    }
+  }
+}
@@ -0,0 +1,503 @@
+#![forbid(unsafe_code)]
+
+use crate::log::{json_escape_into};
+
+use smol_str::{SmolStr};
+
+use std::fmt::{Write as FmtWrite};
+use std::io::{Error as IoError, ErrorKind as IoErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::{Mutex};
+use std::sync::mpsc::{Receiver, RecvTimeoutError, SyncSender, TrySendError, sync_channel};
+use std::thread::{Builder as ThreadBuilder};
+use std::time::{Duration as StdDuration, Instant, SystemTime, UNIX_EPOCH};
+
+pub const TRACEPARENT: &'static str = "traceparent";
+pub const TRACESTATE: &'static str = "tracestate";
+pub const MAX_TRACESTATE_LEN: usize = 512;
+
+#[derive(Clone, Debug)]
+pub struct TraceConfig {
+  // OTLP/HTTP traces endpoint, e.g. `http://127.0.0.1:4318/v1/traces`.
+  // NB: plain HTTP only; point this at a local collector.
+  pub endpoint: SmolStr,
+  pub service_name: SmolStr,
+  // Fraction of new traces (without an incoming `traceparent`) to sample.
+  pub sample_ratio: f64,
+  pub batch_size: usize,
+  pub flush_interval: StdDuration,
+  // Spans beyond this many pending exports are dropped.
+  pub queue_cap: usize,
+}
+
+impl TraceConfig {
+  pub fn new<S: AsRef<str>>(endpoint: S) -> TraceConfig {
+    TraceConfig{
+      endpoint: endpoint.as_ref().into(),
+      service_name: "proxy_gateway".into(),
+      sample_ratio: 1.0,
+      batch_size: 512,
+      flush_interval: StdDuration::from_secs(2),
+      queue_cap: 4096,
+    }
+  }
+}
+
+pub type TraceId = [u8; 16];
+pub type SpanId = [u8; 8];
+
+pub const FLAG_SAMPLED: u8 = 0x01;
+
+// The trace context of an incoming request, after W3C Trace Context.
+#[derive(Clone, Debug)]
+pub struct TraceCtx {
+  pub trace_id: TraceId,
+  pub parent_id: Option<SpanId>,
+  pub flags: u8,
+  pub tracestate: Option<SmolStr>,
+}
+
+impl TraceCtx {
+  // Continues the incoming trace if `traceparent` is valid, otherwise
+  // starts a new one.
+  pub fn from_headers(traceparent: Option<&[u8]>, tracestate: Option<&[u8]>, sample_ratio: f64) -> TraceCtx {
+    match traceparent.and_then(parse_traceparent) {
+      Some((trace_id, parent_id, flags)) => TraceCtx{
+        trace_id,
+        parent_id: Some(parent_id),
+        flags,
+        tracestate: tracestate
+          .filter(|v| v.len() <= MAX_TRACESTATE_LEN)
+          .map(|v| crate::safe_ascii(v)),
+      },
+      None => {
+        let mut trace_id = [0; 16];
+        trace_id[ .. 8].copy_from_slice(&crate::request_id::unique_u64().to_be_bytes());
+        trace_id[8 .. ].copy_from_slice(&crate::request_id::unique_u64().to_be_bytes());
+        let sampled = (crate::request_id::unique_u64() as f64 / u64::MAX as f64) < sample_ratio;
+        TraceCtx{
+          trace_id,
+          parent_id: None,
+          flags: if sampled { FLAG_SAMPLED } else { 0 },
+          tracestate: None,
+        }
+      }
+    }
+  }
+
+  pub fn sampled(&self) -> bool {
+    self.flags & FLAG_SAMPLED != 0
+  }
+
+  pub fn traceparent(&self, span_id: &SpanId) -> String {
+    format!("00-{}-{}-{:02x}", hex(&self.trace_id), hex(span_id), self.flags)
+  }
+}
+
+pub fn new_span_id() -> SpanId {
+  crate::request_id::unique_u64().to_be_bytes()
+}
+
+// Parses `00-<trace-id>-<parent-id>-<flags>`; later versions are parsed
+// as version 00, ignoring any trailing fields.
+pub fn parse_traceparent(v: &[u8]) -> Option<(TraceId, SpanId, u8)> {
+  if v.len() < 55 || (v.len() > 55 && v[55] != b'-') {
+    return None;
+  }
+  let version = unhex::<1>(&v[0 .. 2])?[0];
+  if version == 0xff || (version == 0 && v.len() != 55) {
+    return None;
+  }
+  if v[2] != b'-' || v[35] != b'-' || v[52] != b'-' {
+    return None;
+  }
+  let trace_id = unhex::<16>(&v[3 .. 35])?;
+  let parent_id = unhex::<8>(&v[36 .. 52])?;
+  let flags = unhex::<1>(&v[53 .. 55])?[0];
+  if trace_id == [0; 16] || parent_id == [0; 8] {
+    return None;
+  }
+  Some((trace_id, parent_id, flags))
+}
+
+fn unhex<const N: usize>(s: &[u8]) -> Option<[u8; N]> {
+  if s.len() != 2 * N {
+    return None;
+  }
+  let digit = |x: u8| -> Option<u8> {
+    match x {
+      b'0' ..= b'9' => Some(x - b'0'),
+      b'a' ..= b'f' => Some(x - b'a' + 10),
+      // NB: the spec only allows lowercase.
+      _ => None
+    }
+  };
+  let mut out = [0; N];
+  for i in 0 .. N {
+    out[i] = (digit(s[2 * i])? << 4) | digit(s[2 * i + 1])?;
+  }
+  Some(out)
+}
+
+pub fn hex(b: &[u8]) -> String {
+  let mut s = String::with_capacity(2 * b.len());
+  for x in b.iter() {
+    let _ = write!(&mut s, "{:02x}", x);
+  }
+  s
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SpanKind {
+  Internal = 1,
+  Server = 2,
+  Client = 3,
+}
+
+#[derive(Clone, Debug)]
+pub struct Span {
+  pub trace_id: TraceId,
+  pub span_id: SpanId,
+  pub parent_id: Option<SpanId>,
+  pub name: &'static str,
+  pub kind: SpanKind,
+  pub start: u64,
+  pub end: u64,
+  pub attrs: Vec<(&'static str, SmolStr)>,
+  pub error: bool,
+}
+
+// Maps `Instant`s onto wall clock nanoseconds since the epoch.
+#[derive(Clone, Copy, Debug)]
+pub struct Clock {
+  unix_nanos: u64,
+  t0: Instant,
+}
+
+impl Clock {
+  pub fn now() -> Clock {
+    let unix_nanos = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos() as u64).unwrap_or(0);
+    Clock{unix_nanos, t0: Instant::now()}
+  }
+
+  pub fn nanos(&self, t: Instant) -> u64 {
+    if t >= self.t0 {
+      self.unix_nanos + (t - self.t0).as_nanos() as u64
+    } else {
+      self.unix_nanos.saturating_sub((self.t0 - t).as_nanos() as u64)
+    }
+  }
+}
+
+struct Exporter {
+  tx: SyncSender<Span>,
+}
+
+static EXPORTER: Mutex<Option<Exporter>> = Mutex::new(None);
+
+pub fn enabled() -> bool {
+  EXPORTER.lock().unwrap().is_some()
+}
+
+// NB: resolves the collector address up front, since name resolution may
+// not work once the process is sandboxed.
+pub fn init(cfg: &TraceConfig) -> Result<(), IoError> {
+  let (host, path) = parse_endpoint(&cfg.endpoint)
+    .ok_or_else(|| IoError::new(IoErrorKind::InvalidInput, "expected http://host:port/path"))?;
+  let addr = host.to_socket_addrs()?.next()
+    .ok_or_else(|| IoError::new(IoErrorKind::NotFound, "collector address did not resolve"))?;
+  let (tx, rx) = sync_channel(cfg.queue_cap);
+  let cfg = cfg.clone();
+  let host: SmolStr = host.into();
+  let path: SmolStr = path.into();
+  log_info!("trace: exporting to {} ({})", cfg.endpoint, addr);
+  ThreadBuilder::new()
+    .name("trace-export".into())
+    .spawn(move || export_loop(cfg, addr, host, path, rx))?;
+  *EXPORTER.lock().unwrap() = Some(Exporter{tx});
+  Ok(())
+}
+
+pub fn record(span: Span) {
+  let exporter = EXPORTER.lock().unwrap();
+  if let Some(exporter) = exporter.as_ref() {
+    match exporter.tx.try_send(span) {
+      Ok(_) => {}
+      Err(TrySendError::Full(_)) => {
+        log_debug!("trace: export queue full, dropping span");
+      }
+      Err(TrySendError::Disconnected(_)) => {}
+    }
+  }
+}
+
+fn parse_endpoint(endpoint: &str) -> Option<(&str, &str)> {
+  let rest = endpoint.strip_prefix("http://")?;
+  let (host, path) = match rest.find('/') {
+    None => (rest, "/v1/traces"),
+    Some(pos) => (&rest[ .. pos], &rest[pos .. ])
+  };
+  if host.is_empty() || !host.contains(':') {
+    return None;
+  }
+  Some((host, path))
+}
+
+fn export_loop(cfg: TraceConfig, addr: SocketAddr, host: SmolStr, path: SmolStr, rx: Receiver<Span>) {
+  let mut batch = Vec::with_capacity(cfg.batch_size);
+  let mut last_flush = Instant::now();
+  loop {
+    let timeout = cfg.flush_interval.checked_sub(last_flush.elapsed()).unwrap_or(StdDuration::from_millis(0));
+    match rx.recv_timeout(timeout) {
+      Ok(span) => batch.push(span),
+      Err(RecvTimeoutError::Timeout) => {}
+      Err(RecvTimeoutError::Disconnected) => return,
+    }
+    if batch.len() < cfg.batch_size && last_flush.elapsed() < cfg.flush_interval {
+      continue;
+    }
+    last_flush = Instant::now();
+    if batch.is_empty() {
+      continue;
+    }
+    let body = encode_otlp_json(&cfg.service_name, &batch);
+    match post(addr, &host, &path, body.as_bytes()) {
+      Err(e) => {
+        log_warn!("trace: export of {} spans failed: {:?}", batch.len(), e);
+      }
+      Ok(status) if status / 100 != 2 => {
+        log_warn!("trace: export of {} spans failed: status = {}", batch.len(), status);
+      }
+      Ok(_) => {
+        log_debug!("trace: exported {} spans", batch.len());
+      }
+    }
+    batch.clear();
+  }
+}
+
+fn post(addr: SocketAddr, host: &str, path: &str, body: &[u8]) -> Result<u16, IoError> {
+  let timeout = StdDuration::from_secs(5);
+  let mut stream = TcpStream::connect_timeout(&addr, timeout)?;
+  stream.set_read_timeout(Some(timeout))?;
+  stream.set_write_timeout(Some(timeout))?;
+  let head = format!(
+      "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+      path, host, body.len());
+  stream.write_all(head.as_bytes())?;
+  stream.write_all(body)?;
+  stream.flush()?;
+  let mut rbuf = [0; 64];
+  let mut r_sz = 0;
+  while r_sz < 12 {
+    match stream.read(&mut rbuf[r_sz .. ])? {
+      0 => break,
+      n => r_sz += n,
+    }
+  }
+  crate::access::response_status(&rbuf[ .. r_sz])
+    .ok_or_else(|| IoError::new(IoErrorKind::InvalidData, "invalid status line"))
+}
+
+// NB: the OTLP/JSON encoding, with hex ids and 64-bit integers as strings.
+pub fn encode_otlp_json(service_name: &str, spans: &[Span]) -> String {
+  let mut buf = String::new();
+  buf.push_str("{\"resourceSpans\":[{\"resource\":{\"attributes\":[");
+  push_attr(&mut buf, "service.name", service_name);
+  buf.push_str("]},\"scopeSpans\":[{\"scope\":{\"name\":\"proxy_gateway\"},\"spans\":[");
+  for (i, span) in spans.iter().enumerate() {
+    if i > 0 {
+      buf.push(',');
+    }
+    let _ = write!(&mut buf, "{{\"traceId\":\"{}\",\"spanId\":\"{}\"", hex(&span.trace_id), hex(&span.span_id));
+    if let Some(parent_id) = span.parent_id.as_ref() {
+      let _ = write!(&mut buf, ",\"parentSpanId\":\"{}\"", hex(parent_id));
+    }
+    let _ = write!(&mut buf, ",\"name\":\"{}\",\"kind\":{},\"startTimeUnixNano\":\"{}\",\"endTimeUnixNano\":\"{}\",\"attributes\":[",
+        span.name, span.kind as u8, span.start, span.end);
+    for (j, &(key, ref value)) in span.attrs.iter().enumerate() {
+      if j > 0 {
+        buf.push(',');
+      }
+      push_attr(&mut buf, key, value);
+    }
+    buf.push(']');
+    if span.error {
+      buf.push_str(",\"status\":{\"code\":2}");
+    }
+    buf.push('}');
+  }
+  buf.push_str("]}]}]}");
+  buf
+}
+
+fn push_attr(buf: &mut String, key: &str, value: &str) {
+  let _ = write!(buf, "{{\"key\":\"{}\",\"value\":{{\"stringValue\":\"", key);
+  json_escape_into(buf, value);
+  buf.push_str("\"}}");
+}
+
+// The spans of one request as seen by the gateway: a server span with a
+// child for each phase.
+#[derive(Clone, Debug)]
+pub struct RequestTrace {
+  pub ctx: TraceCtx,
+  pub span_id: SpanId,
+  // Also the parent id sent to the backend in `traceparent`.
+  pub backend_span_id: SpanId,
+  clock: Clock,
+  pub tls: Option<(Instant, Instant)>,
+  pub parse: (Instant, Instant),
+  pub queued: Option<Instant>,
+  pub sent: Option<Instant>,
+  pub received: Option<Instant>,
+  pub write: Option<Instant>,
+}
+
+impl RequestTrace {
+  pub fn new(ctx: TraceCtx, parse: (Instant, Instant)) -> RequestTrace {
+    RequestTrace{
+      ctx,
+      span_id: new_span_id(),
+      backend_span_id: new_span_id(),
+      clock: Clock::now(),
+      tls: None,
+      parse,
+      queued: None,
+      sent: None,
+      received: None,
+      write: None,
+    }
+  }
+
+  pub fn backend_traceparent(&self) -> String {
+    self.ctx.traceparent(&self.backend_span_id)
+  }
+
+  fn span(&self, name: &'static str, kind: SpanKind, span_id: SpanId, start: Instant, end: Instant) -> Span {
+    Span{
+      trace_id: self.ctx.trace_id,
+      span_id,
+      parent_id: Some(self.span_id),
+      name,
+      kind,
+      start: self.clock.nanos(start),
+      end: self.clock.nanos(end),
+      attrs: Vec::new(),
+      error: false,
+    }
+  }
+
+  // Records the spans, ending the server span at `end`.
+  pub fn export(&self, end: Instant, attrs: Vec<(&'static str, SmolStr)>, status: u16, member: Option<&str>) {
+    if !self.ctx.sampled() {
+      return;
+    }
+    let start = self.tls.map_or(self.parse.0, |(t, _)| t);
+    let mut server = self.span("request", SpanKind::Server, self.span_id, start, end);
+    server.parent_id = self.ctx.parent_id;
+    server.attrs = attrs;
+    server.error = status >= 500;
+    record(server);
+    if let Some((t0, t1)) = self.tls {
+      record(self.span("tls_handshake", SpanKind::Internal, new_span_id(), t0, t1));
+    }
+    record(self.span("parse_head", SpanKind::Internal, new_span_id(), self.parse.0, self.parse.1));
+    if let (Some(queued), Some(sent)) = (self.queued, self.sent) {
+      record(self.span("queue_wait", SpanKind::Internal, new_span_id(), queued, sent));
+    }
+    if let (Some(sent), Some(received)) = (self.sent, self.received) {
+      let mut backend = self.span("backend", SpanKind::Client, self.backend_span_id, sent, received);
+      if let Some(member) = member {
+        backend.attrs.push(("server.address", member.into()));
+      }
+      backend.error = status >= 500;
+      record(backend);
+    }
+    if let Some(write) = self.write {
+      record(self.span("write_response", SpanKind::Internal, new_span_id(), write, end));
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const TRACE_ID: TraceId = [0x4b, 0xf9, 0x2f, 0x35, 0x77, 0xb3, 0x4d, 0xa6, 0xa3, 0xce, 0x92, 0x9d, 0x0e, 0x0e, 0x47, 0x36];
+  const PARENT_ID: SpanId = [0x00, 0xf0, 0x67, 0xaa, 0x0b, 0xa9, 0x02, 0xb7];
+
+  #[test]
+  fn test_parse_traceparent() {
+    let cases: Vec<(&str, Option<(TraceId, SpanId, u8)>)> = vec![
+      ("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01", Some((TRACE_ID, PARENT_ID, 0x01))),
+      ("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-00", Some((TRACE_ID, PARENT_ID, 0x00))),
+      // All-zero ids are invalid.
+      ("00-00000000000000000000000000000000-00f067aa0ba902b7-01", None),
+      ("00-4bf92f3577b34da6a3ce929d0e0e4736-0000000000000000-01", None),
+      // Version ff is invalid.
+      ("ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01", None),
+      // Only lowercase hex is allowed.
+      ("00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01", None),
+      ("00-4bf92f3577b34da6a3ce929d0e0e4736-00F067AA0BA902B7-01", None),
+      ("0A-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01", None),
+      // Later versions may append fields after a dash.
+      ("01-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01", Some((TRACE_ID, PARENT_ID, 0x01))),
+      ("cc-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-what-the-future-holds", Some((TRACE_ID, PARENT_ID, 0x01))),
+      ("cc-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01x", None),
+      // Version 00 has exactly four fields.
+      ("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-", None),
+      ("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-1", None),
+      ("00-4bf92f3577b34da6a3ce929d0e0e4736+00f067aa0ba902b7-01", None),
+      ("00-4bf92f3577b34da6a3ce929d0e0e473g-00f067aa0ba902b7-01", None),
+      ("", None),
+    ];
+    for (v, expected) in cases {
+      assert_eq!(parse_traceparent(v.as_bytes()), expected, "traceparent: {:?}", v);
+    }
+  }
+
+  #[test]
+  fn test_encode_otlp_json() {
+    let spans = vec![
+      Span{
+        trace_id: TRACE_ID,
+        span_id: PARENT_ID,
+        parent_id: None,
+        name: "request",
+        kind: SpanKind::Server,
+        start: 1682858096000000000,
+        end: 1682858096005000000,
+        attrs: vec![("http.method", "GET".into()), ("http.target", "/a\"b".into())],
+        error: true,
+      },
+      Span{
+        trace_id: TRACE_ID,
+        span_id: [1, 2, 3, 4, 5, 6, 7, 8],
+        parent_id: Some(PARENT_ID),
+        name: "backend",
+        kind: SpanKind::Client,
+        start: 1682858096001000000,
+        end: 1682858096004000000,
+        attrs: vec![],
+        error: false,
+      },
+    ];
+    let expected = concat!(
+      "{\"resourceSpans\":[{\"resource\":{\"attributes\":[",
+      "{\"key\":\"service.name\",\"value\":{\"stringValue\":\"gw\"}}",
+      "]},\"scopeSpans\":[{\"scope\":{\"name\":\"proxy_gateway\"},\"spans\":[",
+      "{\"traceId\":\"4bf92f3577b34da6a3ce929d0e0e4736\",\"spanId\":\"00f067aa0ba902b7\",",
+      "\"name\":\"request\",\"kind\":2,",
+      "\"startTimeUnixNano\":\"1682858096000000000\",\"endTimeUnixNano\":\"1682858096005000000\",\"attributes\":[",
+      "{\"key\":\"http.method\",\"value\":{\"stringValue\":\"GET\"}},",
+      "{\"key\":\"http.target\",\"value\":{\"stringValue\":\"/a\\\"b\"}}",
+      "],\"status\":{\"code\":2}},",
+      "{\"traceId\":\"4bf92f3577b34da6a3ce929d0e0e4736\",\"spanId\":\"0102030405060708\",",
+      "\"parentSpanId\":\"00f067aa0ba902b7\",\"name\":\"backend\",\"kind\":3,",
+      "\"startTimeUnixNano\":\"1682858096001000000\",\"endTimeUnixNano\":\"1682858096004000000\",\"attributes\":[]}",
+      "]}]}]}",
+    );
+    assert_eq!(encode_otlp_json("gw", &spans), expected);
+  }
+}
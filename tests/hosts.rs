@@ -0,0 +1,45 @@
+extern crate proxy_gateway;
+
+use proxy_gateway::{ProxyGatewayConfig};
+use proxy_gateway::backend::{Strategy};
+
+fn config() -> ProxyGatewayConfig {
+  let mut cfg = ProxyGatewayConfig::default();
+  for group in ["exact", "wild", "deep", "regex", "default"].iter() {
+    cfg.add_backend_group(group, &["127.0.0.1:8080"], Strategy::RoundRobin);
+  }
+  cfg.map_host_to_group("api.example.com", "exact");
+  cfg.map_wildcard_host_to_group("*.example.com", "wild");
+  cfg.map_wildcard_host_to_group("*.eu.example.com", "deep");
+  cfg.map_host_regex_to_group("api\\.example\\.(com|net)", "regex");
+  cfg.map_host_regex_to_group("(?i)[a-z]+\\.example\\.org", "regex");
+  cfg.set_default_group("default");
+  cfg
+}
+
+#[test]
+fn test_host_group_order() {
+  let cfg = config();
+  let cases: Vec<(Option<&str>, &str)> = vec![
+    // Exact beats the wildcard that also matches.
+    (Some("api.example.com"), "exact"),
+    (Some("www.example.com"), "wild"),
+    // The longest wildcard wins, at any depth.
+    (Some("api.eu.example.com"), "deep"),
+    (Some("a.b.eu.example.com"), "deep"),
+    (Some("eu.example.com"), "wild"),
+    // A wildcard does not match the bare domain.
+    (Some("example.com"), "default"),
+    (Some("api.example.net"), "regex"),
+    (Some("www.example.org"), "regex"),
+    // Regexes match the whole host.
+    (Some("api.example.net.evil.test"), "default"),
+    (Some("evil-api.example.net"), "default"),
+    (Some("a.www.example.org"), "default"),
+    (Some("other.test"), "default"),
+    (None, "default"),
+  ];
+  for (host, expected) in cases {
+    assert_eq!(cfg.host_group(host).map(|g| g.as_str()), Some(expected), "host: {:?}", host);
+  }
+}
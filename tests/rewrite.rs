@@ -0,0 +1,101 @@
+extern crate proxy_gateway;
+
+use proxy_gateway::regex::{Regex};
+use proxy_gateway::rewrite::{Rewrite, RewriteOutcome, apply, check_template};
+
+fn strip(prefix: &str) -> Rewrite {
+  Rewrite::StripPrefix(prefix.into())
+}
+
+fn add(prefix: &str) -> Rewrite {
+  Rewrite::AddPrefix(prefix.into())
+}
+
+fn regex(re: &str, template: &str) -> Rewrite {
+  Rewrite::Regex(Regex::new(re).unwrap(), template.into())
+}
+
+fn redirect(status: u16, re: &str, template: &str) -> Rewrite {
+  Rewrite::Redirect(status, Regex::new(re).unwrap(), template.into())
+}
+
+fn forward(target: &str) -> RewriteOutcome {
+  RewriteOutcome::Forward(target.into())
+}
+
+fn moved(status: u16, location: &str) -> RewriteOutcome {
+  RewriteOutcome::Redirect(status, location.into())
+}
+
+#[test]
+fn test_rewrite_table() {
+  let cases: Vec<(&str, Vec<Rewrite>, &str, RewriteOutcome)> = vec![
+    ("no rules", vec![], "/a/b?x=1", forward("/a/b?x=1")),
+    ("strip prefix", vec![strip("/api")], "/api/v1/users", forward("/v1/users")),
+    ("strip prefix, trailing slash", vec![strip("/api/")], "/api/v1", forward("/v1")),
+    ("strip whole path", vec![strip("/api")], "/api", forward("/")),
+    ("strip keeps query", vec![strip("/api")], "/api/x?a=1&b=2", forward("/x?a=1&b=2")),
+    ("strip on segment boundary only", vec![strip("/api")], "/apix/v1", forward("/apix/v1")),
+    ("strip non-matching", vec![strip("/api")], "/static/a.css", forward("/static/a.css")),
+    ("add prefix", vec![add("/v2")], "/users", forward("/v2/users")),
+    ("add prefix, trailing slash", vec![add("/v2/")], "/users", forward("/v2/users")),
+    ("strip then add", vec![strip("/api"), add("/internal")], "/api/users?id=7", forward("/internal/users?id=7")),
+    ("regex with captures", vec![regex("^/users/(\\d+)/posts$", "/posts?user=$1")], "/users/42/posts", forward("/posts?user=42")),
+    ("regex template query", vec![regex("^/users/(\\d+)/posts$", "/posts?user=$1")], "/users/42/posts?x=1", forward("/posts?user=42&x=1")),
+    ("regex template query mid-path", vec![regex("^/a", "/x?y=1")], "/a/b", forward("/x/b?y=1")),
+    ("regex template with ${query}", vec![regex("^/a$", "/b?${query}&z=1")], "/a?x=1", forward("/b?x=1&z=1")),
+    ("regex template empty query", vec![regex("^/a$", "/b?")], "/a?x=1", forward("/b?x=1")),
+    ("regex query then strip", vec![regex("^/api/(\\w+)$", "/api/item?id=$1"), strip("/api")], "/api/7?v=2", forward("/item?id=7&v=2")),
+    ("regex braced captures", vec![regex("^/(\\w+)/(\\w+)$", "/${2}/${1}")], "/a/b", forward("/b/a")),
+    ("regex replaces first match only", vec![regex("//+", "/")], "/a//b///c", forward("/a/b///c")),
+    ("regex non-matching", vec![regex("^/old/", "/new/")], "/other/x", forward("/other/x")),
+    ("regex missing group is empty", vec![regex("^/(a)?b$", "/x$1y")], "/b", forward("/xy")),
+    ("regex literal dollar", vec![regex("^/price$", "/cost$$")], "/price", forward("/cost$")),
+    ("regex keeps a leading slash", vec![regex("^/api/", "")], "/api/users", forward("/users")),
+    ("redirect 301", vec![redirect(301, "^/old(/.*)?$", "/new$1")], "/old/page", moved(301, "/new/page")),
+    ("redirect keeps query", vec![redirect(302, "^/a$", "/b")], "/a?x=1", moved(302, "/b?x=1")),
+    ("redirect own query", vec![redirect(307, "^/a$", "/b?from=a")], "/a?x=1", moved(307, "/b?from=a")),
+    ("redirect templated host", vec![redirect(308, "^/(.*)$", "https://www.${host}/$1")], "/x/y", moved(308, "https://www.example.com/x/y")),
+    ("redirect path and query vars", vec![redirect(302, "^/login$", "https://auth.example.com/?next=${path}&${query}")], "/login?a=b", moved(302, "https://auth.example.com/?next=/login&a=b")),
+    ("redirect non-matching falls through", vec![redirect(301, "^/old$", "/new"), add("/v1")], "/other", forward("/v1/other")),
+    ("redirect after rewrite", vec![strip("/api"), redirect(301, "^/v1/(.*)$", "/api/v2/$1")], "/api/v1/x", moved(301, "/api/v2/x")),
+    ("rules after redirect are skipped", vec![redirect(302, "^/a$", "/b"), add("/never")], "/a", moved(302, "/b")),
+  ];
+  for (name, rewrites, target, expected) in cases {
+    assert_eq!(apply(&rewrites, "example.com", target), expected, "case: {}", name);
+  }
+}
+
+#[test]
+fn test_check_template_table() {
+  let cases: Vec<(&str, usize, bool)> = vec![
+    ("/plain", 0, true),
+    ("/$0", 0, true),
+    ("/$1", 0, false),
+    ("/$1/$2", 2, true),
+    ("/${10}", 9, false),
+    ("/${host}/${path}?${query}", 0, true),
+    ("/${nope}", 0, false),
+    ("/${unterminated", 0, false),
+    ("/$$1", 0, true),
+    ("/cost$", 0, true),
+  ];
+  for (template, groups, ok) in cases {
+    assert_eq!(check_template(template, groups).is_ok(), ok, "template: {}", template);
+  }
+}
+
+#[test]
+fn test_uses_host_table() {
+  let cases: Vec<(&str, Rewrite, bool)> = vec![
+    ("strip", strip("/api"), false),
+    ("add", add("/v2"), false),
+    ("regex", regex("^/(.*)$", "/x/$1"), false),
+    ("regex host", regex("^/(.*)$", "/${host}/$1"), true),
+    ("redirect host", redirect(308, "^/(.*)$", "https://www.${host}/$1"), true),
+    ("redirect escaped", redirect(308, "^/(.*)$", "https://a/$${host}"), false),
+  ];
+  for (name, rewrite, expected) in cases {
+    assert_eq!(rewrite.uses_host(), expected, "case: {}", name);
+  }
+}